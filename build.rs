@@ -1,4 +1,5 @@
 use std::path::PathBuf;
+use std::process::Command;
 use thiserror::Error;
 use winreg::enums::HKEY_LOCAL_MACHINE;
 use winreg::RegKey;
@@ -14,27 +15,84 @@ pub enum Error {
     IoError(#[from] std::io::Error),
     #[error("cannot find the directory")]
     DirectoryNotFound,
+    #[error("{0} failed")]
+    ToolFailed(&'static str),
 }
 
+/// Linker options of the driver image. They are passed for the cdylib only, so
+/// host unit tests (`cargo test`) link as a normal executable.
+const DRIVER_LINK_ARGS: &[&str] = &[
+    "/NOLOGO",
+    "/NXCOMPAT",
+    "/NODEFAULTLIB",
+    "/SUBSYSTEM:NATIVE",
+    "/DRIVER",
+    "/DYNAMICBASE",
+    "/MANIFEST:NO",
+    "/OPT:REF,ICF",
+    "/ENTRY:driver_entry",
+    "/MERGE:.edata=.rdata",
+    "/MERGE:.rustc=.data",
+    "/INTEGRITYCHECK",
+];
+
 fn main() {
     // Get the path to the kernel libraries.
     let dir = get_km_dir(DirectoryType::Library).unwrap();
 
     // Append the architecture based on our target.
-    let target = std::env::var("TARGET").unwrap();
-
-    let arch = if target.contains("x86_64") {
-        "x64"
-    } else if target.contains("i686") {
-        "x86"
-    } else {
-        panic!("The target {} is currently not supported.", target);
+    let target_arch = std::env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+
+    let arch = match target_arch.as_str() {
+        "x86_64" => "x64",
+        "x86" => "x86",
+        _ => panic!(
+            "The target architecture {} is currently not supported.",
+            target_arch
+        ),
     };
 
     let dir = dir.join(arch);
 
     // Specify the link path.
     println!("cargo:rustc-link-search=native={}", dir.to_str().unwrap());
+    for arg in DRIVER_LINK_ARGS {
+        println!("cargo:rustc-cdylib-link-arg={}", arg);
+    }
+
+    // Compile event log message table and link it into the driver.
+    let bin_dir = get_bin_dir(arch).unwrap().join(arch);
+    let res = compile_message_table(&bin_dir).unwrap();
+    println!("cargo:rustc-cdylib-link-arg={}", res.to_str().unwrap());
+    println!("cargo:rerun-if-changed=meta_driver.mc");
+}
+
+/// Compiles `meta_driver.mc` with mc.exe (customer bit set) and the generated
+/// resource script with rc.exe. Returns the path to the `.res` file.
+pub fn compile_message_table(bin_dir: &PathBuf) -> Result<PathBuf, Error> {
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+
+    let status = Command::new(bin_dir.join("mc.exe"))
+        .arg("-c")
+        .arg("-h").arg(&out_dir)
+        .arg("-r").arg(&out_dir)
+        .arg("meta_driver.mc")
+        .status()?;
+    if !status.success() {
+        return Err(Error::ToolFailed("mc.exe"));
+    }
+
+    let res = out_dir.join("meta_driver.res");
+    let status = Command::new(bin_dir.join("rc.exe"))
+        .arg("/nologo")
+        .arg("/fo").arg(&res)
+        .arg(out_dir.join("meta_driver.rc"))
+        .status()?;
+    if !status.success() {
+        return Err(Error::ToolFailed("rc.exe"));
+    }
+
+    Ok(res)
 }
 
 /// Retrieves the path to the Windows Kits directory. The default should be
@@ -47,6 +105,26 @@ pub fn get_windows_kits_dir() -> Result<PathBuf, Error> {
     Ok(dir.into())
 }
 
+/// Retrieves the path to the SDK tools for `arch` (`x64` or `x86`). The path may
+/// look something like:
+/// `C:\Program Files (x86)\Windows Kits\10\bin\10.0.18362.0`.
+pub fn get_bin_dir(arch: &str) -> Result<PathBuf, Error> {
+    let dir = get_windows_kits_dir()?.join("bin").read_dir()?;
+
+    // Same as for libraries, pick the highest version which has the tools.
+    dir.filter_map(|dir| dir.ok())
+        .map(|dir| dir.path())
+        .filter(|dir| {
+            dir.components()
+                .last()
+                .and_then(|c| c.as_os_str().to_str())
+                .map(|c| c.starts_with("10.") && dir.join(arch).join("mc.exe").is_file())
+                .unwrap_or(false)
+        })
+        .max()
+        .ok_or_else(|| Error::DirectoryNotFound)
+}
+
 /// Retrieves the path to the kernel mode libraries. The path may look something like:
 /// `C:\Program Files (x86)\Windows Kits\10\lib\10.0.18362.0\km`.
 pub fn get_km_dir(dir_type: DirectoryType) -> Result<PathBuf, Error> {
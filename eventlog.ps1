@@ -0,0 +1,7 @@
+# Registers meta_driver as a System event log source, so Event Viewer can show
+# messages from the table linked into the driver. Source name must match the service name.
+param([string]$DriverPath = "$env:SystemRoot\System32\drivers\meta_driver.sys")
+$sourceKey = "HKLM:\SYSTEM\CurrentControlSet\Services\EventLog\System\meta_driver"
+New-Item -Path $sourceKey -Force | Out-Null
+Set-ItemProperty -Path $sourceKey -Name EventMessageFile -Type ExpandString -Value "%SystemRoot%\System32\IoLogMsg.dll;$DriverPath"
+Set-ItemProperty -Path $sourceKey -Name TypesSupported -Type DWord -Value 7
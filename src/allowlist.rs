@@ -0,0 +1,107 @@
+// Process image allowlist checked at handle open. Pure matching, the caller
+// looks up the image path of the opening process.
+// Only image paths are matched. Signer and hash checks need code integrity
+// routines which have no documented kernel API.
+
+// Imports
+use wchar::wchar_t;
+use crate::config::WideName;
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Max number of allowed images
+pub const MAX_ALLOWED_IMAGES: usize = 4;
+
+// Max image path length in UTF-16 units, terminating NUL included
+pub const IMAGE_PATH_CAPACITY: usize = 260;
+
+// NT image paths (\Device\HarddiskVolumeN\...) of processes which may open
+// driver devices. Empty list allows every process, an invalid one none.
+#[derive(Copy, Clone)]
+pub struct AllowedImages {
+	paths: [WideName<IMAGE_PATH_CAPACITY>; MAX_ALLOWED_IMAGES],
+	count: usize,
+	// Value is configured but could not be read, fail closed
+	invalid: bool
+}
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl Default for AllowedImages {
+	fn default() -> AllowedImages {
+		AllowedImages::new()
+	}
+}
+
+impl AllowedImages {
+	pub const fn new() -> AllowedImages {
+		AllowedImages {
+			paths: [WideName::from_wchz(&[]); MAX_ALLOWED_IMAGES],
+			count: 0,
+			invalid: false
+		}
+	}
+
+	// Add path, false if list is full
+	pub fn push(&mut self, path: WideName<IMAGE_PATH_CAPACITY>) -> bool {
+		if self.count == MAX_ALLOWED_IMAGES {
+			return false;
+		}
+		self.paths[self.count] = path;
+		self.count += 1;
+		true
+	}
+
+	pub fn clear(&mut self) {
+		self.count = 0;
+		self.invalid = false;
+	}
+
+	// Configured list is invalid, no process is allowed
+	pub fn reject_all(&mut self) {
+		self.count = 0;
+		self.invalid = true;
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.count == 0
+	}
+
+	// Some processes are refused, the caller must be checked
+	pub fn is_restricted(&self) -> bool {
+		self.invalid || self.count != 0
+	}
+
+	pub fn paths(&self) -> &[WideName<IMAGE_PATH_CAPACITY>] {
+		&self.paths[..self.count]
+	}
+
+	// Image path matches an entry, ASCII case-insensitive like NTFS names usually are
+	pub fn allows(&self, image_path: &[wchar_t]) -> bool {
+		!self.is_restricted() || self.paths().iter().any(|path| equal_ignore_ascii_case(path.as_units(), image_path))
+	}
+}
+
+// Last component of image path, for logs
+pub fn image_file_name(image_path: &[wchar_t]) -> &[wchar_t] {
+	match image_path.iter().rposition(|unit| *unit == '\\' as wchar_t) {
+		Some(separator) => &image_path[separator + 1..],
+		None => image_path
+	}
+}
+
+// PRIVATE FUNCTIONS ==========================================
+
+fn equal_ignore_ascii_case(left: &[wchar_t], right: &[wchar_t]) -> bool {
+	left.len() == right.len() && left.iter().zip(right.iter()).all(|(left, right)| {
+		fold_ascii(*left) == fold_ascii(*right)
+	})
+}
+
+fn fold_ascii(unit: wchar_t) -> wchar_t {
+	if unit >= 'A' as wchar_t && unit <= 'Z' as wchar_t {
+		unit + ('a' as wchar_t - 'A' as wchar_t)
+	} else {
+		unit
+	}
+}
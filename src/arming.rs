@@ -0,0 +1,67 @@
+// Driver-wide kill switch. Pure bookkeeping, the watchdog timer fires auto-disarm.
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Armed state with optional auto-disarm deadline
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ArmSwitch {
+	armed: bool,
+	// Disarm when clock::now_ms passes it, None if there is no timeout
+	deadline_ms: Option<u64>
+}
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl Default for ArmSwitch {
+	fn default() -> ArmSwitch {
+		ArmSwitch::new()
+	}
+}
+
+impl ArmSwitch {
+	pub const fn new() -> ArmSwitch {
+		ArmSwitch {
+			armed: false,
+			deadline_ms: None
+		}
+	}
+
+	pub fn is_armed(&self) -> bool {
+		self.armed
+	}
+
+	// Arm or refresh. auto_disarm_ms 0 keeps the switch armed until disarm.
+	pub fn arm(&mut self, now_ms: u64, auto_disarm_ms: u32) {
+		self.armed = true;
+		self.deadline_ms = match auto_disarm_ms {
+			0 => None,
+			auto_disarm_ms => Some(now_ms + auto_disarm_ms as u64)
+		};
+	}
+
+	// Returns true if the switch was armed
+	pub fn disarm(&mut self) -> bool {
+		let was_armed = self.armed;
+		self.armed = false;
+		self.deadline_ms = None;
+		was_armed
+	}
+
+	// Disarm if the deadline passed. Returns true if it did.
+	pub fn expire(&mut self, now_ms: u64) -> bool {
+		match self.deadline_ms {
+			Some(deadline_ms) if deadline_ms <= now_ms => self.disarm(),
+			_ => false
+		}
+	}
+
+	// Auto-disarm deadline, None if disarmed or without timeout
+	pub fn deadline(&self) -> Option<u64> {
+		self.deadline_ms
+	}
+
+	// Milliseconds until auto-disarm, 0 if there is no deadline
+	pub fn remaining_ms(&self, now_ms: u64) -> u64 {
+		self.deadline_ms.map_or(0, |deadline_ms| deadline_ms.saturating_sub(now_ms))
+	}
+}
@@ -0,0 +1,238 @@
+// Ring of recent injections, read by AUDIT_SNAPSHOT.
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Max ring capacity, the configured capacity may be lower
+pub const MAX_AUDIT_CAPACITY: usize = 1024;
+
+// Injected device, values match DeviceRole
+pub const AUDIT_DEVICE_MOUSE: u32 = 0;
+pub const AUDIT_DEVICE_KEYBOARD: u32 = 1;
+
+// One injected event, as returned by AUDIT_SNAPSHOT
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct AuditRecord {
+	// Grows by one per record since load, gaps show overwritten records
+	pub id: u64,
+	// clock::now_ms at injection
+	pub timestamp_ms: u64,
+	// Session id, 0 for input the driver produced itself
+	pub session: u64,
+	// Opening process of the session, 0 for input the driver produced itself
+	pub process_id: u32,
+	// AUDIT_DEVICE_*
+	pub device: u32,
+	// Events the class service callback consumed
+	pub consumed: u32,
+	// Mouse: MOUSE_MOVE_* flags. Keyboard: KEY_* flags.
+	pub flags: u16,
+	// Mouse: button flags. Keyboard: make code.
+	pub code: u16,
+	// Mouse position or movement, 0 for keyboard
+	pub x: i32,
+	pub y: i32,
+	pub extra_information: u32,
+	// 1 if the event was not handed to the class driver, consumed is then 1
+	pub dry_run: u32
+}
+
+// Bounded ring, oldest records are overwritten
+pub struct AuditRing {
+	records: [AuditRecord; MAX_AUDIT_CAPACITY],
+	capacity: usize,
+	// Slot of the next record
+	next: usize,
+	count: usize,
+	next_id: u64
+}
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl AuditRecord {
+	pub const fn zeroed() -> AuditRecord {
+		AuditRecord {
+			id: 0,
+			timestamp_ms: 0,
+			session: 0,
+			process_id: 0,
+			device: 0,
+			consumed: 0,
+			flags: 0,
+			code: 0,
+			x: 0,
+			y: 0,
+			extra_information: 0,
+			dry_run: 0
+		}
+	}
+}
+
+impl Default for AuditRing {
+	fn default() -> AuditRing {
+		AuditRing::new()
+	}
+}
+
+impl AuditRing {
+	pub const fn new() -> AuditRing {
+		AuditRing {
+			records: [AuditRecord::zeroed(); MAX_AUDIT_CAPACITY],
+			capacity: 0,
+			next: 0,
+			count: 0,
+			next_id: 1
+		}
+	}
+
+	// Change capacity, clamped to MAX_AUDIT_CAPACITY. Records are dropped on change,
+	// 0 stops auditing.
+	pub fn set_capacity(&mut self, capacity: usize) {
+		let capacity = capacity.min(MAX_AUDIT_CAPACITY);
+		if capacity != self.capacity {
+			self.capacity = capacity;
+			self.clear();
+		}
+	}
+
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+
+	// Records currently held
+	pub fn len(&self) -> usize {
+		self.count
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.count == 0
+	}
+
+	// Records pushed since load, cleared and overwritten ones included
+	pub fn total(&self) -> u64 {
+		self.next_id - 1
+	}
+
+	// Store record, its id is assigned here
+	pub fn push(&mut self, mut record: AuditRecord) {
+		if self.capacity == 0 {
+			return;
+		}
+		record.id = self.next_id;
+		self.next_id += 1;
+		self.records[self.next] = record;
+		self.next = (self.next + 1) % self.capacity;
+		self.count = (self.count + 1).min(self.capacity);
+	}
+
+	// Ids keep growing, so a later snapshot shows what was cleared
+	pub fn clear(&mut self) {
+		self.next = 0;
+		self.count = 0;
+	}
+
+	// Copy newest records that fit into out, oldest first. Returns number copied.
+	pub fn snapshot(&self, out: &mut [AuditRecord]) -> usize {
+		let copied = self.count.min(out.len());
+		let first = (self.next + self.capacity - copied) % self.capacity.max(1);
+		for (index, record) in out[..copied].iter_mut().enumerate() {
+			*record = self.records[(first + index) % self.capacity];
+		}
+		copied
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn record(code: u16) -> AuditRecord {
+		AuditRecord {
+			code,
+			..AuditRecord::zeroed()
+		}
+	}
+
+	fn codes(ring: &AuditRing, out_len: usize) -> Vec<u16> {
+		let mut out = vec![AuditRecord::zeroed(); out_len];
+		let copied = ring.snapshot(&mut out);
+		out[..copied].iter().map(|record| record.code).collect()
+	}
+
+	#[test]
+	fn ring_wraps_and_keeps_newest() {
+		let mut ring = Box::new(AuditRing::new());
+		ring.set_capacity(3);
+		for code in 1..=5 {
+			ring.push(record(code));
+		}
+		assert_eq!(ring.len(), 3);
+		assert_eq!(codes(&ring, 8), vec![3, 4, 5]);
+		let mut out = [AuditRecord::zeroed(); 3];
+		ring.snapshot(&mut out);
+		assert_eq!(out.iter().map(|record| record.id).collect::<Vec<_>>(), vec![3, 4, 5]);
+	}
+
+	#[test]
+	fn short_snapshot_gets_newest() {
+		let mut ring = Box::new(AuditRing::new());
+		ring.set_capacity(4);
+		for code in 1..=6 {
+			ring.push(record(code));
+		}
+		assert_eq!(codes(&ring, 2), vec![5, 6]);
+		assert_eq!(codes(&ring, 0), Vec::<u16>::new());
+	}
+
+	#[test]
+	fn zero_capacity_drops_records() {
+		let mut ring = Box::new(AuditRing::new());
+		ring.push(record(1));
+		assert!(ring.is_empty());
+		assert_eq!(ring.total(), 0);
+		assert_eq!(codes(&ring, 4), Vec::<u16>::new());
+
+		ring.set_capacity(2);
+		ring.push(record(1));
+		ring.set_capacity(0);
+		ring.push(record(2));
+		assert!(ring.is_empty());
+		assert_eq!(ring.total(), 1);
+	}
+
+	#[test]
+	fn capacity_change_clears() {
+		let mut ring = Box::new(AuditRing::new());
+		ring.set_capacity(4);
+		ring.push(record(1));
+		ring.push(record(2));
+		// Same capacity keeps records
+		ring.set_capacity(4);
+		assert_eq!(ring.len(), 2);
+		ring.set_capacity(2);
+		assert!(ring.is_empty());
+		ring.push(record(3));
+		assert_eq!(codes(&ring, 4), vec![3]);
+		// Clamped to the maximum
+		ring.set_capacity(MAX_AUDIT_CAPACITY + 1);
+		assert_eq!(ring.capacity(), MAX_AUDIT_CAPACITY);
+	}
+
+	#[test]
+	fn total_counts_cleared_records() {
+		let mut ring = Box::new(AuditRing::new());
+		ring.set_capacity(2);
+		for code in 1..=3 {
+			ring.push(record(code));
+		}
+		ring.clear();
+		assert!(ring.is_empty());
+		assert_eq!(ring.total(), 3);
+		// Ids go on after clear
+		ring.push(record(4));
+		let mut out = [AuditRecord::zeroed(); 1];
+		assert_eq!(ring.snapshot(&mut out), 1);
+		assert_eq!(out[0].id, 4);
+		assert_eq!(ring.total(), 4);
+	}
+}
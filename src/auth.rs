@@ -0,0 +1,473 @@
+// Request authentication with a per-installation shared key. Every IOCTL
+// input ends with an AuthTrailer: a sequence number and HMAC-SHA256 over
+// session nonce, IOCTL code, sequence number and request body.
+// Pure code, the caller supplies key, nonce and buffers.
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Key length limits in bytes
+pub const AUTH_KEY_MIN: usize = 16;
+pub const AUTH_KEY_MAX: usize = 64;
+
+pub const MAC_SIZE: usize = 32;
+
+const BLOCK_SIZE: usize = 64;
+
+// Appended to the input buffer of every request when authentication is enabled
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct AuthTrailer {
+	// Must grow with every request on the handle
+	pub sequence: u64,
+	pub mac: [u8; MAC_SIZE]
+}
+
+// Shared key from registry, empty disables authentication
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct AuthKey {
+	bytes: [u8; AUTH_KEY_MAX],
+	len: usize,
+	// Value is configured but unusable, every request fails
+	invalid: bool
+}
+
+// Why a request failed authentication
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AuthFailure {
+	// Input is shorter than the trailer
+	MissingTrailer,
+	// MAC does not match, request was tampered or signed with another key
+	BadMac,
+	// Configured key is invalid, no request can be verified
+	InvalidKey,
+	// Sequence number is not above the last accepted one
+	Replayed
+}
+
+// Accepts strictly increasing sequence numbers, so replayed and reordered
+// requests are both refused
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ReplayGuard {
+	last_sequence: u64
+}
+
+// SHA-256 state
+struct Sha256 {
+	state: [u32; 8],
+	block: [u8; BLOCK_SIZE],
+	block_len: usize,
+	total_len: u64
+}
+
+const SHA256_INITIAL: [u32; 8] = [
+	0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+	0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+];
+
+const SHA256_ROUND: [u32; 64] = [
+	0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+	0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+	0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+	0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+	0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+	0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+	0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+	0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl AuthKey {
+	pub const fn empty() -> AuthKey {
+		AuthKey {
+			bytes: [0; AUTH_KEY_MAX],
+			len: 0,
+			invalid: false
+		}
+	}
+
+	// Key value exists but is unusable, authentication stays required
+	pub const fn invalid() -> AuthKey {
+		AuthKey {
+			bytes: [0; AUTH_KEY_MAX],
+			len: 0,
+			invalid: true
+		}
+	}
+
+	// Key from raw bytes, None if length is out of limits
+	pub fn from_bytes(bytes: &[u8]) -> Option<AuthKey> {
+		if bytes.len() < AUTH_KEY_MIN || bytes.len() > AUTH_KEY_MAX {
+			return None;
+		}
+		let mut key = AuthKey::empty();
+		key.bytes[..bytes.len()].copy_from_slice(bytes);
+		key.len = bytes.len();
+		Some(key)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	// Requests must carry an AuthTrailer, also if the key is invalid
+	pub fn is_required(&self) -> bool {
+		self.invalid || self.len != 0
+	}
+
+	// MAC of request body
+	pub fn request_mac(&self, nonce: u64, io_control_code: u32, sequence: u64, body: &[u8]) -> [u8; MAC_SIZE] {
+		hmac_sha256(&self.bytes[..self.len], &[
+			&nonce.to_le_bytes(),
+			&io_control_code.to_le_bytes(),
+			&sequence.to_le_bytes(),
+			body
+		])
+	}
+
+	// Split input into body and trailer and check the MAC. Returns the sequence
+	// number, which the caller checks against the session ReplayGuard.
+	pub fn verify(&self, nonce: u64, io_control_code: u32, input: &[u8]) -> Result<u64, AuthFailure> {
+		if self.invalid {
+			return Err(AuthFailure::InvalidKey);
+		}
+		let trailer_size = core::mem::size_of::<AuthTrailer>();
+		if input.len() < trailer_size {
+			return Err(AuthFailure::MissingTrailer);
+		}
+		let (body, trailer) = input.split_at(input.len() - trailer_size);
+		let mut sequence_bytes = [0u8; 8];
+		sequence_bytes.copy_from_slice(&trailer[..8]);
+		let sequence = u64::from_le_bytes(sequence_bytes);
+
+		let expected = self.request_mac(nonce, io_control_code, sequence, body);
+		if !equal_constant_time(&expected, &trailer[8..]) {
+			return Err(AuthFailure::BadMac);
+		}
+		Ok(sequence)
+	}
+}
+
+impl AuthFailure {
+	pub fn description(self) -> &'static str {
+		match self {
+			AuthFailure::MissingTrailer => "missing trailer",
+			AuthFailure::BadMac => "MAC mismatch",
+			AuthFailure::InvalidKey => "configured key is invalid",
+			AuthFailure::Replayed => "replayed or reordered"
+		}
+	}
+}
+
+impl Default for ReplayGuard {
+	fn default() -> ReplayGuard {
+		ReplayGuard::new()
+	}
+}
+
+impl ReplayGuard {
+	pub const fn new() -> ReplayGuard {
+		ReplayGuard {
+			last_sequence: 0
+		}
+	}
+
+	// Accept sequence if it is above every accepted one. Sequence 0 is never accepted.
+	pub fn accept(&mut self, sequence: u64) -> Result<(), AuthFailure> {
+		if sequence <= self.last_sequence {
+			return Err(AuthFailure::Replayed);
+		}
+		self.last_sequence = sequence;
+		Ok(())
+	}
+}
+
+// HMAC-SHA256 (RFC 2104) of concatenated parts
+pub fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; MAC_SIZE] {
+	let mut key_block = [0u8; BLOCK_SIZE];
+	if key.len() > BLOCK_SIZE {
+		key_block[..MAC_SIZE].copy_from_slice(&sha256(&[key]));
+	} else {
+		key_block[..key.len()].copy_from_slice(key);
+	}
+
+	let mut inner_pad = [0u8; BLOCK_SIZE];
+	let mut outer_pad = [0u8; BLOCK_SIZE];
+	for index in 0..BLOCK_SIZE {
+		inner_pad[index] = key_block[index] ^ 0x36;
+		outer_pad[index] = key_block[index] ^ 0x5c;
+	}
+
+	let mut inner = Sha256::new();
+	inner.update(&inner_pad);
+	for part in parts.iter() {
+		inner.update(part);
+	}
+	let inner_hash = inner.finish();
+
+	let mut outer = Sha256::new();
+	outer.update(&outer_pad);
+	outer.update(&inner_hash);
+	outer.finish()
+}
+
+// SHA-256 of concatenated parts
+pub fn sha256(parts: &[&[u8]]) -> [u8; MAC_SIZE] {
+	let mut hash = Sha256::new();
+	for part in parts.iter() {
+		hash.update(part);
+	}
+	hash.finish()
+}
+
+// Compare without early exit, timing does not reveal the matching prefix
+pub fn equal_constant_time(left: &[u8], right: &[u8]) -> bool {
+	if left.len() != right.len() {
+		return false;
+	}
+	left.iter().zip(right.iter()).fold(0u8, |diff, (left, right)| diff | (left ^ right)) == 0
+}
+
+// PRIVATE FUNCTIONS ==========================================
+
+impl Sha256 {
+	fn new() -> Sha256 {
+		Sha256 {
+			state: SHA256_INITIAL,
+			block: [0; BLOCK_SIZE],
+			block_len: 0,
+			total_len: 0
+		}
+	}
+
+	fn update(&mut self, mut data: &[u8]) {
+		self.total_len += data.len() as u64;
+		while !data.is_empty() {
+			let take = (BLOCK_SIZE - self.block_len).min(data.len());
+			self.block[self.block_len..self.block_len + take].copy_from_slice(&data[..take]);
+			self.block_len += take;
+			data = &data[take..];
+			if self.block_len == BLOCK_SIZE {
+				self.compress();
+				self.block_len = 0;
+			}
+		}
+	}
+
+	fn finish(mut self) -> [u8; MAC_SIZE] {
+		let bit_len = self.total_len * 8;
+		self.block[self.block_len] = 0x80;
+		self.block_len += 1;
+		if self.block_len > BLOCK_SIZE - 8 {
+			self.block[self.block_len..].fill(0);
+			self.compress();
+			self.block_len = 0;
+		}
+		self.block[self.block_len..BLOCK_SIZE - 8].fill(0);
+		self.block[BLOCK_SIZE - 8..].copy_from_slice(&bit_len.to_be_bytes());
+		self.compress();
+
+		let mut digest = [0u8; MAC_SIZE];
+		for (chunk, word) in digest.chunks_exact_mut(4).zip(self.state.iter()) {
+			chunk.copy_from_slice(&word.to_be_bytes());
+		}
+		digest
+	}
+
+	fn compress(&mut self) {
+		let mut schedule = [0u32; 64];
+		for (word, chunk) in schedule.iter_mut().zip(self.block.chunks_exact(4)) {
+			*word = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+		}
+		for index in 16..64 {
+			let s0 = schedule[index - 15].rotate_right(7) ^ schedule[index - 15].rotate_right(18) ^ (schedule[index - 15] >> 3);
+			let s1 = schedule[index - 2].rotate_right(17) ^ schedule[index - 2].rotate_right(19) ^ (schedule[index - 2] >> 10);
+			schedule[index] = schedule[index - 16]
+				.wrapping_add(s0)
+				.wrapping_add(schedule[index - 7])
+				.wrapping_add(s1);
+		}
+
+		let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+		for index in 0..64 {
+			let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+			let choose = (e & f) ^ (!e & g);
+			let temp1 = h
+				.wrapping_add(s1)
+				.wrapping_add(choose)
+				.wrapping_add(SHA256_ROUND[index])
+				.wrapping_add(schedule[index]);
+			let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+			let majority = (a & b) ^ (a & c) ^ (b & c);
+			let temp2 = s0.wrapping_add(majority);
+			h = g;
+			g = f;
+			f = e;
+			e = d.wrapping_add(temp1);
+			d = c;
+			c = b;
+			b = a;
+			a = temp1.wrapping_add(temp2);
+		}
+
+		for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+			*state = state.wrapping_add(*value);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+	const NONCE: u64 = 0x0123_4567_89AB_CDEF;
+	const CODE: u32 = 0x0022_2004;
+
+	fn hex(digest: &[u8]) -> String {
+		digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+	}
+
+	// Body followed by its AuthTrailer
+	fn signed(key: &AuthKey, io_control_code: u32, sequence: u64, body: &[u8]) -> Vec<u8> {
+		let mut input = body.to_vec();
+		input.extend_from_slice(&sequence.to_le_bytes());
+		input.extend_from_slice(&key.request_mac(NONCE, io_control_code, sequence, body));
+		input
+	}
+
+	#[test]
+	fn sha256_fips_180_4_vectors() {
+		assert_eq!(hex(&sha256(&[b"abc"])), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+		assert_eq!(hex(&sha256(&[b""])), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+		assert_eq!(hex(&sha256(&[b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"])),
+			"248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+		assert_eq!(hex(&sha256(&[b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu"])),
+			"cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1");
+		assert_eq!(hex(&sha256(&[&vec![b'a'; 1_000_000]])), "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+	}
+
+	#[test]
+	fn sha256_parts_are_concatenated() {
+		let message = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+		for split in 0..message.len() {
+			let (left, right) = message.split_at(split);
+			assert_eq!(sha256(&[left, right]), sha256(&[message]));
+		}
+	}
+
+	#[test]
+	fn hmac_sha256_rfc_4231_vectors() {
+		let cases: [(&[u8], &[u8], &str); 6] = [
+			(&[0x0b; 20], b"Hi There",
+				"b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"),
+			(b"Jefe", b"what do ya want for nothing?",
+				"5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+			(&[0xaa; 20], &[0xdd; 50],
+				"773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe"),
+			(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25], &[0xcd; 50],
+				"82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b"),
+			(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First",
+				"60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"),
+			(&[0xaa; 131], b"This is a test using a larger than block-size key and a larger than block-size data. The key needs to be hashed before being used by the HMAC algorithm.",
+				"9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2")
+		];
+		for (key, data, mac) in cases.iter() {
+			assert_eq!(hex(&hmac_sha256(key, &[data])), *mac);
+		}
+	}
+
+	#[test]
+	fn key_length_is_limited() {
+		assert!(AuthKey::from_bytes(&[1; AUTH_KEY_MIN - 1]).is_none());
+		assert!(AuthKey::from_bytes(&[1; AUTH_KEY_MAX + 1]).is_none());
+		assert!(!AuthKey::from_bytes(&[1; AUTH_KEY_MIN]).unwrap().is_empty());
+		assert!(AuthKey::empty().is_empty());
+		assert!(!AuthKey::empty().is_required());
+	}
+
+	#[test]
+	fn invalid_key_rejects_every_request() {
+		let key = AuthKey::invalid();
+		assert!(key.is_required());
+		// Not even a request signed with the empty key gets through
+		let input = signed(&key, CODE, 1, b"body");
+		assert_eq!(key.verify(NONCE, CODE, &input), Err(AuthFailure::InvalidKey));
+		assert_eq!(key.verify(NONCE, CODE, &[]), Err(AuthFailure::InvalidKey));
+	}
+
+	#[test]
+	fn signed_request_is_verified() {
+		let key = AuthKey::from_bytes(KEY).unwrap();
+		let input = signed(&key, CODE, 7, b"body");
+		assert_eq!(key.verify(NONCE, CODE, &input), Ok(7));
+		// Empty body, trailer only
+		assert_eq!(key.verify(NONCE, CODE, &signed(&key, CODE, 8, b"")), Ok(8));
+	}
+
+	#[test]
+	fn short_input_has_no_trailer() {
+		let key = AuthKey::from_bytes(KEY).unwrap();
+		let input = signed(&key, CODE, 1, b"");
+		assert_eq!(key.verify(NONCE, CODE, &input[1..]), Err(AuthFailure::MissingTrailer));
+	}
+
+	#[test]
+	fn tampered_mac_is_rejected() {
+		let key = AuthKey::from_bytes(KEY).unwrap();
+		let mut input = signed(&key, CODE, 1, b"body");
+		*input.last_mut().unwrap() ^= 1;
+		assert_eq!(key.verify(NONCE, CODE, &input), Err(AuthFailure::BadMac));
+		// Signed with another key
+		let other = AuthKey::from_bytes(b"fedcba9876543210").unwrap();
+		assert_eq!(key.verify(NONCE, CODE, &signed(&other, CODE, 1, b"body")), Err(AuthFailure::BadMac));
+	}
+
+	#[test]
+	fn mac_covers_code_body_sequence_and_nonce() {
+		let key = AuthKey::from_bytes(KEY).unwrap();
+		let input = signed(&key, CODE, 1, b"body");
+		assert_eq!(key.verify(NONCE, CODE + 4, &input), Err(AuthFailure::BadMac));
+		assert_eq!(key.verify(NONCE + 1, CODE, &input), Err(AuthFailure::BadMac));
+
+		let mut tampered_body = input.clone();
+		tampered_body[0] ^= 1;
+		assert_eq!(key.verify(NONCE, CODE, &tampered_body), Err(AuthFailure::BadMac));
+
+		let mut tampered_sequence = input.clone();
+		tampered_sequence[4] += 1;
+		assert_eq!(key.verify(NONCE, CODE, &tampered_sequence), Err(AuthFailure::BadMac));
+
+		// Trailer moved onto a longer body
+		let mut extended = b"x".to_vec();
+		extended.extend_from_slice(&input);
+		assert_eq!(key.verify(NONCE, CODE, &extended), Err(AuthFailure::BadMac));
+	}
+
+	#[test]
+	fn replayed_sequence_is_rejected() {
+		let mut guard = ReplayGuard::new();
+		assert_eq!(guard.accept(0), Err(AuthFailure::Replayed));
+		assert_eq!(guard.accept(1), Ok(()));
+		assert_eq!(guard.accept(1), Err(AuthFailure::Replayed));
+		assert_eq!(guard.accept(2), Ok(()));
+	}
+
+	#[test]
+	fn out_of_order_sequence_is_rejected() {
+		let mut guard = ReplayGuard::new();
+		assert_eq!(guard.accept(5), Ok(()));
+		assert_eq!(guard.accept(3), Err(AuthFailure::Replayed));
+		// Gaps are fine, only order matters
+		assert_eq!(guard.accept(9), Ok(()));
+		assert_eq!(guard.accept(u64::MAX), Ok(()));
+		assert_eq!(guard.accept(u64::MAX), Err(AuthFailure::Replayed));
+	}
+
+	#[test]
+	fn constant_time_compare_checks_length() {
+		assert!(equal_constant_time(b"abc", b"abc"));
+		assert!(!equal_constant_time(b"abc", b"abd"));
+		assert!(!equal_constant_time(b"abc", b"ab"));
+	}
+}
@@ -0,0 +1,40 @@
+// Imports
+use winapi::km::wdm::KPROCESSOR_MODE;
+use winapi::shared::ntdef::FALSE;
+use crate::winapi_local::km::wdm::{
+	KeQueryPerformanceCounter,
+	KeDelayExecutionThread
+};
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// 100 ns units per millisecond
+const TICKS_PER_MS: i64 = 10_000;
+
+// PUBLIC FUNCTIONS ==========================================
+
+// Monotonic milliseconds since boot, from performance counter
+pub fn now_ms() -> u64 {
+	let mut frequency: i64 = 0;
+	let counter = unsafe { KeQueryPerformanceCounter(&mut frequency) } as u64;
+	let frequency = frequency as u64;
+	// Split to avoid overflow of counter * 1000
+	(counter / frequency) * 1000 + (counter % frequency) * 1000 / frequency
+}
+
+// Monotonic microseconds since boot, for precise scheduling
+pub fn now_us() -> u64 {
+	let mut frequency: i64 = 0;
+	let counter = unsafe { KeQueryPerformanceCounter(&mut frequency) } as u64;
+	let frequency = frequency as u64;
+	(counter / frequency) * 1_000_000 + (counter % frequency) * 1_000_000 / frequency
+}
+
+/// Wait in the calling thread, PASSIVE_LEVEL only
+///
+/// # Safety
+/// IRQL PASSIVE_LEVEL
+pub unsafe fn sleep_ms(ms: u64) {
+	let interval: i64 = -(ms as i64) * TICKS_PER_MS;
+	KeDelayExecutionThread(KPROCESSOR_MODE::KernelMode, FALSE, &interval);
+}
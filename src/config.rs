@@ -0,0 +1,1149 @@
+// Driver configuration read from the service Parameters key.
+// Parsing works on raw values from a ConfigSource, so it does not depend on the kernel registry.
+
+// Imports
+use wchar::{wchz, wchar_t};
+use winapi::um::winnt::{
+	REG_DWORD,
+	REG_SZ,
+	REG_EXPAND_SZ,
+	REG_MULTI_SZ,
+	REG_BINARY
+};
+use crate::log::{
+	Level,
+	COMPONENTS,
+	COMPONENT_COUNT,
+	DEFAULT_LEVEL
+};
+use crate::device::{
+	DeviceRole,
+	DEVICE_ROLES,
+	DEVICE_ROLE_COUNT
+};
+use crate::ratelimit::{
+	RateLimit,
+	RateLimits,
+	RatePolicy
+};
+use crate::lease::ArbitrationPolicy;
+use crate::auth::{
+	AuthKey,
+	AUTH_KEY_MAX
+};
+use crate::session::SessionId;
+use crate::audit::MAX_AUDIT_CAPACITY;
+use crate::sink::SinkKind;
+use crate::allowlist::{
+	AllowedImages,
+	MAX_ALLOWED_IMAGES,
+	IMAGE_PATH_CAPACITY
+};
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Subkey of the service key with driver parameters
+pub const PARAMETERS_KEY_NAME: &[wchar_t] = wchz!("Parameters");
+
+// Value names
+pub const MOUSE_HID_DRIVER_VALUE: &[wchar_t] = wchz!("MouseHidDriver");
+pub const KEYBOARD_HID_DRIVER_VALUE: &[wchar_t] = wchz!("KeyboardHidDriver");
+pub const LOG_LEVEL_VALUE: &[wchar_t] = wchz!("LogLevel");
+pub const MAX_HOLD_MS_VALUE: &[wchar_t] = wchz!("MaxHoldMs");
+pub const START_ARMED_VALUE: &[wchar_t] = wchz!("StartArmed");
+pub const AUTO_DISARM_MS_VALUE: &[wchar_t] = wchz!("AutoDisarmMs");
+pub const RATE_POLICY_VALUE: &[wchar_t] = wchz!("RateLimitPolicy");
+pub const ARBITRATION_POLICY_VALUE: &[wchar_t] = wchz!("ArbitrationPolicy");
+pub const LEASE_WAIT_MS_VALUE: &[wchar_t] = wchz!("LeaseWaitMs");
+pub const ALLOWED_IMAGES_VALUE: &[wchar_t] = wchz!("AllowedImages");
+// REG_BINARY, AUTH_KEY_MIN to AUTH_KEY_MAX bytes. Missing value disables request authentication,
+// an invalid one fails every authenticated request.
+pub const AUTH_KEY_VALUE: &[wchar_t] = wchz!("AuthKey");
+pub const INJECTION_TAG_VALUE: &[wchar_t] = wchz!("InjectionTag");
+pub const TAG_SESSIONS_VALUE: &[wchar_t] = wchz!("TagSessions");
+pub const AUDIT_CAPACITY_VALUE: &[wchar_t] = wchz!("AuditCapacity");
+pub const UNCONSUMED_POLICY_VALUE: &[wchar_t] = wchz!("UnconsumedPolicy");
+pub const UNCONSUMED_RETRIES_VALUE: &[wchar_t] = wchz!("UnconsumedRetries");
+pub const DRY_RUN_VALUE: &[wchar_t] = wchz!("DryRun");
+pub const MOUSE_SINK_VALUE: &[wchar_t] = wchz!("MouseSink");
+pub const KEYBOARD_SINK_VALUE: &[wchar_t] = wchz!("KeyboardSink");
+
+// Defaults
+pub const DEFAULT_MOUSE_HID_DRIVER: &[wchar_t] = wchz!("\\Driver\\MouHID");
+pub const DEFAULT_KEYBOARD_HID_DRIVER: &[wchar_t] = wchz!("\\Driver\\ASWkbd");
+// Presses are released by the watchdog after this time, 0 disables the limit
+pub const DEFAULT_MAX_HOLD_MS: u32 = 30_000;
+pub const MAX_HOLD_MS_LIMIT: u32 = 3_600_000;
+// Injection is allowed after load unless StartArmed is 0
+pub const DEFAULT_START_ARMED: bool = true;
+// Driver disarms when no arm refresh arrives within this time, 0 disables it
+pub const DEFAULT_AUTO_DISARM_MS: u32 = 0;
+pub const AUTO_DISARM_MS_LIMIT: u32 = 86_400_000;
+// Over-limit requests wait for tokens unless RateLimitPolicy is 0 (reject)
+pub const DEFAULT_RATE_POLICY: RatePolicy = RatePolicy::Delay;
+pub const EVENTS_PER_SEC_LIMIT: u32 = 100_000;
+pub const BURST_LIMIT: u32 = 10_000;
+// Leased device stays with its holder until the lease ends
+pub const DEFAULT_ARBITRATION_POLICY: ArbitrationPolicy = ArbitrationPolicy::FirstCome;
+// Other sessions fail at once while a device is leased, unless they may wait
+pub const DEFAULT_LEASE_WAIT_MS: u32 = 0;
+pub const LEASE_WAIT_MS_LIMIT: u32 = 10_000;
+pub const LEASE_MS_LIMIT: u32 = 3_600_000;
+// ExtraInformation of injected events, 0 keeps them indistinguishable from real input
+pub const DEFAULT_INJECTION_TAG: u32 = 0;
+// With TagSessions the low byte of the tag carries the session: ids cycle through 1 to 255,
+// 0 there marks input the driver produced itself, e.g. hold releases
+pub const SESSION_TAG_MASK: u32 = 0xFF;
+// Injections kept in the audit ring, 0 disables it
+pub const DEFAULT_AUDIT_CAPACITY: u32 = 256;
+// Events the class driver did not take are sent again, with doubling delay
+pub const DEFAULT_UNCONSUMED_POLICY: UnconsumedPolicy = UnconsumedPolicy::Retry;
+pub const DEFAULT_UNCONSUMED_RETRIES: u32 = 3;
+pub const UNCONSUMED_RETRIES_LIMIT: u32 = 8;
+// Protected DACL, full access for LocalSystem and Administrators only
+pub const DEFAULT_DEVICE_SDDL: &[wchar_t] = wchz!("D:P(A;;GA;;;SY)(A;;GA;;;BA)");
+
+// Max name length in UTF-16 units, terminating NUL included
+pub const NAME_CAPACITY: usize = 128;
+
+// Max SDDL string length in UTF-16 units, terminating NUL included
+pub const SDDL_CAPACITY: usize = 256;
+
+// AllowedImages value bytes: every path with its NUL and the list terminator
+const IMAGE_LIST_BUFFER_LEN: usize = (IMAGE_PATH_CAPACITY * MAX_ALLOWED_IMAGES + 1) * 2;
+
+// Value names and defaults of one device object
+struct DeviceValues {
+	name_value: &'static [wchar_t],
+	symlink_name_value: &'static [wchar_t],
+	sddl_value: &'static [wchar_t],
+	default_name: &'static [wchar_t],
+	default_symlink_name: &'static [wchar_t]
+}
+
+// Per device values, in DeviceRole order. Control device keeps the original names.
+const DEVICE_VALUES: [DeviceValues; DEVICE_ROLE_COUNT] = [
+	DeviceValues {
+		name_value: wchz!("MouseDeviceName"),
+		symlink_name_value: wchz!("MouseSymlinkName"),
+		sddl_value: wchz!("MouseDeviceSddl"),
+		default_name: wchz!("\\Device\\MetaDriverMouse"),
+		default_symlink_name: wchz!("\\??\\MetaDriverMouse")
+	},
+	DeviceValues {
+		name_value: wchz!("KeyboardDeviceName"),
+		symlink_name_value: wchz!("KeyboardSymlinkName"),
+		sddl_value: wchz!("KeyboardDeviceSddl"),
+		default_name: wchz!("\\Device\\MetaDriverKeyboard"),
+		default_symlink_name: wchz!("\\??\\MetaDriverKeyboard")
+	},
+	DeviceValues {
+		name_value: wchz!("ControlDeviceName"),
+		symlink_name_value: wchz!("ControlSymlinkName"),
+		sddl_value: wchz!("ControlDeviceSddl"),
+		default_name: wchz!("\\Device\\MetaDriver"),
+		default_symlink_name: wchz!("\\??\\MetaDriver")
+	}
+];
+
+// What to do with an event the class service callback did not consume
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum UnconsumedPolicy {
+	// Fail the request at once
+	Report = 0,
+	// Send again after a delay, fail when retries run out
+	Retry = 1
+}
+
+// Value names and defaults of rate limits of one device
+struct RateValues {
+	session_rate_value: &'static [wchar_t],
+	session_burst_value: &'static [wchar_t],
+	global_rate_value: &'static [wchar_t],
+	global_burst_value: &'static [wchar_t],
+	defaults: RateLimits
+}
+
+// Sessions are unlimited by default, driver-wide limits stop floods only
+const MOUSE_RATE_VALUES: RateValues = RateValues {
+	session_rate_value: wchz!("MouseSessionRate"),
+	session_burst_value: wchz!("MouseSessionBurst"),
+	global_rate_value: wchz!("MouseGlobalRate"),
+	global_burst_value: wchz!("MouseGlobalBurst"),
+	defaults: RateLimits {
+		session: RateLimit::UNLIMITED,
+		global: RateLimit {
+			events_per_sec: 4000,
+			burst: 250
+		}
+	}
+};
+
+const KEYBOARD_RATE_VALUES: RateValues = RateValues {
+	session_rate_value: wchz!("KeyboardSessionRate"),
+	session_burst_value: wchz!("KeyboardSessionBurst"),
+	global_rate_value: wchz!("KeyboardGlobalRate"),
+	global_burst_value: wchz!("KeyboardGlobalBurst"),
+	defaults: RateLimits {
+		session: RateLimit::UNLIMITED,
+		global: RateLimit {
+			events_per_sec: 1000,
+			burst: 100
+		}
+	}
+};
+
+// Max number of reported issues, further ones are dropped
+pub const MAX_ISSUES: usize = 16;
+
+// Source of raw registry values. Kernel registry and test fakes implement it.
+pub trait ConfigSource {
+	// Copy value data into buffer (truncated if it does not fit).
+	// Returns value type and full data length, None if value is missing.
+	fn query_value(&self, name: &[wchar_t], buffer: &mut [u8]) -> Option<(u32, usize)>;
+}
+
+// NUL terminated UTF-16 string stored inline
+#[derive(Copy, Clone)]
+pub struct WideName<const N: usize = NAME_CAPACITY> {
+	buf: [wchar_t; N],
+	len: usize
+}
+
+// Names and security of one device object
+#[derive(Copy, Clone, PartialEq)]
+pub struct DeviceConfig {
+	pub name: WideName,
+	pub symlink_name: WideName,
+	pub sddl: WideName<SDDL_CAPACITY>
+}
+
+// Effective driver configuration. Large, kept in static storage and updated in place.
+#[derive(Clone)]
+pub struct DriverConfig {
+	// Indexed by DeviceRole
+	pub devices: [DeviceConfig; DEVICE_ROLE_COUNT],
+	pub mouse_hid_driver: WideName,
+	pub keyboard_hid_driver: WideName,
+	pub log_levels: [Level; COMPONENT_COUNT],
+	pub max_hold_ms: u32,
+	pub start_armed: bool,
+	pub auto_disarm_ms: u32,
+	pub mouse_rate: RateLimits,
+	pub keyboard_rate: RateLimits,
+	pub rate_policy: RatePolicy,
+	pub arbitration_policy: ArbitrationPolicy,
+	pub lease_wait_ms: u32,
+	pub auth_key: AuthKey,
+	pub injection_tag: u32,
+	pub tag_sessions: bool,
+	pub audit_capacity: u32,
+	pub unconsumed_policy: UnconsumedPolicy,
+	pub unconsumed_retries: u32,
+	// Requests are validated, audited and counted but never reach the class drivers
+	pub dry_run: bool,
+	// Where injected events of each device go
+	pub mouse_sink: SinkKind,
+	pub keyboard_sink: SinkKind
+}
+
+// Why a value was ignored
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ConfigIssueKind {
+	WrongType,
+	OutOfRange,
+	TooLong,
+	BadFormat
+}
+
+// Ignored value, its default is used instead
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ConfigIssue {
+	pub value_name: &'static [wchar_t],
+	pub kind: ConfigIssueKind
+}
+
+// Device part of RELOAD_CONFIG reply, NUL terminated strings
+#[repr(C)]
+pub struct DeviceReply {
+	pub name: [wchar_t; NAME_CAPACITY],
+	pub symlink_name: [wchar_t; NAME_CAPACITY],
+	pub sddl: [wchar_t; SDDL_CAPACITY]
+}
+
+// Rate limit part of RELOAD_CONFIG reply, 0 events per second is unlimited
+#[repr(C)]
+pub struct RateLimitReply {
+	pub session_events_per_sec: u32,
+	pub session_burst: u32,
+	pub global_events_per_sec: u32,
+	pub global_burst: u32
+}
+
+// RELOAD_CONFIG reply, effective configuration after reload
+#[repr(C)]
+pub struct ConfigReply {
+	// 1 if a changed value only takes effect after driver restart
+	pub restart_required: u32,
+	// Level per log component, in Component order
+	pub log_levels: [u32; COMPONENT_COUNT],
+	// In DeviceRole order
+	pub devices: [DeviceReply; DEVICE_ROLE_COUNT],
+	// NUL terminated names
+	pub mouse_hid_driver: [wchar_t; NAME_CAPACITY],
+	pub keyboard_hid_driver: [wchar_t; NAME_CAPACITY],
+	pub max_hold_ms: u32,
+	pub start_armed: u32,
+	pub auto_disarm_ms: u32,
+	pub mouse_rate: RateLimitReply,
+	pub keyboard_rate: RateLimitReply,
+	// RatePolicy
+	pub rate_policy: u32,
+	// ArbitrationPolicy
+	pub arbitration_policy: u32,
+	pub lease_wait_ms: u32,
+	// Allowed NT image paths, NUL terminated, unused entries empty
+	pub allowed_image_count: u32,
+	pub allowed_images: [[wchar_t; IMAGE_PATH_CAPACITY]; MAX_ALLOWED_IMAGES],
+	// 1 if requests must be authenticated, the key itself is never returned
+	pub auth_enabled: u32,
+	pub injection_tag: u32,
+	pub tag_sessions: u32,
+	pub audit_capacity: u32,
+	// UnconsumedPolicy
+	pub unconsumed_policy: u32,
+	pub unconsumed_retries: u32,
+	pub dry_run: u32,
+	// SinkKind
+	pub mouse_sink: u32,
+	pub keyboard_sink: u32
+}
+
+// Configuration read from the registry, validated before it is applied.
+// Too large for the kernel stack, lives in static storage and is loaded in place.
+pub struct ConfigStaging {
+	pub config: DriverConfig,
+	pub allowed_images: AllowedImages,
+	// Raw AllowedImages value
+	buffer: [u8; IMAGE_LIST_BUFFER_LEN]
+}
+
+// Issues found while loading configuration
+pub struct ConfigReport {
+	issues: [Option<ConfigIssue>; MAX_ISSUES],
+	count: usize
+}
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl<const N: usize> WideName<N> {
+	// Name from NUL terminated constant, truncated to capacity
+	pub const fn from_wchz(name: &[wchar_t]) -> WideName<N> {
+		let mut buf = [0 as wchar_t; N];
+		let mut len = 0;
+		while len < name.len() && len < N - 1 && name[len] != 0 {
+			buf[len] = name[len];
+			len += 1;
+		}
+		WideName {
+			buf,
+			len
+		}
+	}
+
+	// Name from units without NUL, None if too long
+	pub fn from_units(units: &[wchar_t]) -> Option<WideName<N>> {
+		if units.len() >= N {
+			return None;
+		}
+		let mut name = WideName::<N>::from_wchz(&[]);
+		name.buf[..units.len()].copy_from_slice(units);
+		name.len = units.len();
+		Some(name)
+	}
+
+	// Units with terminating NUL, e.g. for RtlInitUnicodeString
+	pub fn as_wchz(&self) -> &[wchar_t] {
+		&self.buf[..self.len + 1]
+	}
+
+	// Units without NUL
+	pub fn as_units(&self) -> &[wchar_t] {
+		&self.buf[..self.len]
+	}
+}
+
+impl<const N: usize> PartialEq for WideName<N> {
+	fn eq(&self, other: &WideName<N>) -> bool {
+		self.as_units() == other.as_units()
+	}
+}
+
+impl ConfigIssueKind {
+	pub fn description(self) -> &'static str {
+		match self {
+			ConfigIssueKind::WrongType => "wrong value type",
+			ConfigIssueKind::OutOfRange => "out of range",
+			ConfigIssueKind::TooLong => "too long",
+			ConfigIssueKind::BadFormat => "bad format"
+		}
+	}
+}
+
+impl Default for ConfigReport {
+	fn default() -> ConfigReport {
+		ConfigReport::new()
+	}
+}
+
+impl ConfigReport {
+	pub const fn new() -> ConfigReport {
+		ConfigReport {
+			issues: [None; MAX_ISSUES],
+			count: 0
+		}
+	}
+
+	fn push(&mut self, value_name: &'static [wchar_t], kind: ConfigIssueKind) {
+		if self.count < MAX_ISSUES {
+			self.issues[self.count] = Some(ConfigIssue {
+				value_name,
+				kind
+			});
+			self.count += 1;
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.count == 0
+	}
+
+	pub fn issues(&self) -> impl Iterator<Item = &ConfigIssue> {
+		self.issues[..self.count].iter().filter_map(|issue| issue.as_ref())
+	}
+}
+
+impl DriverConfig {
+	// Built-in defaults, used when Parameters key or a value is missing
+	pub const fn defaults() -> DriverConfig {
+		DriverConfig {
+			devices: [
+				DeviceConfig::defaults(DeviceRole::Mouse),
+				DeviceConfig::defaults(DeviceRole::Keyboard),
+				DeviceConfig::defaults(DeviceRole::Control)
+			],
+			mouse_hid_driver: WideName::from_wchz(DEFAULT_MOUSE_HID_DRIVER),
+			keyboard_hid_driver: WideName::from_wchz(DEFAULT_KEYBOARD_HID_DRIVER),
+			log_levels: [DEFAULT_LEVEL; COMPONENT_COUNT],
+			max_hold_ms: DEFAULT_MAX_HOLD_MS,
+			start_armed: DEFAULT_START_ARMED,
+			auto_disarm_ms: DEFAULT_AUTO_DISARM_MS,
+			mouse_rate: MOUSE_RATE_VALUES.defaults,
+			keyboard_rate: KEYBOARD_RATE_VALUES.defaults,
+			rate_policy: DEFAULT_RATE_POLICY,
+			arbitration_policy: DEFAULT_ARBITRATION_POLICY,
+			lease_wait_ms: DEFAULT_LEASE_WAIT_MS,
+			auth_key: AuthKey::empty(),
+			injection_tag: DEFAULT_INJECTION_TAG,
+			tag_sessions: false,
+			audit_capacity: DEFAULT_AUDIT_CAPACITY,
+			unconsumed_policy: DEFAULT_UNCONSUMED_POLICY,
+			unconsumed_retries: DEFAULT_UNCONSUMED_RETRIES,
+			dry_run: false,
+			mouse_sink: SinkKind::Class,
+			keyboard_sink: SinkKind::Class
+		}
+	}
+
+	// Take values which can change at runtime from a newer configuration.
+	// Names and SDDL stay, devices already exist and discovery uses the loaded names.
+	// Returns true if a name differs, restart is needed to apply it.
+	pub fn apply_runtime(&mut self, newer: &DriverConfig) -> bool {
+		self.log_levels = newer.log_levels;
+		self.max_hold_ms = newer.max_hold_ms;
+		self.start_armed = newer.start_armed;
+		self.auto_disarm_ms = newer.auto_disarm_ms;
+		self.mouse_rate = newer.mouse_rate;
+		self.keyboard_rate = newer.keyboard_rate;
+		self.rate_policy = newer.rate_policy;
+		self.arbitration_policy = newer.arbitration_policy;
+		self.lease_wait_ms = newer.lease_wait_ms;
+		self.auth_key = newer.auth_key;
+		self.injection_tag = newer.injection_tag;
+		self.tag_sessions = newer.tag_sessions;
+		self.audit_capacity = newer.audit_capacity;
+		self.unconsumed_policy = newer.unconsumed_policy;
+		self.unconsumed_retries = newer.unconsumed_retries;
+		self.dry_run = newer.dry_run;
+		self.mouse_sink = newer.mouse_sink;
+		self.keyboard_sink = newer.keyboard_sink;
+		self.devices != newer.devices
+			|| self.mouse_hid_driver != newer.mouse_hid_driver
+			|| self.keyboard_hid_driver != newer.keyboard_hid_driver
+	}
+
+	// ExtraInformation of events injected for session, None for driver produced input
+	pub fn extra_information(&self, session: Option<SessionId>) -> u32 {
+		if !self.tag_sessions {
+			return self.injection_tag;
+		}
+		let session_tag = session.map_or(0, |session| ((session.value() - 1) % SESSION_TAG_MASK as u64) as u32 + 1);
+		(self.injection_tag & !SESSION_TAG_MASK) | session_tag
+	}
+
+	// Fill reply in place, it is too large to be built on the kernel stack
+	pub fn fill_reply(&self, allowed_images: &AllowedImages, reply: &mut ConfigReply, restart_required: bool) {
+		reply.restart_required = restart_required as u32;
+		for (reply_level, level) in reply.log_levels.iter_mut().zip(self.log_levels.iter()) {
+			*reply_level = *level as u32;
+		}
+		for (reply_device, device) in reply.devices.iter_mut().zip(self.devices.iter()) {
+			reply_device.name = device.name.buf;
+			reply_device.symlink_name = device.symlink_name.buf;
+			reply_device.sddl = device.sddl.buf;
+		}
+		reply.mouse_hid_driver = self.mouse_hid_driver.buf;
+		reply.keyboard_hid_driver = self.keyboard_hid_driver.buf;
+		reply.max_hold_ms = self.max_hold_ms;
+		reply.start_armed = self.start_armed as u32;
+		reply.auto_disarm_ms = self.auto_disarm_ms;
+		reply.mouse_rate = RateLimitReply::from(&self.mouse_rate);
+		reply.keyboard_rate = RateLimitReply::from(&self.keyboard_rate);
+		reply.rate_policy = self.rate_policy as u32;
+		reply.arbitration_policy = self.arbitration_policy as u32;
+		reply.lease_wait_ms = self.lease_wait_ms;
+		reply.allowed_image_count = allowed_images.paths().len() as u32;
+		for (reply_path, path) in reply.allowed_images.iter_mut().zip(allowed_images.paths().iter()) {
+			*reply_path = path.buf;
+		}
+		reply.auth_enabled = self.auth_key.is_required() as u32;
+		reply.injection_tag = self.injection_tag;
+		reply.tag_sessions = self.tag_sessions as u32;
+		reply.audit_capacity = self.audit_capacity;
+		reply.unconsumed_policy = self.unconsumed_policy as u32;
+		reply.unconsumed_retries = self.unconsumed_retries;
+		reply.dry_run = self.dry_run as u32;
+		reply.mouse_sink = self.mouse_sink as u32;
+		reply.keyboard_sink = self.keyboard_sink as u32;
+	}
+}
+
+impl Default for ConfigStaging {
+	fn default() -> ConfigStaging {
+		ConfigStaging::new()
+	}
+}
+
+impl ConfigStaging {
+	pub const fn new() -> ConfigStaging {
+		ConfigStaging {
+			config: DriverConfig::defaults(),
+			allowed_images: AllowedImages::new(),
+			buffer: [0; IMAGE_LIST_BUFFER_LEN]
+		}
+	}
+
+	// Built-in defaults, used when Parameters key is missing
+	pub fn reset(&mut self) {
+		self.config = DriverConfig::defaults();
+		self.allowed_images.clear();
+	}
+
+	// Read and validate all values. Invalid values are reported and replaced by defaults.
+	pub fn load(&mut self, source: &dyn ConfigSource, report: &mut ConfigReport) {
+		self.reset();
+		let config = &mut self.config;
+
+		let default_level = read_dword(source, LOG_LEVEL_VALUE, Level::Off as u32, Level::Trace as u32, report)
+			.map(Level::from_u32)
+			.unwrap_or(DEFAULT_LEVEL);
+		config.log_levels = [default_level; COMPONENT_COUNT];
+		for component in COMPONENTS.iter() {
+			if let Some(level) = read_dword(source, component.level_value_name(), Level::Off as u32, Level::Trace as u32, report) {
+				config.log_levels[*component as usize] = Level::from_u32(level);
+			}
+		}
+
+		for role in DEVICE_ROLES.iter() {
+			config.devices[*role as usize] = DeviceConfig::load(*role, source, report);
+		}
+
+		config.mouse_hid_driver = read_name(source, MOUSE_HID_DRIVER_VALUE, &["\\Driver\\"], report)
+			.unwrap_or(config.mouse_hid_driver);
+		config.keyboard_hid_driver = read_name(source, KEYBOARD_HID_DRIVER_VALUE, &["\\Driver\\"], report)
+			.unwrap_or(config.keyboard_hid_driver);
+		config.max_hold_ms = read_dword(source, MAX_HOLD_MS_VALUE, 0, MAX_HOLD_MS_LIMIT, report)
+			.unwrap_or(config.max_hold_ms);
+		config.start_armed = read_dword(source, START_ARMED_VALUE, 0, 1, report)
+			.map(|start_armed| start_armed != 0)
+			.unwrap_or(config.start_armed);
+		config.auto_disarm_ms = read_dword(source, AUTO_DISARM_MS_VALUE, 0, AUTO_DISARM_MS_LIMIT, report)
+			.unwrap_or(config.auto_disarm_ms);
+		config.mouse_rate = MOUSE_RATE_VALUES.load(source, report);
+		config.keyboard_rate = KEYBOARD_RATE_VALUES.load(source, report);
+		config.rate_policy = read_dword(source, RATE_POLICY_VALUE, RatePolicy::Reject as u32, RatePolicy::Delay as u32, report)
+			.map(RatePolicy::from_u32)
+			.unwrap_or(config.rate_policy);
+		config.arbitration_policy = read_dword(source, ARBITRATION_POLICY_VALUE,
+			ArbitrationPolicy::FirstCome as u32, ArbitrationPolicy::Priority as u32, report)
+			.map(ArbitrationPolicy::from_u32)
+			.unwrap_or(config.arbitration_policy);
+		config.lease_wait_ms = read_dword(source, LEASE_WAIT_MS_VALUE, 0, LEASE_WAIT_MS_LIMIT, report)
+			.unwrap_or(config.lease_wait_ms);
+		read_image_list(source, ALLOWED_IMAGES_VALUE, &mut self.buffer, &mut self.allowed_images, report);
+		config.auth_key = read_auth_key(source, AUTH_KEY_VALUE, report)
+			.unwrap_or(config.auth_key);
+		config.injection_tag = read_dword(source, INJECTION_TAG_VALUE, 0, u32::MAX, report)
+			.unwrap_or(config.injection_tag);
+		config.tag_sessions = read_dword(source, TAG_SESSIONS_VALUE, 0, 1, report)
+			.map(|tag_sessions| tag_sessions != 0)
+			.unwrap_or(config.tag_sessions);
+		config.audit_capacity = read_dword(source, AUDIT_CAPACITY_VALUE, 0, MAX_AUDIT_CAPACITY as u32, report)
+			.unwrap_or(config.audit_capacity);
+		config.unconsumed_policy = read_dword(source, UNCONSUMED_POLICY_VALUE,
+			UnconsumedPolicy::Report as u32, UnconsumedPolicy::Retry as u32, report)
+			.map(UnconsumedPolicy::from_u32)
+			.unwrap_or(config.unconsumed_policy);
+		config.unconsumed_retries = read_dword(source, UNCONSUMED_RETRIES_VALUE, 0, UNCONSUMED_RETRIES_LIMIT, report)
+			.unwrap_or(config.unconsumed_retries);
+		config.dry_run = read_dword(source, DRY_RUN_VALUE, 0, 1, report)
+			.map(|dry_run| dry_run != 0)
+			.unwrap_or(config.dry_run);
+		config.mouse_sink = read_dword(source, MOUSE_SINK_VALUE, SinkKind::Class as u32, SinkKind::Null as u32, report)
+			.map(SinkKind::from_u32)
+			.unwrap_or(config.mouse_sink);
+		config.keyboard_sink = read_dword(source, KEYBOARD_SINK_VALUE, SinkKind::Class as u32, SinkKind::Null as u32, report)
+			.map(SinkKind::from_u32)
+			.unwrap_or(config.keyboard_sink);
+	}
+}
+
+impl UnconsumedPolicy {
+	pub fn from_u32(value: u32) -> UnconsumedPolicy {
+		match value {
+			0 => UnconsumedPolicy::Report,
+			_ => UnconsumedPolicy::Retry
+		}
+	}
+}
+
+impl RateLimitReply {
+	fn from(limits: &RateLimits) -> RateLimitReply {
+		RateLimitReply {
+			session_events_per_sec: limits.session.events_per_sec,
+			session_burst: limits.session.burst,
+			global_events_per_sec: limits.global.events_per_sec,
+			global_burst: limits.global.burst
+		}
+	}
+}
+
+impl DeviceConfig {
+	pub const fn defaults(role: DeviceRole) -> DeviceConfig {
+		let values = &DEVICE_VALUES[role as usize];
+		DeviceConfig {
+			name: WideName::from_wchz(values.default_name),
+			symlink_name: WideName::from_wchz(values.default_symlink_name),
+			sddl: WideName::from_wchz(DEFAULT_DEVICE_SDDL)
+		}
+	}
+
+	fn load(role: DeviceRole, source: &dyn ConfigSource, report: &mut ConfigReport) -> DeviceConfig {
+		let values = &DEVICE_VALUES[role as usize];
+		let defaults = DeviceConfig::defaults(role);
+		DeviceConfig {
+			name: read_name(source, values.name_value, &["\\Device\\"], report)
+				.unwrap_or(defaults.name),
+			symlink_name: read_name(source, values.symlink_name_value, &["\\??\\", "\\DosDevices\\"], report)
+				.unwrap_or(defaults.symlink_name),
+			// Only the DACL prefix is checked here, IoCreateDeviceSecure parses the rest
+			sddl: read_name(source, values.sddl_value, &["D:"], report)
+				.unwrap_or(defaults.sddl)
+		}
+	}
+}
+
+// PRIVATE FUNCTIONS ==========================================
+
+// REG_DWORD value within [min, max]
+fn read_dword(source: &dyn ConfigSource, name: &'static [wchar_t], min: u32, max: u32, report: &mut ConfigReport) -> Option<u32> {
+	let mut buffer = [0u8; 4];
+	match source.query_value(name, &mut buffer) {
+		None => None,
+		Some((REG_DWORD, 4)) => {
+			let value = u32::from_le_bytes(buffer);
+			if value < min || value > max {
+				report.push(name, ConfigIssueKind::OutOfRange);
+				None
+			} else {
+				Some(value)
+			}
+		}
+		Some(_) => {
+			report.push(name, ConfigIssueKind::WrongType);
+			None
+		}
+	}
+}
+
+impl RateValues {
+	// Each value falls back to its own default
+	fn load(&self, source: &dyn ConfigSource, report: &mut ConfigReport) -> RateLimits {
+		RateLimits {
+			session: RateLimit {
+				events_per_sec: read_dword(source, self.session_rate_value, 0, EVENTS_PER_SEC_LIMIT, report)
+					.unwrap_or(self.defaults.session.events_per_sec),
+				burst: read_dword(source, self.session_burst_value, 1, BURST_LIMIT, report)
+					.unwrap_or(self.defaults.session.burst)
+			},
+			global: RateLimit {
+				events_per_sec: read_dword(source, self.global_rate_value, 0, EVENTS_PER_SEC_LIMIT, report)
+					.unwrap_or(self.defaults.global.events_per_sec),
+				burst: read_dword(source, self.global_burst_value, 1, BURST_LIMIT, report)
+					.unwrap_or(self.defaults.global.burst)
+			}
+		}
+	}
+}
+
+// REG_BINARY shared key. An invalid key is reported and every authenticated request fails,
+// falling back to no authentication would open the driver to any caller.
+fn read_auth_key(source: &dyn ConfigSource, name: &'static [wchar_t], report: &mut ConfigReport) -> Option<AuthKey> {
+	let mut buffer = [0u8; AUTH_KEY_MAX];
+	let (value_type, length) = source.query_value(name, &mut buffer)?;
+	if value_type != REG_BINARY {
+		report.push(name, ConfigIssueKind::WrongType);
+		return Some(AuthKey::invalid());
+	}
+	if length > buffer.len() {
+		report.push(name, ConfigIssueKind::TooLong);
+		return Some(AuthKey::invalid());
+	}
+	let key = AuthKey::from_bytes(&buffer[..length]);
+	if key.is_none() {
+		report.push(name, ConfigIssueKind::OutOfRange);
+	}
+	Some(key.unwrap_or(AuthKey::invalid()))
+}
+
+// REG_MULTI_SZ list of NT image paths, decoded into images in place.
+// A bad value rejects every process, a partial or dropped allowlist could let in the wrong one.
+fn read_image_list(source: &dyn ConfigSource, name: &'static [wchar_t], buffer: &mut [u8; IMAGE_LIST_BUFFER_LEN],
+	images: &mut AllowedImages, report: &mut ConfigReport) {
+	images.clear();
+	let (value_type, length) = match source.query_value(name, buffer) {
+		Some(value) => value,
+		None => return
+	};
+	if value_type != REG_MULTI_SZ {
+		images.reject_all();
+		report.push(name, ConfigIssueKind::WrongType);
+		return;
+	}
+	if length > buffer.len() {
+		images.reject_all();
+		report.push(name, ConfigIssueKind::TooLong);
+		return;
+	}
+
+	// One entry at a time, a last entry without NUL is taken too
+	let mut entry = [0 as wchar_t; IMAGE_PATH_CAPACITY];
+	let mut entry_len = 0;
+	let units = buffer[..length].chunks_exact(2)
+		.map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+		.chain(core::iter::once(0));
+	for unit in units {
+		let issue = if unit == 0 {
+			if entry_len == 0 {
+				continue;
+			}
+			let pushed = WideName::from_units(&entry[..entry_len]).map(|path| images.push(path));
+			entry_len = 0;
+			if pushed == Some(true) {
+				continue;
+			}
+			ConfigIssueKind::TooLong
+		} else if entry_len == 0 && unit != '\\' as wchar_t {
+			ConfigIssueKind::BadFormat
+		} else if entry_len == entry.len() {
+			ConfigIssueKind::TooLong
+		} else {
+			entry[entry_len] = unit;
+			entry_len += 1;
+			continue;
+		};
+		images.reject_all();
+		report.push(name, issue);
+		return;
+	}
+}
+
+// REG_SZ string starting with one of prefixes (ASCII, case-insensitive)
+fn read_name<const N: usize>(source: &dyn ConfigSource, name: &'static [wchar_t], prefixes: &[&str], report: &mut ConfigReport) -> Option<WideName<N>> {
+	let mut buffer = [0u8; SDDL_CAPACITY * 2];
+	let (value_type, length) = source.query_value(name, &mut buffer)?;
+	if value_type != REG_SZ && value_type != REG_EXPAND_SZ {
+		report.push(name, ConfigIssueKind::WrongType);
+		return None;
+	}
+	if length > N * 2 {
+		report.push(name, ConfigIssueKind::TooLong);
+		return None;
+	}
+
+	// Decode units, registry strings usually carry their own NUL
+	let mut units = [0 as wchar_t; N];
+	let mut units_len = 0;
+	for pair in buffer[..length].chunks_exact(2) {
+		let unit = u16::from_le_bytes([pair[0], pair[1]]);
+		if unit == 0 {
+			break;
+		}
+		units[units_len] = unit;
+		units_len += 1;
+	}
+	let units = &units[..units_len];
+
+	let prefix_found = prefixes.iter().any(|prefix| {
+		let prefix = prefix.as_bytes();
+		units.len() > prefix.len()
+			&& units.iter().zip(prefix.iter()).all(|(unit, byte)| {
+				*unit < 0x80 && (*unit as u8).eq_ignore_ascii_case(byte)
+			})
+	});
+	if !prefix_found {
+		report.push(name, ConfigIssueKind::BadFormat);
+		return None;
+	}
+
+	match WideName::from_units(units) {
+		Some(wide_name) => Some(wide_name),
+		None => {
+			report.push(name, ConfigIssueKind::TooLong);
+			None
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use winapi::um::winnt::REG_QWORD;
+	use crate::log::Component;
+
+	// In-memory Parameters key
+	struct FakeKey {
+		values: Vec<(&'static [wchar_t], u32, Vec<u8>)>
+	}
+
+	impl FakeKey {
+		fn new() -> FakeKey {
+			FakeKey {
+				values: Vec::new()
+			}
+		}
+
+		fn raw(mut self, name: &'static [wchar_t], value_type: u32, data: Vec<u8>) -> FakeKey {
+			self.values.push((name, value_type, data));
+			self
+		}
+
+		fn dword(self, name: &'static [wchar_t], value: u32) -> FakeKey {
+			self.raw(name, REG_DWORD, value.to_le_bytes().to_vec())
+		}
+
+		fn string(self, name: &'static [wchar_t], value: &str) -> FakeKey {
+			self.raw(name, REG_SZ, utf16(&[value]))
+		}
+
+		fn multi_string(self, name: &'static [wchar_t], values: &[&str]) -> FakeKey {
+			let mut data = utf16(values);
+			data.extend_from_slice(&[0, 0]);
+			self.raw(name, REG_MULTI_SZ, data)
+		}
+	}
+
+	impl ConfigSource for FakeKey {
+		fn query_value(&self, name: &[wchar_t], buffer: &mut [u8]) -> Option<(u32, usize)> {
+			let (_, value_type, data) = self.values.iter().find(|(value_name, _, _)| *value_name == name)?;
+			let copied = data.len().min(buffer.len());
+			buffer[..copied].copy_from_slice(&data[..copied]);
+			Some((*value_type, data.len()))
+		}
+	}
+
+	// Little endian UTF-16 bytes, every string NUL terminated
+	fn utf16(values: &[&str]) -> Vec<u8> {
+		values.iter()
+			.flat_map(|value| value.encode_utf16().chain(core::iter::once(0)))
+			.flat_map(|unit| unit.to_le_bytes())
+			.collect()
+	}
+
+	fn units(value: &str) -> Vec<wchar_t> {
+		value.encode_utf16().collect()
+	}
+
+	fn load(source: &FakeKey) -> (DriverConfig, AllowedImages, Vec<ConfigIssue>) {
+		let mut staging = Box::new(ConfigStaging::new());
+		let mut report = ConfigReport::new();
+		staging.load(source, &mut report);
+		let issues = report.issues().copied().collect();
+		(staging.config.clone(), staging.allowed_images, issues)
+	}
+
+	fn issue(value_name: &'static [wchar_t], kind: ConfigIssueKind) -> ConfigIssue {
+		ConfigIssue {
+			value_name,
+			kind
+		}
+	}
+
+	#[test]
+	fn empty_key_gives_defaults() {
+		let (config, images, issues) = load(&FakeKey::new());
+		assert!(issues.is_empty());
+		let defaults = DriverConfig::defaults();
+		assert!(config.devices == defaults.devices);
+		assert_eq!(config.devices[DeviceRole::Mouse as usize].name.as_units(), &units("\\Device\\MetaDriverMouse")[..]);
+		assert_eq!(config.devices[DeviceRole::Control as usize].symlink_name.as_units(), &units("\\??\\MetaDriver")[..]);
+		assert_eq!(config.mouse_hid_driver.as_units(), &units("\\Driver\\MouHID")[..]);
+		assert_eq!(config.keyboard_hid_driver.as_units(), &units("\\Driver\\ASWkbd")[..]);
+		assert_eq!(config.log_levels, [DEFAULT_LEVEL; COMPONENT_COUNT]);
+		assert_eq!(config.max_hold_ms, DEFAULT_MAX_HOLD_MS);
+		assert_eq!(config.start_armed, DEFAULT_START_ARMED);
+		assert_eq!(config.auto_disarm_ms, DEFAULT_AUTO_DISARM_MS);
+		assert_eq!(config.mouse_rate, MOUSE_RATE_VALUES.defaults);
+		assert_eq!(config.keyboard_rate, KEYBOARD_RATE_VALUES.defaults);
+		assert_eq!(config.rate_policy, DEFAULT_RATE_POLICY);
+		assert_eq!(config.lease_wait_ms, DEFAULT_LEASE_WAIT_MS);
+		assert!(images.is_empty());
+		assert!(config.auth_key.is_empty());
+		assert_eq!(config.audit_capacity, DEFAULT_AUDIT_CAPACITY);
+		assert_eq!(config.unconsumed_policy, DEFAULT_UNCONSUMED_POLICY);
+		assert_eq!(config.unconsumed_retries, DEFAULT_UNCONSUMED_RETRIES);
+		assert!(!config.dry_run);
+		assert_eq!(config.mouse_sink, SinkKind::Class);
+		assert_eq!(config.keyboard_sink, SinkKind::Class);
+	}
+
+	#[test]
+	fn valid_values_are_taken() {
+		let source = FakeKey::new()
+			.dword(LOG_LEVEL_VALUE, Level::Warn as u32)
+			.dword(Component::Mouse.level_value_name(), Level::Trace as u32)
+			.dword(MAX_HOLD_MS_VALUE, 0)
+			.dword(START_ARMED_VALUE, 0)
+			.dword(wchz!("MouseSessionRate"), 50)
+			.dword(wchz!("MouseSessionBurst"), 5)
+			.dword(KEYBOARD_SINK_VALUE, SinkKind::Loopback as u32)
+			.string(MOUSE_HID_DRIVER_VALUE, "\\driver\\MyMouHID")
+			.string(wchz!("KeyboardSymlinkName"), "\\DosDevices\\Keys");
+		let (config, _, issues) = load(&source);
+		assert!(issues.is_empty());
+		let mut levels = [Level::Warn; COMPONENT_COUNT];
+		levels[Component::Mouse as usize] = Level::Trace;
+		assert_eq!(config.log_levels, levels);
+		assert_eq!(config.max_hold_ms, 0);
+		assert!(!config.start_armed);
+		assert_eq!(config.mouse_rate.session, RateLimit { events_per_sec: 50, burst: 5 });
+		assert_eq!(config.mouse_rate.global, MOUSE_RATE_VALUES.defaults.global);
+		assert_eq!(config.keyboard_sink, SinkKind::Loopback);
+		assert_eq!(config.mouse_hid_driver.as_units(), &units("\\driver\\MyMouHID")[..]);
+		assert_eq!(config.devices[DeviceRole::Keyboard as usize].symlink_name.as_units(), &units("\\DosDevices\\Keys")[..]);
+	}
+
+	#[test]
+	fn out_of_range_values_are_rejected() {
+		let source = FakeKey::new()
+			.dword(MAX_HOLD_MS_VALUE, MAX_HOLD_MS_LIMIT + 1)
+			.dword(START_ARMED_VALUE, 2)
+			.dword(LOG_LEVEL_VALUE, Level::Trace as u32 + 1)
+			.dword(wchz!("KeyboardGlobalBurst"), 0)
+			.dword(UNCONSUMED_RETRIES_VALUE, UNCONSUMED_RETRIES_LIMIT + 1)
+			.dword(MOUSE_SINK_VALUE, SinkKind::Null as u32 + 1);
+		let (config, _, issues) = load(&source);
+		assert_eq!(issues, vec![
+			issue(LOG_LEVEL_VALUE, ConfigIssueKind::OutOfRange),
+			issue(MAX_HOLD_MS_VALUE, ConfigIssueKind::OutOfRange),
+			issue(START_ARMED_VALUE, ConfigIssueKind::OutOfRange),
+			issue(wchz!("KeyboardGlobalBurst"), ConfigIssueKind::OutOfRange),
+			issue(UNCONSUMED_RETRIES_VALUE, ConfigIssueKind::OutOfRange),
+			issue(MOUSE_SINK_VALUE, ConfigIssueKind::OutOfRange)
+		]);
+		assert_eq!(config.log_levels, [DEFAULT_LEVEL; COMPONENT_COUNT]);
+		assert_eq!(config.max_hold_ms, DEFAULT_MAX_HOLD_MS);
+		assert_eq!(config.start_armed, DEFAULT_START_ARMED);
+		assert_eq!(config.keyboard_rate, KEYBOARD_RATE_VALUES.defaults);
+		assert_eq!(config.unconsumed_retries, DEFAULT_UNCONSUMED_RETRIES);
+		assert_eq!(config.mouse_sink, SinkKind::Class);
+	}
+
+	#[test]
+	fn wrong_value_types_are_rejected() {
+		let source = FakeKey::new()
+			.string(MAX_HOLD_MS_VALUE, "5000")
+			.raw(AUTO_DISARM_MS_VALUE, REG_QWORD, 5000u64.to_le_bytes().to_vec())
+			.dword(MOUSE_HID_DRIVER_VALUE, 1)
+			.string(AUTH_KEY_VALUE, "secret")
+			.string(ALLOWED_IMAGES_VALUE, "\\Device\\HarddiskVolume1\\app.exe");
+		let (config, images, issues) = load(&source);
+		assert_eq!(issues, vec![
+			issue(MOUSE_HID_DRIVER_VALUE, ConfigIssueKind::WrongType),
+			issue(MAX_HOLD_MS_VALUE, ConfigIssueKind::WrongType),
+			issue(AUTO_DISARM_MS_VALUE, ConfigIssueKind::WrongType),
+			issue(ALLOWED_IMAGES_VALUE, ConfigIssueKind::WrongType),
+			issue(AUTH_KEY_VALUE, ConfigIssueKind::WrongType)
+		]);
+		assert_eq!(config.max_hold_ms, DEFAULT_MAX_HOLD_MS);
+		assert_eq!(config.auto_disarm_ms, DEFAULT_AUTO_DISARM_MS);
+		assert_eq!(config.mouse_hid_driver.as_units(), &units("\\Driver\\MouHID")[..]);
+		assert!(config.auth_key == AuthKey::invalid());
+		assert!(images.is_empty());
+	}
+
+	#[test]
+	fn too_long_strings_are_rejected() {
+		let long_name = format!("\\Driver\\{}", "x".repeat(NAME_CAPACITY));
+		let long_sddl = format!("D:{}", "(A;;GA;;;SY)".repeat(SDDL_CAPACITY / 10));
+		let source = FakeKey::new()
+			.string(KEYBOARD_HID_DRIVER_VALUE, &long_name)
+			.string(wchz!("ControlDeviceSddl"), &long_sddl)
+			.raw(AUTH_KEY_VALUE, REG_BINARY, vec![7; AUTH_KEY_MAX + 1]);
+		let (config, _, issues) = load(&source);
+		assert_eq!(issues, vec![
+			issue(wchz!("ControlDeviceSddl"), ConfigIssueKind::TooLong),
+			issue(KEYBOARD_HID_DRIVER_VALUE, ConfigIssueKind::TooLong),
+			issue(AUTH_KEY_VALUE, ConfigIssueKind::TooLong)
+		]);
+		assert!(config.devices == DriverConfig::defaults().devices);
+		assert_eq!(config.keyboard_hid_driver.as_units(), &units("\\Driver\\ASWkbd")[..]);
+		assert!(config.auth_key == AuthKey::invalid());
+	}
+
+	#[test]
+	fn name_needs_prefix() {
+		let source = FakeKey::new()
+			.string(wchz!("MouseDeviceName"), "MetaDriverMouse")
+			.string(wchz!("MouseDeviceSddl"), "O:BA")
+			// Prefix alone is no name
+			.string(KEYBOARD_HID_DRIVER_VALUE, "\\Driver\\");
+		let (config, _, issues) = load(&source);
+		assert_eq!(issues, vec![
+			issue(wchz!("MouseDeviceName"), ConfigIssueKind::BadFormat),
+			issue(wchz!("MouseDeviceSddl"), ConfigIssueKind::BadFormat),
+			issue(KEYBOARD_HID_DRIVER_VALUE, ConfigIssueKind::BadFormat)
+		]);
+		assert!(config.devices == DriverConfig::defaults().devices);
+		assert_eq!(config.keyboard_hid_driver.as_units(), &units("\\Driver\\ASWkbd")[..]);
+	}
+
+	#[test]
+	fn allowlist_is_read() {
+		let source = FakeKey::new().multi_string(ALLOWED_IMAGES_VALUE, &[
+			"\\Device\\HarddiskVolume1\\Tools\\app.exe",
+			"\\Device\\HarddiskVolume2\\bot.exe"
+		]);
+		let (_, images, issues) = load(&source);
+		assert!(issues.is_empty());
+		assert_eq!(images.paths().len(), 2);
+		assert!(images.allows(&units("\\device\\harddiskvolume2\\BOT.EXE")));
+		assert!(!images.allows(&units("\\Device\\HarddiskVolume1\\other.exe")));
+	}
+
+	#[test]
+	fn allowlist_with_bad_prefix_denies_all() {
+		let source = FakeKey::new().multi_string(ALLOWED_IMAGES_VALUE, &[
+			"\\Device\\HarddiskVolume1\\app.exe",
+			"C:\\Tools\\app.exe"
+		]);
+		let (_, images, issues) = load(&source);
+		assert_eq!(issues, vec![issue(ALLOWED_IMAGES_VALUE, ConfigIssueKind::BadFormat)]);
+		assert!(images.is_empty());
+		assert!(!images.allows(&units("\\Device\\HarddiskVolume1\\app.exe")));
+	}
+
+	#[test]
+	fn allowlist_over_capacity_denies_all() {
+		let paths: Vec<String> = (0..MAX_ALLOWED_IMAGES + 1)
+			.map(|index| format!("\\Device\\HarddiskVolume1\\app{}.exe", index))
+			.collect();
+		let paths: Vec<&str> = paths.iter().map(|path| path.as_str()).collect();
+		let (_, images, issues) = load(&FakeKey::new().multi_string(ALLOWED_IMAGES_VALUE, &paths));
+		assert_eq!(issues, vec![issue(ALLOWED_IMAGES_VALUE, ConfigIssueKind::TooLong)]);
+		assert!(images.is_empty());
+		assert!(!images.allows(&units("\\Device\\HarddiskVolume1\\app0.exe")));
+	}
+
+	#[test]
+	fn allowlist_of_wrong_type_denies_all() {
+		let (_, images, issues) = load(&FakeKey::new().dword(ALLOWED_IMAGES_VALUE, 1));
+		assert_eq!(issues, vec![issue(ALLOWED_IMAGES_VALUE, ConfigIssueKind::WrongType)]);
+		assert!(images.is_restricted());
+		assert!(!images.allows(&units("\\Device\\HarddiskVolume1\\app.exe")));
+	}
+
+	#[test]
+	fn missing_allowlist_allows_all() {
+		let (_, images, issues) = load(&FakeKey::new());
+		assert!(issues.is_empty());
+		assert!(!images.is_restricted());
+		assert!(images.allows(&units("\\Device\\HarddiskVolume1\\app.exe")));
+	}
+
+	#[test]
+	fn auth_key_length_is_checked() {
+		let (config, _, issues) = load(&FakeKey::new().raw(AUTH_KEY_VALUE, REG_BINARY, vec![1; 4]));
+		assert_eq!(issues, vec![issue(AUTH_KEY_VALUE, ConfigIssueKind::OutOfRange)]);
+		assert!(config.auth_key == AuthKey::invalid());
+		let (config, _, issues) = load(&FakeKey::new().raw(AUTH_KEY_VALUE, REG_BINARY, vec![1; AUTH_KEY_MAX]));
+		assert!(issues.is_empty());
+		assert!(!config.auth_key.is_empty());
+	}
+
+	#[test]
+	fn report_keeps_first_issues() {
+		let mut source = FakeKey::new();
+		for component in COMPONENTS.iter() {
+			source = source.dword(component.level_value_name(), 99);
+		}
+		for role in DEVICE_ROLES.iter() {
+			let values = &DEVICE_VALUES[*role as usize];
+			source = source.dword(values.name_value, 0).dword(values.symlink_name_value, 0).dword(values.sddl_value, 0);
+		}
+		source = source.dword(MOUSE_HID_DRIVER_VALUE, 0).dword(KEYBOARD_HID_DRIVER_VALUE, 0);
+		let (_, _, issues) = load(&source);
+		assert_eq!(issues.len(), MAX_ISSUES);
+		assert_eq!(issues[0], issue(Component::Driver.level_value_name(), ConfigIssueKind::OutOfRange));
+	}
+
+	#[test]
+	fn session_tag_skips_zero() {
+		let mut config = DriverConfig::defaults();
+		config.injection_tag = 0x1234_5600;
+		assert_eq!(config.extra_information(None), 0x1234_5600);
+		config.tag_sessions = true;
+		let mut sessions = crate::session::SessionTable::new();
+		let tags: Vec<u32> = (0..256).map(|_| {
+			let id = sessions.open(0, 0).unwrap();
+			sessions.close(id);
+			config.extra_information(Some(id))
+		}).collect();
+		assert_eq!(tags[0], 0x1234_5601);
+		assert_eq!(tags[254], 0x1234_56FF);
+		assert_eq!(tags[255], 0x1234_5601);
+		assert_eq!(config.extra_information(None), 0x1234_5600);
+	}
+
+	#[test]
+	fn reload_reports_restart_for_names() {
+		let mut config = DriverConfig::defaults();
+		let (newer, _, _) = load(&FakeKey::new().dword(MAX_HOLD_MS_VALUE, 1000));
+		assert!(!config.apply_runtime(&newer));
+		assert_eq!(config.max_hold_ms, 1000);
+		let (newer, _, _) = load(&FakeKey::new().string(wchz!("MouseSymlinkName"), "\\??\\Other"));
+		assert!(config.apply_runtime(&newer));
+		assert!(config.devices == DriverConfig::defaults().devices);
+	}
+}
@@ -0,0 +1,160 @@
+// Imports
+use winapi::shared::ntdef::{
+	NTSTATUS,
+	UNICODE_STRING,
+	FALSE
+};
+use winapi::shared::guiddef::GUID;
+use winapi::km::wdm::{
+	IoDeleteDevice,
+	IoCreateSymbolicLink,
+	IoDeleteSymbolicLink,
+	DEVICE_TYPE,
+	DEVICE_OBJECT,
+	PDEVICE_OBJECT,
+	PDRIVER_OBJECT
+};
+use crate::winapi_local::km::wdm::{
+	zeroed_unicode_string,
+	RtlInitUnicodeString,
+	IoCreateDeviceSecure,
+	FILE_DEVICE_SECURE_OPEN,
+	DO_DIRECT_IO,
+	DO_DEVICE_INITIALIZING
+};
+use crate::config::DeviceConfig;
+use crate::error::{
+	NtReason,
+	NtResult,
+	nt_check
+};
+use crate::log::{Status, WideStr};
+use crate::eventlog;
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Device object role. Every device accepts only its own IOCTL family,
+// so access can be granted per device with its security descriptor.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum DeviceRole {
+	// Mouse injection
+	Mouse = 0,
+	// Keyboard injection
+	Keyboard = 1,
+	// Configuration and status
+	Control = 2
+}
+
+pub const DEVICE_ROLE_COUNT: usize = 3;
+
+pub const DEVICE_ROLES: [DeviceRole; DEVICE_ROLE_COUNT] = [
+	DeviceRole::Mouse,
+	DeviceRole::Keyboard,
+	DeviceRole::Control
+];
+
+// Device setup class of driver devices. Administrators may override the
+// device security with the Security property of this class in the registry.
+// {7B3F5E2A-94C1-4D8E-A61F-3C529BE047D3}
+const META_DEVICE_CLASS_GUID: GUID = GUID {
+	Data1: 0x7b3f5e2a,
+	Data2: 0x94c1,
+	Data3: 0x4d8e,
+	Data4: [0xa6, 0x1f, 0x3c, 0x52, 0x9b, 0xe0, 0x47, 0xd3]
+};
+
+// Device extension of every driver device
+#[repr(C)]
+pub struct DeviceExtension {
+	pub role: DeviceRole
+}
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl DeviceRole {
+	pub fn name(self) -> &'static str {
+		match self {
+			DeviceRole::Mouse => "mouse",
+			DeviceRole::Keyboard => "keyboard",
+			DeviceRole::Control => "control"
+		}
+	}
+}
+
+/// Create device object with its symlink
+///
+/// # Safety
+/// driver must be the driver object passed to driver_entry, IRQL PASSIVE_LEVEL
+pub unsafe fn create_device(driver: PDRIVER_OBJECT, role: DeviceRole, config: &DeviceConfig) -> NtResult<PDEVICE_OBJECT> {
+	let mut device_name_unicode: UNICODE_STRING = zeroed_unicode_string();
+	RtlInitUnicodeString(&mut device_name_unicode as *mut UNICODE_STRING,
+		config.name.as_wchz().as_ptr());
+	let mut symlink_name_unicode: UNICODE_STRING = zeroed_unicode_string();
+	RtlInitUnicodeString(&mut symlink_name_unicode as *mut UNICODE_STRING,
+		config.symlink_name.as_wchz().as_ptr());
+
+	// SYSTEM and Administrators only unless overridden
+	let mut sddl_unicode: UNICODE_STRING = zeroed_unicode_string();
+	RtlInitUnicodeString(&mut sddl_unicode as *mut UNICODE_STRING,
+		config.sddl.as_wchz().as_ptr());
+	log_debug!(Driver, "Creating {} device {}, SDDL: {}", role.name(), WideStr(config.name.as_units()), WideStr(config.sddl.as_units()));
+
+	let mut device_obj_ptr: PDEVICE_OBJECT = core::ptr::null_mut();
+	let status: NTSTATUS = IoCreateDeviceSecure(
+		driver,
+		core::mem::size_of::<DeviceExtension>() as u32,
+		&mut device_name_unicode as *mut UNICODE_STRING,
+		DEVICE_TYPE::FILE_DEVICE_UNKNOWN,
+		FILE_DEVICE_SECURE_OPEN,
+		FALSE,
+		&sddl_unicode,
+		&META_DEVICE_CLASS_GUID,
+		&mut device_obj_ptr
+	);
+	log_debug!(Driver, "IoCreateDeviceSecure status: {}", Status(status));
+	if let Err(error) = nt_check(status, NtReason::DeviceCreateFailed) {
+		eventlog::device_create_failed(&WideStr(config.name.as_units()), status);
+		return Err(error);
+	}
+	core::ptr::write((*device_obj_ptr).DeviceExtension as *mut DeviceExtension, DeviceExtension {
+		role
+	});
+
+	let status: NTSTATUS = IoCreateSymbolicLink(&symlink_name_unicode, &device_name_unicode);
+	log_debug!(Driver, "IoCreateSymbolicLink status: {}", Status(status));
+	if let Err(error) = nt_check(status, NtReason::SymlinkCreateFailed) {
+		IoDeleteDevice(device_obj_ptr);
+		return Err(error);
+	}
+
+	(*device_obj_ptr).Flags |= DO_DIRECT_IO;
+	(*device_obj_ptr).Flags &= !DO_DEVICE_INITIALIZING;
+	Ok(device_obj_ptr)
+}
+
+/// Delete all driver devices and their symlinks
+///
+/// # Safety
+/// driver must be the driver object passed to driver_entry, no request may still use its devices
+pub unsafe fn delete_devices(driver: PDRIVER_OBJECT, configs: &[DeviceConfig]) {
+	let mut device_obj_ptr: PDEVICE_OBJECT = (*driver).DeviceObject;
+	while !device_obj_ptr.is_null() {
+		let next_device_obj_ptr = (*device_obj_ptr).NextDevice;
+		let role = device_role(&*device_obj_ptr);
+
+		let mut symlink_name_unicode: UNICODE_STRING = zeroed_unicode_string();
+		RtlInitUnicodeString(&mut symlink_name_unicode as *mut UNICODE_STRING,
+			configs[role as usize].symlink_name.as_wchz().as_ptr());
+		IoDeleteSymbolicLink(&symlink_name_unicode);
+		IoDeleteDevice(device_obj_ptr);
+		log_debug!(Driver, "Device {} removed with its symlink.", role.name());
+
+		device_obj_ptr = next_device_obj_ptr;
+	}
+}
+
+// Role of driver device, set in create_device
+pub fn device_role(device: &DEVICE_OBJECT) -> DeviceRole {
+	unsafe { (*(device.DeviceExtension as *const DeviceExtension)).role }
+}
@@ -0,0 +1,252 @@
+// Imports
+use winapi::shared::ntdef::NTSTATUS;
+use winapi::shared::ntstatus::{
+	STATUS_SUCCESS,
+	STATUS_UNSUCCESSFUL,
+	STATUS_NOT_FOUND,
+	STATUS_FAIL_CHECK,
+	STATUS_NO_SUCH_DEVICE,
+	STATUS_INVALID_DEVICE_REQUEST,
+	STATUS_INVALID_PARAMETER,
+	STATUS_BUFFER_TOO_SMALL,
+	STATUS_OBJECT_NAME_NOT_FOUND,
+	STATUS_TOO_MANY_OPENED_FILES,
+	STATUS_INSUFFICIENT_RESOURCES,
+	STATUS_DEVICE_NOT_READY,
+	STATUS_QUOTA_EXCEEDED,
+	STATUS_LOCK_NOT_GRANTED,
+	STATUS_ACCESS_DENIED,
+	STATUS_DEVICE_BUSY,
+	STATUS_CANCELLED
+};
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Driver specific reason codes. Values are stable and may be used by clients and logs.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum NtReason {
+	// Status comes from a kernel routine, no extra driver context
+	Kernel = 0,
+	// Class driver (MouClass, KbdClass) could not be referenced
+	ClassDriverNotFound = 1,
+	// HID driver (MouHID, KbdHID) could not be referenced
+	HidDriverNotFound = 2,
+	// Class service callback was not found in HID device extension
+	CallbackNotFound = 3,
+	// Class device object was not found
+	DeviceNotFound = 4,
+	// IoCreateDevice failed
+	DeviceCreateFailed = 5,
+	// IoCreateSymbolicLink failed
+	SymlinkCreateFailed = 6,
+	// IOCTL code or IRP major function is not handled by the driver
+	UnsupportedIoctl = 7,
+	// Input buffer is smaller than the request structure
+	InputBufferTooSmall = 8,
+	// Output buffer is smaller than the reply structure
+	OutputBufferTooSmall = 9,
+	// Request fields are out of range
+	InvalidRequest = 10,
+	// Registry key could not be opened
+	RegistryOpenFailed = 11,
+	// ETW provider registration failed
+	EtwRegisterFailed = 12,
+	// Configuration values failed validation, nothing was applied
+	ConfigInvalid = 13,
+	// All session slots are in use
+	TooManySessions = 14,
+	// All hold slots are armed
+	TooManyHolds = 15,
+	// Injection is switched off by DISARM or auto-disarm timeout
+	Disarmed = 16,
+	// Session or driver-wide rate limit exceeded
+	RateLimited = 17,
+	// Device is leased by another session
+	LeaseBusy = 18,
+	// Opening process is not on the image allowlist
+	CallerNotAllowed = 19,
+	// Request MAC or sequence number check failed
+	AuthFailed = 20,
+	// Class service callback did not consume the event, its queue is full
+	InputNotConsumed = 21,
+	// Another scheduled playback is running
+	PlaybackBusy = 22,
+	// Pending request was cancelled or its handle closed
+	Cancelled = 23,
+	// High resolution timer could not be allocated
+	TimerCreateFailed = 24,
+	// Another loopback read of the device is waiting
+	ReadBusy = 25
+}
+
+// Number of reasons, last reason + 1
+pub const REASON_COUNT: usize = 26;
+
+// Driver error: NTSTATUS reported to the client plus driver specific reason
+#[must_use]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct NtError {
+	pub status: NTSTATUS,
+	pub reason: NtReason
+}
+
+// Driver result type
+pub type NtResult<T> = Result<T, NtError>;
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl NtReason {
+	// Status a client sees when the error has no kernel status attached
+	pub const fn default_status(self) -> NTSTATUS {
+		match self {
+			NtReason::Kernel => STATUS_UNSUCCESSFUL,
+			NtReason::ClassDriverNotFound => STATUS_NOT_FOUND,
+			NtReason::HidDriverNotFound => STATUS_NOT_FOUND,
+			NtReason::CallbackNotFound => STATUS_FAIL_CHECK,
+			NtReason::DeviceNotFound => STATUS_NO_SUCH_DEVICE,
+			NtReason::DeviceCreateFailed => STATUS_UNSUCCESSFUL,
+			NtReason::SymlinkCreateFailed => STATUS_UNSUCCESSFUL,
+			NtReason::UnsupportedIoctl => STATUS_INVALID_DEVICE_REQUEST,
+			NtReason::InputBufferTooSmall => STATUS_BUFFER_TOO_SMALL,
+			NtReason::OutputBufferTooSmall => STATUS_BUFFER_TOO_SMALL,
+			NtReason::InvalidRequest => STATUS_INVALID_PARAMETER,
+			NtReason::RegistryOpenFailed => STATUS_OBJECT_NAME_NOT_FOUND,
+			NtReason::EtwRegisterFailed => STATUS_UNSUCCESSFUL,
+			NtReason::ConfigInvalid => STATUS_INVALID_PARAMETER,
+			NtReason::TooManySessions => STATUS_TOO_MANY_OPENED_FILES,
+			NtReason::TooManyHolds => STATUS_INSUFFICIENT_RESOURCES,
+			NtReason::Disarmed => STATUS_DEVICE_NOT_READY,
+			NtReason::RateLimited => STATUS_QUOTA_EXCEEDED,
+			NtReason::LeaseBusy => STATUS_LOCK_NOT_GRANTED,
+			NtReason::CallerNotAllowed => STATUS_ACCESS_DENIED,
+			NtReason::AuthFailed => STATUS_ACCESS_DENIED,
+			NtReason::InputNotConsumed => STATUS_DEVICE_BUSY,
+			NtReason::PlaybackBusy => STATUS_DEVICE_BUSY,
+			NtReason::Cancelled => STATUS_CANCELLED,
+			NtReason::TimerCreateFailed => STATUS_INSUFFICIENT_RESOURCES,
+			NtReason::ReadBusy => STATUS_DEVICE_BUSY
+		}
+	}
+}
+
+impl NtError {
+	// Error with explicit status
+	pub const fn new(status: NTSTATUS, reason: NtReason) -> NtError {
+		NtError {
+			status,
+			reason
+		}
+	}
+
+	// Error with reason default status
+	pub const fn from_reason(reason: NtReason) -> NtError {
+		NtError::new(reason.default_status(), reason)
+	}
+}
+
+// Plain kernel status without driver context
+impl From<NTSTATUS> for NtError {
+	fn from(status: NTSTATUS) -> NtError {
+		NtError::new(status, NtReason::Kernel)
+	}
+}
+
+// NT_SUCCESS check which turns kernel status into NtResult
+pub fn nt_check(status: NTSTATUS, reason: NtReason) -> NtResult<()> {
+	if status >= 0 {
+		Ok(())
+	} else {
+		Err(NtError::new(status, reason))
+	}
+}
+
+// Status and Information values a client sees for a dispatch result
+pub fn io_status(result: &NtResult<usize>) -> (NTSTATUS, usize) {
+	match result {
+		Ok(information) => (STATUS_SUCCESS, *information),
+		Err(error) => (error.status, 0)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Every reason with the status a client sees, in reason order
+	const REASON_STATUS: [(NtReason, NTSTATUS); REASON_COUNT] = [
+		(NtReason::Kernel, STATUS_UNSUCCESSFUL),
+		(NtReason::ClassDriverNotFound, STATUS_NOT_FOUND),
+		(NtReason::HidDriverNotFound, STATUS_NOT_FOUND),
+		(NtReason::CallbackNotFound, STATUS_FAIL_CHECK),
+		(NtReason::DeviceNotFound, STATUS_NO_SUCH_DEVICE),
+		(NtReason::DeviceCreateFailed, STATUS_UNSUCCESSFUL),
+		(NtReason::SymlinkCreateFailed, STATUS_UNSUCCESSFUL),
+		(NtReason::UnsupportedIoctl, STATUS_INVALID_DEVICE_REQUEST),
+		(NtReason::InputBufferTooSmall, STATUS_BUFFER_TOO_SMALL),
+		(NtReason::OutputBufferTooSmall, STATUS_BUFFER_TOO_SMALL),
+		(NtReason::InvalidRequest, STATUS_INVALID_PARAMETER),
+		(NtReason::RegistryOpenFailed, STATUS_OBJECT_NAME_NOT_FOUND),
+		(NtReason::EtwRegisterFailed, STATUS_UNSUCCESSFUL),
+		(NtReason::ConfigInvalid, STATUS_INVALID_PARAMETER),
+		(NtReason::TooManySessions, STATUS_TOO_MANY_OPENED_FILES),
+		(NtReason::TooManyHolds, STATUS_INSUFFICIENT_RESOURCES),
+		(NtReason::Disarmed, STATUS_DEVICE_NOT_READY),
+		(NtReason::RateLimited, STATUS_QUOTA_EXCEEDED),
+		(NtReason::LeaseBusy, STATUS_LOCK_NOT_GRANTED),
+		(NtReason::CallerNotAllowed, STATUS_ACCESS_DENIED),
+		(NtReason::AuthFailed, STATUS_ACCESS_DENIED),
+		(NtReason::InputNotConsumed, STATUS_DEVICE_BUSY),
+		(NtReason::PlaybackBusy, STATUS_DEVICE_BUSY),
+		(NtReason::Cancelled, STATUS_CANCELLED),
+		(NtReason::TimerCreateFailed, STATUS_INSUFFICIENT_RESOURCES),
+		(NtReason::ReadBusy, STATUS_DEVICE_BUSY)
+	];
+
+	#[test]
+	fn reason_values_are_stable() {
+		for (index, (reason, _)) in REASON_STATUS.iter().enumerate() {
+			assert_eq!(*reason as u32, index as u32, "{:?}", reason);
+		}
+	}
+
+	#[test]
+	fn reason_maps_to_client_status() {
+		for (reason, status) in REASON_STATUS.iter() {
+			assert_eq!(reason.default_status(), *status, "{:?}", reason);
+			let error = NtError::from_reason(*reason);
+			assert_eq!(error.status, *status);
+			assert_eq!(error.reason, *reason);
+			assert_eq!(io_status(&Err(error)), (*status, 0), "{:?}", reason);
+		}
+	}
+
+	#[test]
+	fn every_error_status_is_a_failure() {
+		for (reason, status) in REASON_STATUS.iter() {
+			assert!(*status < 0, "{:?}", reason);
+		}
+	}
+
+	#[test]
+	fn kernel_status_is_kept() {
+		let error = NtError::from(STATUS_ACCESS_DENIED);
+		assert_eq!(error, NtError::new(STATUS_ACCESS_DENIED, NtReason::Kernel));
+		assert_eq!(io_status(&Err(error)), (STATUS_ACCESS_DENIED, 0));
+		assert_eq!(nt_check(STATUS_SUCCESS, NtReason::RegistryOpenFailed), Ok(()));
+		assert_eq!(nt_check(STATUS_OBJECT_NAME_NOT_FOUND, NtReason::RegistryOpenFailed),
+			Err(NtError::new(STATUS_OBJECT_NAME_NOT_FOUND, NtReason::RegistryOpenFailed)));
+	}
+
+	#[test]
+	fn success_reports_information() {
+		assert_eq!(io_status(&Ok(0)), (STATUS_SUCCESS, 0));
+		assert_eq!(io_status(&Ok(24)), (STATUS_SUCCESS, 24));
+	}
+
+	#[test]
+	fn explicit_status_overrides_reason_default() {
+		let error = NtError::new(STATUS_INSUFFICIENT_RESOURCES, NtReason::DeviceCreateFailed);
+		assert_eq!(io_status(&Err(error)), (STATUS_INSUFFICIENT_RESOURCES, 0));
+	}
+}
@@ -0,0 +1,126 @@
+// Imports
+use core::sync::atomic::{AtomicU64, Ordering};
+use winapi::shared::ntdef::BOOLEAN;
+use crate::winapi_local::km::wdm::{
+	EtwRegister,
+	EtwUnregister,
+	EtwSetInformation,
+	EtwProviderEnabled,
+	EtwWrite,
+	REGHANDLE,
+	EVENT_DESCRIPTOR,
+	EVENT_DATA_DESCRIPTOR,
+	EVENT_INFO_CLASS,
+	EVENT_DATA_DESCRIPTOR_TYPE_NONE,
+	EVENT_DATA_DESCRIPTOR_TYPE_EVENT_METADATA,
+	EVENT_DATA_DESCRIPTOR_TYPE_PROVIDER_METADATA
+};
+use crate::tracelog::{
+	EventBuilder,
+	ProviderMetadata,
+	PROVIDER_GUID,
+	TRACELOGGING_CHANNEL
+};
+use crate::error::{
+	NtResult,
+	NtReason,
+	nt_check
+};
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Provider registration handle, 0 if not registered
+static REG_HANDLE: AtomicU64 = AtomicU64::new(0);
+
+// Provider traits sent with every event
+static PROVIDER_METADATA: ProviderMetadata = ProviderMetadata::new();
+
+// PUBLIC FUNCTIONS ==========================================
+
+// Register ETW provider
+pub fn register() -> NtResult<()> {
+	unsafe {
+		let mut reg_handle: REGHANDLE = 0;
+		let status = EtwRegister(&PROVIDER_GUID, core::ptr::null_mut(), core::ptr::null_mut(), &mut reg_handle);
+		nt_check(status, NtReason::EtwRegisterFailed)?;
+
+		// Provider name for decoders, and descriptor types for metadata blobs.
+		// Failures only affect decoding on old systems, so they are ignored.
+		let traits = PROVIDER_METADATA.as_bytes();
+		EtwSetInformation(
+			reg_handle,
+			EVENT_INFO_CLASS::EventProviderSetTraits,
+			traits.as_ptr() as _,
+			traits.len() as u32
+		);
+		let mut use_descriptor_type: BOOLEAN = 1;
+		EtwSetInformation(
+			reg_handle,
+			EVENT_INFO_CLASS::EventProviderUseDescriptorType,
+			&mut use_descriptor_type as *mut BOOLEAN as _,
+			core::mem::size_of::<BOOLEAN>() as u32
+		);
+
+		REG_HANDLE.store(reg_handle, Ordering::Release);
+	}
+	Ok(())
+}
+
+// Unregister ETW provider
+pub fn unregister() {
+	let reg_handle = REG_HANDLE.swap(0, Ordering::AcqRel);
+	if reg_handle != 0 {
+		unsafe {
+			EtwUnregister(reg_handle);
+		}
+	}
+}
+
+// Write encoded event if a session listens for its level and keyword
+pub fn write(mut event: EventBuilder) {
+	let reg_handle = REG_HANDLE.load(Ordering::Acquire);
+	if reg_handle == 0 {
+		return;
+	}
+	unsafe {
+		if EtwProviderEnabled(reg_handle, event.level(), event.keyword()) == 0 {
+			return;
+		}
+
+		let descriptor = EVENT_DESCRIPTOR {
+			Id: 0,
+			Version: 0,
+			Channel: TRACELOGGING_CHANNEL,
+			Level: event.level(),
+			Opcode: 0,
+			Task: 0,
+			Keyword: event.keyword()
+		};
+		let (metadata, payload) = match event.encoded() {
+			Some(encoded) => encoded,
+			None => {
+				log_warn!(Driver, "ETW event dropped, encoded size exceeds buffer");
+				return;
+			}
+		};
+		let provider = PROVIDER_METADATA.as_bytes();
+		let data = [
+			EVENT_DATA_DESCRIPTOR {
+				Ptr: provider.as_ptr() as u64,
+				Size: provider.len() as u32,
+				Reserved: EVENT_DATA_DESCRIPTOR_TYPE_PROVIDER_METADATA
+			},
+			EVENT_DATA_DESCRIPTOR {
+				Ptr: metadata.as_ptr() as u64,
+				Size: metadata.len() as u32,
+				Reserved: EVENT_DATA_DESCRIPTOR_TYPE_EVENT_METADATA
+			},
+			EVENT_DATA_DESCRIPTOR {
+				Ptr: payload.as_ptr() as u64,
+				Size: payload.len() as u32,
+				Reserved: EVENT_DATA_DESCRIPTOR_TYPE_NONE
+			}
+		];
+		EtwWrite(reg_handle, &descriptor, core::ptr::null(), data.len() as u32, data.as_ptr());
+	}
+}
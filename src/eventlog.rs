@@ -0,0 +1,149 @@
+// Imports
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use winapi::shared::ntdef::{
+	NTSTATUS,
+	PVOID
+};
+use crate::winapi_local::km::wdm::{
+	IoAllocateErrorLogEntry,
+	IoWriteErrorLogEntry,
+	IO_ERROR_LOG_PACKET,
+	PIO_ERROR_LOG_PACKET,
+	ERROR_LOG_MAXIMUM_SIZE
+};
+use crate::log::Status;
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Event code layout, must match meta_driver.mc compiled with "mc -c"
+const CUSTOMER_FLAG: u32 = 0x20000000;
+const FACILITY_META_DRIVER: u32 = 0x0F1;
+const SEVERITY_WARNING: u32 = 0x2;
+const SEVERITY_ERROR: u32 = 0x3;
+
+const fn event_code(severity: u32, id: u32) -> NTSTATUS {
+	((severity << 30) | CUSTOMER_FLAG | (FACILITY_META_DRIVER << 16) | id) as NTSTATUS
+}
+
+// Event codes. Event Viewer shows the low 16 bits as Event ID.
+pub const META_EVENT_NO_CLASS_CALLBACK: NTSTATUS = event_code(SEVERITY_ERROR, 0x0001);
+pub const META_EVENT_DEVICE_CREATE_FAILED: NTSTATUS = event_code(SEVERITY_ERROR, 0x0002);
+pub const META_EVENT_REDISCOVERY_FAILED: NTSTATUS = event_code(SEVERITY_WARNING, 0x0003);
+pub const META_EVENT_UNLOAD_WITH_LEAKS: NTSTATUS = event_code(SEVERITY_WARNING, 0x0004);
+pub const META_EVENT_CONFIG_INVALID: NTSTATUS = event_code(SEVERITY_WARNING, 0x0005);
+pub const META_EVENT_OPEN_REJECTED: NTSTATUS = event_code(SEVERITY_WARNING, 0x0006);
+
+// Driver object which owns log entries
+static IO_OBJECT: AtomicPtr<core::ffi::c_void> = AtomicPtr::new(core::ptr::null_mut());
+
+// Counts UTF-16 units a value needs
+struct WideCounter {
+	units: usize
+}
+
+// Writes UTF-16 units straight into the log entry
+struct WideWriter {
+	dest: *mut u16
+}
+
+// PUBLIC FUNCTIONS ==========================================
+
+// Set driver object used for IoAllocateErrorLogEntry
+pub fn set_io_object(driver: PVOID) {
+	IO_OBJECT.store(driver, Ordering::Release);
+}
+
+// Write System event log entry. Insertion strings become %2, %3, ...
+pub fn write_entry(code: NTSTATUS, final_status: NTSTATUS, strings: &[&dyn fmt::Display]) {
+	let io_object = IO_OBJECT.load(Ordering::Acquire);
+	if io_object.is_null() {
+		return;
+	}
+
+	// Measure strings first, entry size must be known before allocation
+	let packet_size = core::mem::size_of::<IO_ERROR_LOG_PACKET>();
+	let mut counter = WideCounter { units: 0 };
+	for string in strings.iter() {
+		let _ = write!(counter, "{}", string);
+		counter.units += 1;
+	}
+	let entry_size = packet_size + counter.units * 2;
+	if entry_size > ERROR_LOG_MAXIMUM_SIZE {
+		log_warn!(Driver, "Event log entry 0x{:08X} dropped, {} bytes exceed limit", code as u32, entry_size);
+		return;
+	}
+
+	unsafe {
+		let entry = IoAllocateErrorLogEntry(io_object, entry_size as u8) as PIO_ERROR_LOG_PACKET;
+		if entry.is_null() {
+			return;
+		}
+		core::ptr::write_bytes(entry as *mut u8, 0, packet_size);
+		(*entry).ErrorCode = code;
+		(*entry).FinalStatus = final_status;
+		(*entry).NumberOfStrings = strings.len() as u16;
+		(*entry).StringOffset = packet_size as u16;
+
+		let mut writer = WideWriter {
+			dest: (entry as *mut u8).add(packet_size) as *mut u16
+		};
+		for string in strings.iter() {
+			let _ = write!(writer, "{}", string);
+			*writer.dest = 0;
+			writer.dest = writer.dest.add(1);
+		}
+
+		IoWriteErrorLogEntry(entry as PVOID);
+	}
+}
+
+// Class service callback was not found at load
+pub fn no_class_callback(device: &str, status: NTSTATUS) {
+	write_entry(META_EVENT_NO_CLASS_CALLBACK, status, &[&device, &Status(status)]);
+}
+
+// IoCreateDevice failed
+pub fn device_create_failed(device_name: &dyn fmt::Display, status: NTSTATUS) {
+	write_entry(META_EVENT_DEVICE_CREATE_FAILED, status, &[device_name, &Status(status)]);
+}
+
+// Class service callback lookup retried on request and failed again
+pub fn rediscovery_failed(device: &str, status: NTSTATUS) {
+	write_entry(META_EVENT_REDISCOVERY_FAILED, status, &[&device, &Status(status)]);
+}
+
+// Unload found resources which were never released
+pub fn unload_with_leaks(count: u32, what: &str) {
+	write_entry(META_EVENT_UNLOAD_WITH_LEAKS, 0, &[&count, &what]);
+}
+
+// Registry value was ignored, default is used instead
+pub fn config_invalid(value_name: &dyn fmt::Display, description: &str) {
+	write_entry(META_EVENT_CONFIG_INVALID, 0, &[value_name, &description]);
+}
+
+// Opening process is not on the image allowlist
+pub fn open_rejected(process_id: usize, image_name: &dyn fmt::Display) {
+	write_entry(META_EVENT_OPEN_REJECTED, 0, &[&process_id, image_name]);
+}
+
+impl fmt::Write for WideCounter {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		self.units += s.encode_utf16().count();
+		Ok(())
+	}
+}
+
+impl fmt::Write for WideWriter {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		for unit in s.encode_utf16() {
+			unsafe {
+				*self.dest = unit;
+				self.dest = self.dest.add(1);
+			}
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,189 @@
+// Release deadlines of pressed keys and buttons. Pure bookkeeping, the watchdog
+// timer fires the releases.
+
+// Imports
+use crate::keyboard::{
+	KEY_BREAK,
+	KEY_E0,
+	KEY_E1
+};
+use crate::mouse::MOUSE_LEFT_BUTTON_DOWN;
+use crate::session::{SessionId, InputEvent};
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Max number of armed holds, over all sessions
+pub const MAX_HOLDS: usize = 64;
+
+// Mouse buttons are DOWN/UP flag pairs, left button in the lowest pair
+const MOUSE_BUTTON_COUNT: u16 = 5;
+
+// Pressed key or button
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum HoldInput {
+	// Make code with KEY_E0 if extended
+	Key { make_code: u16, flags: u16 },
+	// Button index, 0 is left button
+	Button(u16)
+}
+
+// Armed hold, released when deadline passes
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Hold {
+	pub session: SessionId,
+	pub input: HoldInput,
+	pub deadline_ms: u64
+}
+
+// Armed holds
+pub struct HoldTable {
+	holds: [Option<Hold>; MAX_HOLDS]
+}
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl HoldInput {
+	// Key press or release. None for E1 sequences, they have no break.
+	pub fn from_key(make_code: u16, flags: u16) -> Option<(HoldInput, bool)> {
+		if make_code > 0xFF || flags & KEY_E1 != 0 {
+			return None;
+		}
+		let input = HoldInput::Key {
+			make_code,
+			flags: flags & KEY_E0
+		};
+		Some((input, flags & KEY_BREAK == 0))
+	}
+
+	// Button presses and releases in MOUSE_*_BUTTON_DOWN / _UP flags
+	pub fn from_buttons(button_flags: u16) -> impl Iterator<Item = (HoldInput, bool)> {
+		(0..MOUSE_BUTTON_COUNT).filter_map(move |button| {
+			let down = MOUSE_LEFT_BUTTON_DOWN << (button * 2);
+			let up = down << 1;
+			if button_flags & up != 0 {
+				Some((HoldInput::Button(button), false))
+			} else if button_flags & down != 0 {
+				Some((HoldInput::Button(button), true))
+			} else {
+				None
+			}
+		})
+	}
+
+	// Call f with every press (true) and release (false) in event
+	pub fn for_each_in<F: FnMut(HoldInput, bool)>(event: InputEvent, mut f: F) {
+		match event {
+			InputEvent::Key { make_code, flags } => {
+				if let Some((input, pressed)) = HoldInput::from_key(make_code, flags) {
+					f(input, pressed);
+				}
+			}
+			InputEvent::Buttons(button_flags) => {
+				for (input, pressed) in HoldInput::from_buttons(button_flags) {
+					f(input, pressed);
+				}
+			}
+		}
+	}
+
+	// Event which releases the input
+	pub fn release_event(self) -> InputEvent {
+		match self {
+			HoldInput::Key { make_code, flags } => InputEvent::Key {
+				make_code,
+				flags: flags | KEY_BREAK
+			},
+			HoldInput::Button(button) => InputEvent::Buttons(MOUSE_LEFT_BUTTON_DOWN << (button * 2 + 1))
+		}
+	}
+}
+
+impl Default for HoldTable {
+	fn default() -> HoldTable {
+		HoldTable::new()
+	}
+}
+
+impl HoldTable {
+	pub const fn new() -> HoldTable {
+		HoldTable {
+			holds: [None; MAX_HOLDS]
+		}
+	}
+
+	pub fn has_room(&self) -> bool {
+		self.holds.iter().any(|hold| hold.is_none())
+	}
+
+	// Arm or re-arm hold of session input. False if table is full.
+	pub fn arm(&mut self, session: SessionId, input: HoldInput, deadline_ms: u64) -> bool {
+		let hold = Hold {
+			session,
+			input,
+			deadline_ms
+		};
+		let existing = self.holds.iter().position(|slot| match slot {
+			Some(armed) => armed.session == session && armed.input == input,
+			None => false
+		});
+		match existing.or_else(|| self.holds.iter().position(|slot| slot.is_none())) {
+			Some(index) => {
+				self.holds[index] = Some(hold);
+				true
+			}
+			None => false
+		}
+	}
+
+	// Slot of armed hold of session input, stays the same while re-armed
+	pub fn slot_of(&self, session: SessionId, input: HoldInput) -> Option<usize> {
+		self.holds.iter().position(|slot| matches!(slot, Some(armed) if armed.session == session && armed.input == input))
+	}
+
+	// Input was released in time
+	pub fn disarm(&mut self, session: SessionId, input: HoldInput) {
+		for slot in self.holds.iter_mut() {
+			if matches!(slot, Some(armed) if armed.session == session && armed.input == input) {
+				*slot = None;
+			}
+		}
+	}
+
+	// Session is gone, its input is released by cleanup
+	pub fn disarm_session(&mut self, session: SessionId) {
+		for slot in self.holds.iter_mut() {
+			if matches!(slot, Some(armed) if armed.session == session) {
+				*slot = None;
+			}
+		}
+	}
+
+	// Everything is released at once, on disarm
+	pub fn clear(&mut self) {
+		self.holds = [None; MAX_HOLDS];
+	}
+
+	// Remove holds with deadline at or before now. Returns number written to expired,
+	// holds which do not fit stay armed for the next run.
+	pub fn take_expired(&mut self, now_ms: u64, expired: &mut [Option<Hold>]) -> usize {
+		let mut count = 0;
+		for slot in self.holds.iter_mut() {
+			if count == expired.len() {
+				break;
+			}
+			if let Some(hold) = *slot {
+				if hold.deadline_ms <= now_ms {
+					expired[count] = Some(hold);
+					count += 1;
+					*slot = None;
+				}
+			}
+		}
+		count
+	}
+
+	// Earliest deadline, None if nothing is armed
+	pub fn next_deadline(&self) -> Option<u64> {
+		self.holds.iter().filter_map(|hold| hold.map(|hold| hold.deadline_ms)).min()
+	}
+}
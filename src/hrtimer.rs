@@ -0,0 +1,73 @@
+// High resolution timer driving scheduled playback. Unlike the watchdog
+// KTIMER it is not rounded to the system clock tick, so event spacing stays
+// close to what the client asked for.
+
+// Imports
+use winapi::shared::ntdef::TRUE;
+use crate::winapi_local::km::wdm::{
+	ExAllocateTimer,
+	ExSetTimer,
+	ExCancelTimer,
+	ExDeleteTimer,
+	PEX_TIMER,
+	PEXT_CALLBACK,
+	EX_TIMER_HIGH_RESOLUTION
+};
+use crate::error::{NtError, NtReason, NtResult};
+use crate::clock;
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// 100 ns units per microsecond
+const TICKS_PER_US: i64 = 10;
+
+static mut PLAYBACK_TIMER: PEX_TIMER = core::ptr::null_mut();
+
+// PUBLIC FUNCTIONS ==========================================
+
+/// Allocate timer, callback runs at DISPATCH_LEVEL when it fires
+///
+/// # Safety
+/// Call once at load, IRQL PASSIVE_LEVEL
+pub unsafe fn init(callback: PEXT_CALLBACK) -> NtResult<()> {
+	PLAYBACK_TIMER = ExAllocateTimer(callback, core::ptr::null_mut(), EX_TIMER_HIGH_RESOLUTION);
+	if PLAYBACK_TIMER.is_null() {
+		return Err(NtError::from_reason(NtReason::TimerCreateFailed));
+	}
+	Ok(())
+}
+
+// Timer was allocated, playback is possible
+pub fn available() -> bool {
+	unsafe { !PLAYBACK_TIMER.is_null() }
+}
+
+// Arm timer to deadline (clock::now_us based), or cancel it if there is none.
+// A deadline which already passed fires at once.
+pub fn schedule(deadline_us: Option<u64>) {
+	unsafe {
+		if PLAYBACK_TIMER.is_null() {
+			return;
+		}
+		match deadline_us {
+			Some(deadline_us) => {
+				let delay_us = deadline_us.saturating_sub(clock::now_us()) as i64;
+				ExSetTimer(PLAYBACK_TIMER, -delay_us * TICKS_PER_US, 0, core::ptr::null_mut());
+			}
+			None => {
+				ExCancelTimer(PLAYBACK_TIMER, core::ptr::null_mut());
+			}
+		}
+	}
+}
+
+/// Cancel timer, wait for a running callback and free it, before unload
+///
+/// # Safety
+/// IRQL PASSIVE_LEVEL, not from the timer callback
+pub unsafe fn stop() {
+	if !PLAYBACK_TIMER.is_null() {
+		ExDeleteTimer(PLAYBACK_TIMER, TRUE, TRUE, core::ptr::null_mut());
+		PLAYBACK_TIMER = core::ptr::null_mut();
+	}
+}
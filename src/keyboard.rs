@@ -16,15 +16,20 @@ pub use winapi::shared::minwindef::{
 	PUCHAR
 };
 use winapi::km::wdm::{
-	DbgPrintEx,
 	DEVICE_OBJECT,
 	PDEVICE_OBJECT,
 	PDRIVER_OBJECT,
 	KPROCESSOR_MODE
 };
-use winapi::shared::ntstatus::{
-	STATUS_SUCCESS,
-	STATUS_FAIL_CHECK
+use crate::log::{Status, WideStr};
+use crate::etw;
+use crate::tracelog;
+use crate::stats;
+use crate::error::{
+	NtError,
+	NtReason,
+	NtResult,
+	nt_check
 };
 use crate::winapi_local::km::wdm::{
     zeroed_unicode_string,
@@ -34,6 +39,7 @@ use crate::winapi_local::km::wdm::{
     IoDriverObjectType,
     KeRaiseIrql,
     KeLowerIrql,
+    KeQueryPerformanceCounter,
     ULONG_PTR,
     PULONG_PTR,
     DISPATCH_LEVEL,
@@ -74,8 +80,11 @@ pub type PKBD_OBJECT = *mut KBD_OBJECT;
 
 // Constants
 pub const KBD_DRIVER_CLASS_NAME: &[wchar_t] = wchz!("\\Driver\\KbdClass");
-// pub const KBD_DRIVER_HID_NAME: &[wchar_t] = wchz!("\\Driver\\KbdHID");
-pub const KBD_DRIVER_HID_NAME: &[wchar_t] = wchz!("\\Driver\\ASWkbd");
+
+pub const KEY_MAKE: USHORT = 0;  // Key pressed.
+pub const KEY_BREAK: USHORT = 1;  // Key released.
+pub const KEY_E0: USHORT = 2;  // Extended scan code, E0 prefix.
+pub const KEY_E1: USHORT = 4;  // Extended scan code, E1 prefix.
 
 
 // PUBLIC FUNCTIONS ==========================================
@@ -87,9 +96,21 @@ pub const fn zeroed_kbd_object() -> KBD_OBJECT {
 	}
 }
 
-pub fn kbd_init(kbd_object: PKBD_OBJECT) -> NTSTATUS {
+// Keyboard input data of one event
+pub const fn kbd_input_data(make_code: USHORT, flags: USHORT, extra_information: ULONG) -> KEYBOARD_INPUT_DATA {
+	KEYBOARD_INPUT_DATA {
+		UnitId: 0,
+		MakeCode: make_code,
+		Flags: flags,
+		Reserved: 0,
+		ExtraInformation: extra_information
+	}
+}
+
+// Keyboard init, HID driver name must be NUL terminated
+pub fn kbd_init(kbd_object: PKBD_OBJECT, hid_driver_name: &[wchar_t]) -> NtResult<()> {
 	unsafe {
-		DbgPrintEx(0, 0, "kbd_init called.\0".as_ptr());
+		log_debug!(Discovery, "kbd_init called.");
 
 		// FIND KBD CLASS DRIVER
 		let mut kbd_driver_class_unicode: UNICODE_STRING = zeroed_unicode_string();
@@ -106,18 +127,16 @@ pub fn kbd_init(kbd_object: PKBD_OBJECT) -> NTSTATUS {
 			core::ptr::null_mut(),
 			&mut class_driver_obj as *mut _ as *mut PVOID
 		);
-		DbgPrintEx(0, 0, "ObReferenceObjectByName>>KbdClass status: %u\0".as_ptr(), kbd_driver_find_status);
-		DbgPrintEx(0, 0, "Kbd class object pointer: %p\0".as_ptr(), class_driver_obj);
-		if kbd_driver_find_status != STATUS_SUCCESS {
-			return kbd_driver_find_status;
-		}
+		log_debug!(Discovery, "ObReferenceObjectByName>>KbdClass status: {}", Status(kbd_driver_find_status));
+		log_trace!(Discovery, "Kbd class object pointer: {:p}", class_driver_obj);
+		nt_check(kbd_driver_find_status, NtReason::ClassDriverNotFound)?;
 		let class_driver_obj_uptr: ULONG_PTR = class_driver_obj as ULONG_PTR;
 
 		// FIND KBD HID DRIVER
 		let mut kdb_driver_hid_unicode: UNICODE_STRING = zeroed_unicode_string();
 		let mut hid_driver_obj: PDRIVER_OBJECT = core::ptr::null_mut();
 		RtlInitUnicodeString(&mut kdb_driver_hid_unicode as *mut UNICODE_STRING,
-            KBD_DRIVER_HID_NAME.as_ptr());
+            hid_driver_name.as_ptr());
 		let hid_driver_find_status = ObReferenceObjectByName(
 			&mut kdb_driver_hid_unicode as *mut UNICODE_STRING,
 			OBJ_CASE_INSENSITIVE,
@@ -128,11 +147,11 @@ pub fn kbd_init(kbd_object: PKBD_OBJECT) -> NTSTATUS {
 			core::ptr::null_mut(),
 			&mut hid_driver_obj as *mut _ as *mut PVOID
 		);
-		DbgPrintEx(0, 0, "ObReferenceObjectByName>>KbdHID status: %u\0".as_ptr(), hid_driver_find_status);
-		DbgPrintEx(0, 0, "Kbd Hid object pointer: %p\0".as_ptr(), hid_driver_obj);
-		if hid_driver_find_status != STATUS_SUCCESS {
+		log_debug!(Discovery, "ObReferenceObjectByName>>{} status: {}", WideStr(hid_driver_name), Status(hid_driver_find_status));
+		log_trace!(Discovery, "Kbd Hid object pointer: {:p}", hid_driver_obj);
+		if let Err(error) = nt_check(hid_driver_find_status, NtReason::HidDriverNotFound) {
 			ObDereferenceObject(class_driver_obj as PVOID);
-			return hid_driver_find_status;
+			return Err(error);
 		}
 
 		// LOOKUP
@@ -147,7 +166,8 @@ pub fn kbd_init(kbd_object: PKBD_OBJECT) -> NTSTATUS {
 					(*kbd_object).kbd_device = class_device_obj;
 				}
 				if (*hid_device_obj).DeviceObjectExtension.is_null() {
-					DbgPrintEx(0, 0, "Kbd NULL DEVICE EXTENSION\0".as_ptr());
+					log_warn!(Discovery, "Kbd NULL DEVICE EXTENSION");
+					class_device_obj = (*class_device_obj).NextDevice;
 					continue;
 				}
 
@@ -155,7 +175,7 @@ pub fn kbd_init(kbd_object: PKBD_OBJECT) -> NTSTATUS {
 				let device_extension_ptr: PULONG_PTR = (*hid_device_obj).DeviceExtension as PULONG_PTR;
 				let mut device_extension_size: ULONG_PTR = (*hid_device_obj).DeviceObjectExtension as ULONG_PTR;
 				device_extension_size = (device_extension_size - device_extension) / 4;
-				DbgPrintEx(0, 0, "Kbd Device extension size: %u\0".as_ptr(), device_extension_size);
+				log_trace!(Discovery, "Kbd Device extension size: {}", device_extension_size);
 				
 				class_driver_base = (*class_driver_obj).DriverStart as PVOID;
 				let class_device_obj_uptr: ULONG_PTR = class_device_obj as ULONG_PTR;
@@ -165,7 +185,7 @@ pub fn kbd_init(kbd_object: PKBD_OBJECT) -> NTSTATUS {
 					if *device_extension_ptr.offset(ext_idx_i) == class_device_obj_uptr
 						&& *device_extension_ptr.offset(ext_idx_i + 1) > class_driver_obj_uptr {
 							(*kbd_object).service_callback = Some(core::mem::transmute(*device_extension_ptr.offset(ext_idx_i + 1)));
-							DbgPrintEx(0, 0, "KBD Service callback address: %p\0".as_ptr(), (*kbd_object).service_callback.unwrap());
+							log_debug!(Discovery, "KBD Service callback address: {:p}", (*kbd_object).service_callback.unwrap());
 							break;
 					}
 				}
@@ -193,6 +213,76 @@ pub fn kbd_init(kbd_object: PKBD_OBJECT) -> NTSTATUS {
 		ObDereferenceObject(class_driver_obj as PVOID);
 		ObDereferenceObject(hid_driver_obj as PVOID);
 
+		// Check lookup results
+		if (*kbd_object).kbd_device.is_null() {
+			return Err(NtError::from_reason(NtReason::DeviceNotFound));
+		}
+		if (*kbd_object).service_callback.is_none() {
+			return Err(NtError::from_reason(NtReason::CallbackNotFound));
+		}
+	}
+	Ok(())
+}
+
+
+/// Keyboard event. Null keyboard object (not discovered) fails like a missing callback.
+///
+/// # Safety
+/// kbd_object must be null or point to a discovered KBD_OBJECT, IRQL <= DISPATCH_LEVEL
+pub unsafe fn kbd_event(kbd_object: PKBD_OBJECT, make_code: USHORT, flags: USHORT, extra_information: ULONG) -> NtResult<ULONG> {
+	if !kbd_object.is_null() && (*kbd_object).service_callback.is_some() {
+		let mut input_data: ULONG = 0u32;
+		let mut origin_irql: KIRQL = PASSIVE_LEVEL;
+		let mut kbd_input_data = kbd_input_data(make_code, flags, extra_information);
+		let kbd_input_data_ptr = &mut kbd_input_data as PKEYBOARD_INPUT_DATA;
+
+		log_trace!(Keyboard, "kbd_event: CALLBACK ADDR {:p}", (*kbd_object).service_callback.unwrap());
+		let mut frequency: i64 = 0;
+		let start = KeQueryPerformanceCounter(&mut frequency);
+		KeRaiseIrql(DISPATCH_LEVEL, &mut origin_irql as PKIRQL);
+		((*kbd_object).service_callback.unwrap())(
+			(*kbd_object).kbd_device,
+			kbd_input_data_ptr,
+			kbd_input_data_ptr.offset(1),
+			&mut input_data
+		);
+		KeLowerIrql(origin_irql);
+		let end = KeQueryPerformanceCounter(core::ptr::null_mut());
+		let latency_us = (end - start) as u64 * 1_000_000 / frequency as u64;
+		etw::write(tracelog::injection(tracelog::Device::Keyboard, 1, input_data, latency_us));
+		stats::keyboard_batch(flags & KEY_BREAK != 0, input_data, latency_us);
+		log_debug!(Keyboard, "kbd_event: service callback called with make_code: 0x{:04X} flags: 0x{:04X}", make_code, flags);
+		Ok(input_data)
+	} else {
+		log_error!(Keyboard, "kbd_event: service callback not defined");
+		Err(NtError::from_reason(NtReason::CallbackNotFound))
+	}
+}
+
+/// Call service callback with an empty batch, no input reaches the system.
+/// Not counted in statistics or traced. Returns consumed count.
+///
+/// # Safety
+/// kbd_object must be null or point to a discovered KBD_OBJECT, IRQL <= DISPATCH_LEVEL
+pub unsafe fn kbd_probe(kbd_object: PKBD_OBJECT) -> NtResult<ULONG> {
+	if !kbd_object.is_null() && (*kbd_object).service_callback.is_some() {
+		let mut input_data: ULONG = 0u32;
+		let mut origin_irql: KIRQL = PASSIVE_LEVEL;
+		let mut kbd_input_data = kbd_input_data(0, 0, 0);
+		let kbd_input_data_ptr = &mut kbd_input_data as PKEYBOARD_INPUT_DATA;
+
+		KeRaiseIrql(DISPATCH_LEVEL, &mut origin_irql as PKIRQL);
+		((*kbd_object).service_callback.unwrap())(
+			(*kbd_object).kbd_device,
+			kbd_input_data_ptr,
+			kbd_input_data_ptr,
+			&mut input_data
+		);
+		KeLowerIrql(origin_irql);
+		log_debug!(Keyboard, "kbd_probe: service callback called with empty batch, consumed {}", input_data);
+		Ok(input_data)
+	} else {
+		log_error!(Keyboard, "kbd_probe: service callback not defined");
+		Err(NtError::from_reason(NtReason::CallbackNotFound))
 	}
-	STATUS_SUCCESS
-}
\ No newline at end of file
+}
@@ -0,0 +1,118 @@
+// Arbitration between injecting sessions. A session may lease a device for a
+// time window, other sessions are refused until it ends. Pure bookkeeping,
+// expiry is checked against the time passed in by the caller.
+
+// Imports
+use crate::device::{DeviceRole, DEVICE_ROLE_COUNT};
+use crate::session::SessionId;
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// How a lease request is decided while another session holds the device
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum ArbitrationPolicy {
+	// First holder keeps the device until its lease ends
+	FirstCome = 0,
+	// Higher priority request takes the device over
+	Priority = 1
+}
+
+// Device held by a session
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Lease {
+	pub session: SessionId,
+	pub priority: u32,
+	pub expires_ms: u64
+}
+
+// Lease per device, indexed by DeviceRole
+pub struct LeaseTable {
+	leases: [Option<Lease>; DEVICE_ROLE_COUNT]
+}
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl ArbitrationPolicy {
+	pub fn from_u32(value: u32) -> ArbitrationPolicy {
+		match value {
+			0 => ArbitrationPolicy::FirstCome,
+			_ => ArbitrationPolicy::Priority
+		}
+	}
+}
+
+impl Default for LeaseTable {
+	fn default() -> LeaseTable {
+		LeaseTable::new()
+	}
+}
+
+impl LeaseTable {
+	pub const fn new() -> LeaseTable {
+		LeaseTable {
+			leases: [None; DEVICE_ROLE_COUNT]
+		}
+	}
+
+	// Acquire or renew lease. Returns the lease which keeps the device busy on failure.
+	pub fn acquire(&mut self, role: DeviceRole, session: SessionId, priority: u32, duration_ms: u32,
+		now_ms: u64, policy: ArbitrationPolicy) -> Result<(), Lease> {
+		let lease = Lease {
+			session,
+			priority,
+			expires_ms: now_ms + duration_ms as u64
+		};
+		match self.current(role, now_ms) {
+			Some(holder) if holder.session != session => {
+				if policy == ArbitrationPolicy::Priority && priority > holder.priority {
+					self.leases[role as usize] = Some(lease);
+					Ok(())
+				} else {
+					Err(holder)
+				}
+			}
+			_ => {
+				self.leases[role as usize] = Some(lease);
+				Ok(())
+			}
+		}
+	}
+
+	// Give up lease held by session, false if it held none
+	pub fn release(&mut self, role: DeviceRole, session: SessionId) -> bool {
+		let slot = &mut self.leases[role as usize];
+		if matches!(slot, Some(lease) if lease.session == session) {
+			*slot = None;
+			true
+		} else {
+			false
+		}
+	}
+
+	// Session is gone, drop all its leases
+	pub fn release_session(&mut self, session: SessionId) {
+		for slot in self.leases.iter_mut() {
+			if matches!(slot, Some(lease) if lease.session == session) {
+				*slot = None;
+			}
+		}
+	}
+
+	// May session (None for requests without one) inject on device now
+	pub fn allows(&mut self, role: DeviceRole, session: Option<SessionId>, now_ms: u64) -> bool {
+		match self.current(role, now_ms) {
+			Some(holder) => Some(holder.session) == session,
+			None => true
+		}
+	}
+
+	// Active lease, an expired one is dropped
+	pub fn current(&mut self, role: DeviceRole, now_ms: u64) -> Option<Lease> {
+		let slot = &mut self.leases[role as usize];
+		if matches!(slot, Some(lease) if lease.expires_ms <= now_ms) {
+			*slot = None;
+		}
+		*slot
+	}
+}
@@ -1,20 +1,15 @@
-// Remove standard library which cannot be used in the kernel
-#![no_std]
+// Remove standard library which cannot be used in the kernel.
+// Host unit tests link std, they only exercise pure modules.
+#![cfg_attr(not(test), no_std)]
 
 // Immports
+#[cfg(not(test))]
 use core::panic::PanicInfo;
-use winapi::shared::ntdef::{NTSTATUS, UNICODE_STRING, FALSE};
-use winapi::shared::ntstatus::{
-    STATUS_SUCCESS,
-    STATUS_INVALID_PARAMETER
-};
-use wchar::{wchz, wchar_t};
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicPtr, Ordering};
+use winapi::shared::ntdef::{NTSTATUS, UNICODE_STRING, PUNICODE_STRING, PVOID};
+use winapi::shared::ntstatus::{STATUS_SUCCESS, STATUS_NAME_TOO_LONG, STATUS_PENDING};
 use winapi::km::wdm::{
-    DbgPrintEx,
-    IoCreateDevice,
-    IoDeleteDevice,
-    IoCreateSymbolicLink,
-    IoDeleteSymbolicLink,
     IoCompleteRequest,
     IoGetCurrentIrpStackLocation,
     DRIVER_OBJECT,
@@ -42,31 +37,76 @@ use winapi_local::km::wdm::{
     IRP_MJ_MAXIMUM_FUNCTION,
     IRP_MJ_CREATE,
     IRP_MJ_CLOSE,
-    IRP_MJ_DEVICE_CONTROL,
-    FILE_DEVICE_SECURE_OPEN,
-    DO_DIRECT_IO,
-    DO_DEVICE_INITIALIZING
+    IRP_MJ_CLEANUP,
+    PFILE_OBJECT,
+    PKDPC,
+    IoGetCurrentProcess,
+    PsGetCurrentProcessId,
+    SeLocateProcessImageName,
+    ExFreePool,
+    KeGetCurrentIrql,
+    PEX_TIMER,
+    PASSIVE_LEVEL,
+    IRP_MJ_DEVICE_CONTROL
 };
 use mouse::{
     zeroed_mouse_object,
     mouse_init,
-    mouse_event,
+    mouse_input_data,
+    mouse_probe,
+    MOUSE_INPUT_DATA,
     MOUSE_OBJECT,
-    PMOUSE_OBJECT,
     MOUSE_LEFT_BUTTON_DOWN,
     MOUSE_LEFT_BUTTON_UP,
-    MOUSE_MOVE_ABSOLUTE
+    MOUSE_MOVE_RELATIVE,
+    MOUSE_MOVE_ABSOLUTE,
+    MOUSE_VIRTUAL_DESKTOP,
+    MOUSE_MOVE_NOCOALESCE
 };
 use keyboard::{
     zeroed_kbd_object,
     kbd_init,
-    KBD_OBJECT,
-    PKBD_OBJECT
+    kbd_input_data,
+    kbd_probe,
+    KEYBOARD_INPUT_DATA,
+    KEY_BREAK,
+    KEY_E0,
+    KEY_E1,
+    KBD_OBJECT
 };
-
-// Constants
-const IO_DEVICE_NAME: &[wchar_t] = wchz!("\\Device\\MetaDriver");
-const IO_SYMLINK_NAME: &[wchar_t] = wchz!("\\??\\MetaDriver");
+use error::{
+    NtError,
+    NtReason,
+    NtResult,
+    io_status
+};
+use registry::RegistryKey;
+use config::{
+    DriverConfig,
+    ConfigStaging,
+    ConfigReply,
+    ConfigReport,
+    WideName,
+    UnconsumedPolicy,
+    PARAMETERS_KEY_NAME
+};
+use sync::{SpinLock, Mutex};
+use device::{DeviceRole, DEVICE_ROLES};
+use session::{SessionTable, SessionId, HeldInput, InputEvent};
+use holds::{HoldTable, HoldInput, Hold, MAX_HOLDS};
+use arming::ArmSwitch;
+use ratelimit::{TokenBucket, RateDecision, RatePolicy};
+use lease::LeaseTable;
+use allowlist::AllowedImages;
+use auth::{AuthFailure, AuthTrailer};
+use audit::{AuditRing, AuditRecord};
+use stats::Stats;
+use selftest::{SelfTestReport, ProbeReport};
+use pending::{PendingIrp, Parked};
+use playback::{Playback, PlaybackHeader, PlaybackEvent, PLAYBACK_DEVICE_MOUSE, PLAYBACK_DEVICE_KEYBOARD};
+use sink::{InputSink, SinkKind, ClassSink, LoopbackSink, LoopbackQueue, NullSink};
+use log::{Status, WideStr};
+use tracelog::Device;
 
 // IRP CODES & OBJECTS
 pub const fn CTL_CODE(
@@ -77,103 +117,310 @@ pub const fn CTL_CODE(
 ) -> DWORD {
     (DeviceType << 16) | (Access << 14) | (Function << 2) | Method
 }
+// Mouse device
 const META_IRP_MOUSE_EVENT: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf9004, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+const META_IRP_MOUSE_INPUT: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf9007, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+const META_IRP_MOUSE_HOLD: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf9009, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+const META_IRP_MOUSE_LEASE: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf900b, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+const META_IRP_MOUSE_LOOPBACK_READ: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf9012, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+// Keyboard device
+const META_IRP_KEYBOARD_EVENT: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf9006, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+const META_IRP_KEYBOARD_HOLD: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf9008, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+const META_IRP_KEYBOARD_LEASE: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf900c, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+const META_IRP_KEYBOARD_LOOPBACK_READ: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf9013, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+// Control device
+const META_IRP_RELOAD_CONFIG: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf9005, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+const META_IRP_SET_ARMED: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf900a, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+const META_IRP_AUDIT_SNAPSHOT: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf900e, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+const META_IRP_AUDIT_CLEAR: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf900f, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+const META_IRP_STATS: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf9010, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+const META_IRP_SELF_TEST: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf9014, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+// Mouse and keyboard device, events must all be of the device the request is sent to
+const META_IRP_PLAYBACK: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf9015, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+const META_IRP_SET_DRY_RUN: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf9011, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
+// Every device, never authenticated
+const META_IRP_AUTH_NONCE: DWORD = CTL_CODE(DEVICE_TYPE::FILE_DEVICE_UNKNOWN as DWORD, 0xf900d, METHOD_BUFFERED, FILE_SPECIAL_ACCESS);
 
+#[repr(C)]
 pub struct MouseRequest {
     x: u32,
     y: u32,
+    // REQUEST_POLICY_*, optional
+    unconsumed_policy: u32,
 }
 pub type PMouseRequest = *mut MouseRequest;
 
+// Mouse move and button request. Flags are MOUSE_MOVE_* and button flags
+// MOUSE_BUTTON_*_DOWN / _UP, wheel is not supported.
+#[repr(C)]
+pub struct MouseInputRequest {
+    flags: u16,
+    button_flags: u16,
+    x: i32,
+    y: i32,
+    // REQUEST_POLICY_*, optional
+    unconsumed_policy: u32,
+}
+pub type PMouseInputRequest = *mut MouseInputRequest;
+
+// Allowed MouseInputRequest flags
+const MOUSE_INPUT_FLAGS: USHORT = MOUSE_MOVE_ABSOLUTE | MOUSE_VIRTUAL_DESKTOP | MOUSE_MOVE_NOCOALESCE;
+const MOUSE_INPUT_BUTTON_FLAGS: USHORT = 0x03FF;
+
+// Key scan code with KEY_BREAK / KEY_E0 / KEY_E1 flags
+#[repr(C)]
+pub struct KeyboardRequest {
+    make_code: u16,
+    flags: u16,
+    // REQUEST_POLICY_*, optional
+    unconsumed_policy: u32,
+}
+pub type PKeyboardRequest = *mut KeyboardRequest;
+
+// Unconsumed event handling of one input request. Clients which end the request
+// before unconsumed_policy get the configured UnconsumedPolicy.
+const REQUEST_POLICY_DEFAULT: u32 = 0;
+const REQUEST_POLICY_REPORT: u32 = 1;
+const REQUEST_POLICY_RETRY: u32 = 2;
+
+// Shortest accepted input requests, without unconsumed_policy
+const MOUSE_REQUEST_MIN_SIZE: usize = core::mem::offset_of!(MouseRequest, unconsumed_policy);
+const MOUSE_INPUT_REQUEST_MIN_SIZE: usize = core::mem::offset_of!(MouseInputRequest, unconsumed_policy);
+const KEYBOARD_REQUEST_MIN_SIZE: usize = core::mem::offset_of!(KeyboardRequest, unconsumed_policy);
+
+// Press button (1 left .. 5 X2), released by the driver after duration.
+// Pends until the release, cancelling the request releases the button at once.
+pub struct MouseHoldRequest {
+    button: u16,
+    reserved: u16,
+    duration_ms: u32,
+}
+pub type PMouseHoldRequest = *mut MouseHoldRequest;
+
+// Press key (flags 0 or KEY_E0), released by the driver after duration.
+// Pends until the release, cancelling the request releases the key at once.
+pub struct KeyboardHoldRequest {
+    make_code: u16,
+    flags: u16,
+    duration_ms: u32,
+}
+pub type PKeyboardHoldRequest = *mut KeyboardHoldRequest;
+
+// Kill switch: 1 arms or refreshes auto-disarm, 0 disarms
+pub struct ArmRequest {
+    armed: u32,
+}
+pub type PArmRequest = *mut ArmRequest;
+
+// Kill switch state after the request
+pub struct ArmReply {
+    pub armed: u32,
+    // Time left until auto-disarm, 0 if there is none
+    pub remaining_ms: u32,
+}
+
+// Lease of the device the request is sent to, duration 0 releases it.
+// Priority only matters with the Priority arbitration policy.
+pub struct LeaseRequest {
+    priority: u32,
+    duration_ms: u32,
+}
+pub type PLeaseRequest = *mut LeaseRequest;
+
+// Nonce of the handle, clients include it in request MACs
+pub struct AuthNonceReply {
+    pub nonce: u64,
+}
+
+// AUDIT_SNAPSHOT reply, followed by record_count AuditRecords, oldest first.
+// Newest records are returned if the output buffer is too small for all.
+pub struct AuditSnapshotHeader {
+    // Records pushed since load, gaps in record ids show overwritten ones
+    pub total_count: u64,
+    pub record_count: u32,
+    pub capacity: u32,
+}
+
+// STATS request, optional. Reply is stats::Stats.
+pub struct StatsRequest {
+    // 1 resets counters after the snapshot
+    reset: u32,
+}
+pub type PStatsRequest = *mut StatsRequest;
+
+// Dry run of the session the request is sent on: 1 validates, audits and
+// counts input without injecting it, 0 injects again
+pub struct DryRunRequest {
+    enabled: u32,
+}
+pub type PDryRunRequest = *mut DryRunRequest;
+
+// PLAYBACK reply. The request is pended and completed when the sequence ends.
+// On failure no reply is copied, IoStatus.Information holds events played instead.
+pub struct PlaybackReply {
+    pub delivered: u32,
+}
+
+// Poll interval of requests waiting for a leased device
+const LEASE_POLL_MS: u64 = 10;
+
+// Max time a request waits for rate limit tokens, longer waits are rejected
+const MAX_RATE_DELAY_MS: u64 = 1000;
+
+// First delay before an unconsumed event is sent again, doubles per retry
+const UNCONSUMED_BACKOFF_MS: u64 = 1;
+
+// Max events played by one playback timer run, rest follows at once
+const PLAYBACK_BATCH: usize = 16;
+
+// Max holds released by one watchdog DPC run, rest follows at once
+const WATCHDOG_BATCH: usize = 16;
+
 // Define modules
 pub mod winapi_local;
+#[macro_use]
+pub mod log;
+pub mod error;
+pub mod registry;
+pub mod config;
+pub mod sync;
+pub mod device;
+pub mod session;
+pub mod arming;
+pub mod ratelimit;
+pub mod lease;
+pub mod allowlist;
+pub mod auth;
+pub mod audit;
+pub mod stats;
+pub mod sink;
+pub mod selftest;
+pub mod playback;
+pub mod hrtimer;
+pub mod pending;
+pub mod clock;
+pub mod holds;
+pub mod watchdog;
+pub mod tracelog;
+pub mod etw;
+pub mod eventlog;
 pub mod mouse;
 pub mod keyboard;
 
-// Static mouse object
-static mut GLOBAL_MOUSE_OBJ: MOUSE_OBJECT = zeroed_mouse_object();
+// Driver state shared between dispatch routines
+struct DriverState {
+    // Effective configuration, read in driver_init and on RELOAD_CONFIG
+    config: DriverConfig,
+    // Process image allowlist checked at handle open, loaded with config
+    allowed_images: AllowedImages,
+    // Session of every open handle
+    sessions: SessionTable,
+    // Release deadlines of pressed input, served by watchdog
+    holds: HoldTable,
+    // Kill switch, injection is rejected while disarmed
+    arm: ArmSwitch,
+    // Driver-wide rate limit state
+    mouse_bucket: TokenBucket,
+    keyboard_bucket: TokenBucket,
+    // Device leases of sessions
+    leases: LeaseTable,
+    // Session input each pended hold request waits for, by hold slot
+    hold_waits: [Option<(SessionId, HoldInput)>; MAX_HOLDS]
+}
+
+// Driver state lock
+static GLOBAL_STATE: SpinLock<DriverState> = SpinLock::new(DriverState {
+    config: DriverConfig::defaults(),
+    allowed_images: AllowedImages::new(),
+    sessions: SessionTable::new(),
+    holds: HoldTable::new(),
+    arm: ArmSwitch::new(),
+    mouse_bucket: TokenBucket::new(),
+    keyboard_bucket: TokenBucket::new(),
+    leases: LeaseTable::new(),
+    hold_waits: [None; MAX_HOLDS]
+});
+
+// Configuration being read, serializes loads. Swapped into GLOBAL_STATE once validated.
+static CONFIG_STAGING: Mutex<ConfigStaging> = Mutex::new(ConfigStaging::new());
+
+// Service key path for reloads, RegistryPath is only valid in driver_entry
+static mut SERVICE_KEY_PATH: Option<WideName> = None;
+
+// Discovered class objects. Discovery fills them under DISCOVERY_LOCK while
+// they are unpublished, published ones are never written again.
+static mut MOUSE_OBJ_STORAGE: MOUSE_OBJECT = zeroed_mouse_object();
+static mut KBD_OBJ_STORAGE: KBD_OBJECT = zeroed_kbd_object();
+
+// Published class objects, null until discovery succeeded. Readers at any
+// IRQL load these pointers instead of touching the storage.
+static GLOBAL_MOUSE_OBJ: AtomicPtr<MOUSE_OBJECT> = AtomicPtr::new(core::ptr::null_mut());
+static GLOBAL_KBD_OBJ: AtomicPtr<KBD_OBJECT> = AtomicPtr::new(core::ptr::null_mut());
+
+// Serializes discovery at load and rediscovery from dispatch threads
+static DISCOVERY_LOCK: Mutex<()> = Mutex::new(());
+
+// Set once mouse rediscovery failure is in the event log, cleared on success
+static MOUSE_REDISCOVERY_REPORTED: AtomicBool = AtomicBool::new(false);
+
+// Same for keyboard
+static KEYBOARD_REDISCOVERY_REPORTED: AtomicBool = AtomicBool::new(false);
+
+// Handles opened and not yet closed, reported on unload
+static OPEN_HANDLES: AtomicU32 = AtomicU32::new(0);
+
+// Recent injections, own lock so snapshots do not block dispatch state
+static AUDIT_RING: SpinLock<AuditRing> = SpinLock::new(AuditRing::new());
+
+// Loopback sink queues, one global queue per device with a single consumer
+static LOOPBACK_MOUSE: SpinLock<LoopbackQueue<MOUSE_INPUT_DATA>> =
+    SpinLock::new(LoopbackQueue::new(mouse_input_data(0, 0, 0, 0, 0)));
+static LOOPBACK_KEYBOARD: SpinLock<LoopbackQueue<KEYBOARD_INPUT_DATA>> =
+    SpinLock::new(LoopbackQueue::new(kbd_input_data(0, 0, 0)));
+
+// Loopback reads waiting for events, used under the lock of their queue
+static LOOPBACK_MOUSE_READ: PendingIrp = PendingIrp::new();
+static LOOPBACK_KEYBOARD_READ: PendingIrp = PendingIrp::new();
+
+// Scheduled playback, one sequence at a time driver-wide
+static PLAYBACK: SpinLock<Playback> = SpinLock::new(Playback::new());
+
+// Pending PLAYBACK request, used under the PLAYBACK lock
+static PLAYBACK_IRP: PendingIrp = PendingIrp::new();
+
+// Pending MOUSE_HOLD and KEYBOARD_HOLD requests by hold slot, used under the GLOBAL_STATE lock
+static HOLD_IRPS: [PendingIrp; MAX_HOLDS] = [const { PendingIrp::new() }; MAX_HOLDS];
+
+// Next session nonce, seeded from the clock so nonces differ between driver loads
+static NEXT_NONCE: AtomicU64 = AtomicU64::new(0);
 
 // Temporary _fltused fix
+#[cfg(not(test))]
 #[no_mangle]
 pub static _fltused: i32 = 0;
 
 // Define our own panic handler (defeule RUST panic handler was in standard lib)
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
     loop {}
 }
 
-// Entry point
+// Entry point, signature fixed by the I/O manager
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "system" fn driver_entry(
     driver: &mut DRIVER_OBJECT,
-    _: *const UNICODE_STRING,
+    registry_path: *const UNICODE_STRING,
 ) -> NTSTATUS {
     // Unsafe code
-    unsafe {
-
-        // Debug output
-        DbgPrintEx(0, 0, "meta_driver loaded. Start initialization.\0".as_ptr());
-
-        // Create and normalize device name
-        let mut io_device_name_unicode: UNICODE_STRING = zeroed_unicode_string();
-        RtlInitUnicodeString(&mut io_device_name_unicode as *mut UNICODE_STRING,
-            IO_DEVICE_NAME.as_ptr());
-        // Debug check
-        DbgPrintEx(0, 0, "Unicode device name struct initialized. Buffer size is %u.\0".as_ptr(), io_device_name_unicode.Length as u32);
-
-        // Create and normalize symlink name
-        let mut io_symlink_name_unicode: UNICODE_STRING = zeroed_unicode_string();
-        RtlInitUnicodeString(&mut io_symlink_name_unicode as *mut UNICODE_STRING,
-            IO_SYMLINK_NAME.as_ptr());
-        // Debug check
-        DbgPrintEx(0, 0, "Unicode device symlink struct initialized. Buffer size is %u.\0".as_ptr(), io_symlink_name_unicode.Length as u32);
-
-        // Create IO device
-        let mut device_obj_ptr: PDEVICE_OBJECT = core::ptr::null_mut();
-        let status: NTSTATUS = IoCreateDevice(
-            driver as PDRIVER_OBJECT,
-            0,
-            &mut io_device_name_unicode as *mut UNICODE_STRING,
-            DEVICE_TYPE::FILE_DEVICE_UNKNOWN,
-            FILE_DEVICE_SECURE_OPEN,
-            FALSE,
-            &mut device_obj_ptr
-        );
-        DbgPrintEx(0, 0, "IoCreateDevice status: %u\0".as_ptr(), status);
-
-        // Create IO symlink
-        let status: NTSTATUS = IoCreateSymbolicLink(&io_symlink_name_unicode, &io_device_name_unicode);
-        DbgPrintEx(0, 0, "IoCreateSymbolicLink status: %u\0".as_ptr(), status);
-
-        // Assign driver major functions
-        for func_idx in 0..IRP_MJ_MAXIMUM_FUNCTION {
-            driver.MajorFunction[func_idx as usize] = Some(irp_mj_unsupported);
-        }
-        
-        driver.MajorFunction[IRP_MJ_CREATE] = Some(irp_mj_create);
-        driver.MajorFunction[IRP_MJ_CLOSE] = Some(irp_mj_close);
-        driver.MajorFunction[IRP_MJ_DEVICE_CONTROL] = Some(irp_mj_device_control);
-        
-
-        // Set device flags
-        (*device_obj_ptr).Flags |= DO_DIRECT_IO;
-        (*device_obj_ptr).Flags &= !DO_DEVICE_INITIALIZING;
-
-        // Init mouse
-        let mouse_init_status = mouse_init(&mut GLOBAL_MOUSE_OBJ as PMOUSE_OBJECT);
-        DbgPrintEx(0, 0, "mouse::mouse_init status: %u\0".as_ptr(), mouse_init_status);
-
-        // Init keyboard
-        let mut kbd_object = zeroed_kbd_object();
-        let kdb_init_status = kbd_init(&mut kbd_object as PKBD_OBJECT);
-        
-        // Test
-        // let mut flags: USHORT = 0;
-        // flags |= MOUSE_MOVE_ABSOLUTE;
-        // mouse_event(&mut GLOBAL_MOUSE_OBJ as PMOUSE_OBJECT, 10, 10, 0x0000, flags);
-        //DbgPrintEx(0, 0, "MOUSE POINTER: %p\0".as_ptr(), GLOBAL_MOUSE_OBJ_PTR);
+    if let Err(error) = unsafe { driver_init(driver, registry_path) } {
+        log_error!(Driver, "meta_driver initialization failed: {}", error);
+        etw::write(tracelog::driver_load(error.status));
+        etw::unregister();
+        return error.status;
     }
+    etw::write(tracelog::driver_load(STATUS_SUCCESS));
 
     // Assign unload function
     driver.DriverUnload = Some(driver_exit);
@@ -183,64 +430,1615 @@ pub extern "system" fn driver_entry(
     STATUS_SUCCESS
 }
 
+// Driver initialization
+unsafe fn driver_init(driver: &mut DRIVER_OBJECT, registry_path: *const UNICODE_STRING) -> NtResult<()> {
+    // Event log entries are owned by driver object
+    eventlog::set_io_object(driver as *mut DRIVER_OBJECT as _);
+
+    // Load configuration, invalid values fall back to defaults.
+    // Staging stays locked while initialization reads it.
+    CONFIG_STAGING.init();
+    let mut staging = CONFIG_STAGING.lock();
+    let mut report = ConfigReport::new();
+    if let Err(error) = read_config(registry_path, &mut staging, &mut report) {
+        log_info!(Driver, "Parameters key not opened, default configuration used: {}", error);
+        staging.reset();
+    }
+    let config = &staging.config;
+    log::apply_levels(&config.log_levels);
+    report_config_issues(&report);
+    {
+        let mut state = GLOBAL_STATE.lock();
+        state.config.clone_from(config);
+        state.allowed_images = staging.allowed_images;
+    }
+    AUDIT_RING.lock().set_capacity(config.audit_capacity as usize);
+
+    // Counters start now
+    stats::start(clock::now_ms());
+
+    // Session nonces, unique while the system is up
+    NEXT_NONCE.store(clock::now_ms() << 20, Ordering::Relaxed);
+
+    // Keep service key path for RELOAD_CONFIG
+    let registry_units = core::slice::from_raw_parts((*registry_path).Buffer, (*registry_path).Length as usize / 2);
+    let service_key_path = WideName::from_units(registry_units);
+    if service_key_path.is_none() {
+        log_warn!(Driver, "Service key path too long, configuration reload unavailable.");
+    }
+    SERVICE_KEY_PATH = service_key_path;
+
+    // Debug output
+    log_info!(Driver, "meta_driver loaded. Start initialization.");
+
+    // Discovery may run from any dispatch thread once devices exist
+    DISCOVERY_LOCK.init();
+
+    // Register ETW provider. Driver works without it.
+    if let Err(error) = etw::register() {
+        log_warn!(Driver, "ETW provider not registered: {}", error);
+    }
+
+    // Hold releases and auto-disarm, armed by injections and SET_ARMED
+    watchdog::init(watchdog_dpc);
+    // Scheduled playback, fails on systems before Windows 8.1
+    if let Err(error) = hrtimer::init(playback_timer) {
+        log_warn!(Driver, "Playback timer not available, PLAYBACK is refused: {}", error);
+    }
+
+    // Create mouse, keyboard and control devices, each with own symlink and security
+    for role in DEVICE_ROLES.iter() {
+        if let Err(error) = device::create_device(driver as PDRIVER_OBJECT, *role, &config.devices[*role as usize]) {
+            log_error!(Driver, "Creating {} device failed: {}", role.name(), error);
+            device::delete_devices(driver as PDRIVER_OBJECT, &config.devices);
+            stop_timers();
+            return Err(error);
+        }
+    }
+
+    // Auto-disarm deadline starts once the driver can no longer fail to load
+    if config.start_armed {
+        arm();
+    } else {
+        log_warn!(Driver, "Starting disarmed, injection needs SET_ARMED.");
+    }
+
+    // Assign driver major functions
+    for func_idx in 0..IRP_MJ_MAXIMUM_FUNCTION {
+        driver.MajorFunction[func_idx as usize] = Some(irp_mj_unsupported);
+    }
+    
+    driver.MajorFunction[IRP_MJ_CREATE] = Some(irp_mj_create);
+    driver.MajorFunction[IRP_MJ_CLEANUP] = Some(irp_mj_cleanup);
+    driver.MajorFunction[IRP_MJ_CLOSE] = Some(irp_mj_close);
+    driver.MajorFunction[IRP_MJ_DEVICE_CONTROL] = Some(irp_mj_device_control);
+
+    // Init mouse. Driver stays loaded without it, mouse requests will fail.
+    let mouse_init_result = discover_mouse(&config.mouse_hid_driver);
+    if let Err(error) = mouse_init_result {
+        log_error!(Discovery, "mouse::mouse_init failed: {}", error);
+        eventlog::no_class_callback(Device::Mouse.name(), error.status);
+    }
+    trace_discovery(Device::Mouse, mouse_init_result, mouse_init_result.is_ok());
+
+    // Init keyboard. Driver stays loaded without it, keyboard requests will fail.
+    let kbd_init_result = discover_keyboard(&config.keyboard_hid_driver);
+    if let Err(error) = kbd_init_result {
+        log_error!(Discovery, "keyboard::kbd_init failed: {}", error);
+        eventlog::no_class_callback(Device::Keyboard.name(), error.status);
+    }
+    trace_discovery(Device::Keyboard, kbd_init_result, kbd_init_result.is_ok());
+    
+    // Test
+    // let mut flags: USHORT = 0;
+    // flags |= MOUSE_MOVE_ABSOLUTE;
+    // mouse_event(&mut GLOBAL_MOUSE_OBJ as PMOUSE_OBJECT, 10, 10, 0x0000, flags);
+    // log_trace!(Mouse, "MOUSE POINTER: {:p}", GLOBAL_MOUSE_OBJ_PTR);
+
+    Ok(())
+}
+
+// Stop watchdog and release playback timer, on unload and on every failure
+// after they were initialized. No DPC may run once the image is unloaded.
+unsafe fn stop_timers() {
+    watchdog::stop();
+    hrtimer::stop();
+}
+
+// Read configuration from Parameters subkey of service key.
+// Missing values give defaults, invalid values are reported and replaced by defaults.
+// Staging is left untouched if the key cannot be opened.
+unsafe fn read_config(service_key_path: *const UNICODE_STRING, staging: &mut ConfigStaging, report: &mut ConfigReport) -> NtResult<()> {
+    let parameters = RegistryKey::open(service_key_path)?.open_subkey(PARAMETERS_KEY_NAME)?;
+    staging.load(&parameters, report);
+    Ok(())
+}
+
+// Re-read configuration and swap runtime values in.
+// Nothing is applied if the key cannot be opened or any value is invalid. Reply is filled in place.
+unsafe fn reload_config(reply: &mut ConfigReply) -> NtResult<()> {
+    let service_key_path = match SERVICE_KEY_PATH {
+        Some(path) => path,
+        None => return Err(NtError::new(STATUS_NAME_TOO_LONG, NtReason::RegistryOpenFailed))
+    };
+    let mut service_key_unicode: UNICODE_STRING = zeroed_unicode_string();
+    RtlInitUnicodeString(&mut service_key_unicode as *mut UNICODE_STRING,
+        service_key_path.as_wchz().as_ptr());
+
+    let mut staging = CONFIG_STAGING.lock();
+    let mut report = ConfigReport::new();
+    read_config(&service_key_unicode, &mut staging, &mut report)?;
+    if !report.is_empty() {
+        report_config_issues(&report);
+        return Err(NtError::from_reason(NtReason::ConfigInvalid));
+    }
+
+    let restart_required = {
+        let mut state = GLOBAL_STATE.lock();
+        let restart_required = state.config.apply_runtime(&staging.config);
+        state.allowed_images = staging.allowed_images;
+        log::apply_levels(&state.config.log_levels);
+        AUDIT_RING.lock().set_capacity(state.config.audit_capacity as usize);
+        state.config.fill_reply(&state.allowed_images, reply, restart_required);
+        restart_required
+    };
+    log_info!(Driver, "Configuration reloaded, restart required: {}.", restart_required);
+    Ok(())
+}
+
+// Log invalid configuration values, each one also goes to the event log
+fn report_config_issues(report: &ConfigReport) {
+    for issue in report.issues() {
+        log_warn!(Driver, "Configuration value {} invalid: {}.", WideStr(issue.value_name), issue.kind.description());
+        eventlog::config_invalid(&WideStr(issue.value_name), issue.kind.description());
+    }
+}
+
+// Discovery result ETW event
+fn trace_discovery(device: Device, result: NtResult<()>, callback_found: bool) {
+    stats::discovery(device, result.is_ok() && callback_found);
+    let (status, reason) = match result {
+        Ok(()) => (STATUS_SUCCESS, NtReason::Kernel),
+        Err(error) => (error.status, error.reason)
+    };
+    etw::write(tracelog::discovery(device, status, reason as u32, callback_found));
+}
+
+// Discover mouse class objects and publish them. Concurrent callers wait for
+// the running discovery and then find the object published.
+unsafe fn discover_mouse(hid_driver_name: &WideName) -> NtResult<()> {
+    let _discovery = DISCOVERY_LOCK.lock();
+    if !GLOBAL_MOUSE_OBJ.load(Ordering::Acquire).is_null() {
+        return Ok(());
+    }
+    MOUSE_OBJ_STORAGE = zeroed_mouse_object();
+    let result = mouse_init(addr_of_mut!(MOUSE_OBJ_STORAGE), hid_driver_name.as_wchz());
+    if result.is_ok() {
+        GLOBAL_MOUSE_OBJ.store(addr_of_mut!(MOUSE_OBJ_STORAGE), Ordering::Release);
+    }
+    result
+}
+
+// Same for keyboard
+unsafe fn discover_keyboard(hid_driver_name: &WideName) -> NtResult<()> {
+    let _discovery = DISCOVERY_LOCK.lock();
+    if !GLOBAL_KBD_OBJ.load(Ordering::Acquire).is_null() {
+        return Ok(());
+    }
+    KBD_OBJ_STORAGE = zeroed_kbd_object();
+    let result = kbd_init(addr_of_mut!(KBD_OBJ_STORAGE), hid_driver_name.as_wchz());
+    if result.is_ok() {
+        GLOBAL_KBD_OBJ.store(addr_of_mut!(KBD_OBJ_STORAGE), Ordering::Release);
+    }
+    result
+}
+
+// Retry mouse discovery if callback was not found at load,
+// e.g. mouse stack was not ready yet.
+unsafe fn ensure_mouse_discovered() -> NtResult<()> {
+    if !GLOBAL_MOUSE_OBJ.load(Ordering::Acquire).is_null() {
+        return Ok(());
+    }
+    log_info!(Discovery, "Mouse service callback missing, rediscovery started.");
+    let hid_driver_name = GLOBAL_STATE.lock().config.mouse_hid_driver;
+    let result = discover_mouse(&hid_driver_name);
+    trace_discovery(Device::Mouse, result, result.is_ok());
+    match result {
+        Ok(()) => {
+            MOUSE_REDISCOVERY_REPORTED.store(false, Ordering::Relaxed);
+            Ok(())
+        }
+        Err(error) => {
+            log_error!(Discovery, "Mouse rediscovery failed: {}", error);
+            // One event log entry per failure streak
+            if !MOUSE_REDISCOVERY_REPORTED.swap(true, Ordering::Relaxed) {
+                eventlog::rediscovery_failed(Device::Mouse.name(), error.status);
+            }
+            Err(error)
+        }
+    }
+}
+
+// Retry keyboard discovery, same as for mouse
+unsafe fn ensure_keyboard_discovered() -> NtResult<()> {
+    if !GLOBAL_KBD_OBJ.load(Ordering::Acquire).is_null() {
+        return Ok(());
+    }
+    log_info!(Discovery, "Keyboard service callback missing, rediscovery started.");
+    let hid_driver_name = GLOBAL_STATE.lock().config.keyboard_hid_driver;
+    let result = discover_keyboard(&hid_driver_name);
+    trace_discovery(Device::Keyboard, result, result.is_ok());
+    match result {
+        Ok(()) => {
+            KEYBOARD_REDISCOVERY_REPORTED.store(false, Ordering::Relaxed);
+            Ok(())
+        }
+        Err(error) => {
+            log_error!(Discovery, "Keyboard rediscovery failed: {}", error);
+            // One event log entry per failure streak
+            if !KEYBOARD_REDISCOVERY_REPORTED.swap(true, Ordering::Relaxed) {
+                eventlog::rediscovery_failed(Device::Keyboard.name(), error.status);
+            }
+            Err(error)
+        }
+    }
+}
+
+// Complete IRP with dispatch result.
+// This is the only place where a result becomes the IRP's IoStatus.
+unsafe fn complete_request(irp: &mut IRP, result: NtResult<usize>) -> NTSTATUS {
+    let (status, information) = io_status(&result);
+    complete_with_information(irp, status, information)
+}
+
+// Complete request with explicit Information
+unsafe fn complete_with_information(irp: &mut IRP, status: NTSTATUS, information: usize) -> NTSTATUS {
+    irp.IoStatus.Information = information;
+    let io_status = irp.IoStatus.__bindgen_anon_1.Status_mut();
+    *io_status = status;
+
+    IoCompleteRequest(irp as PIRP, IO_PRIORITY::IO_NO_INCREMENT);
+
+    status
+}
+
 // I/O Request Package Major function - create - Unsupported
 pub unsafe extern "system" fn irp_mj_unsupported(device: &mut DEVICE_OBJECT, irp: &mut IRP) -> NTSTATUS {
-    DbgPrintEx(0, 0, "Unsupported IRP called.\0".as_ptr());
-    STATUS_SUCCESS
+    log_debug!(Dispatch, "Unsupported IRP called.");
+    complete_request(irp, Err(NtError::from_reason(NtReason::UnsupportedIoctl)))
 }
 
 // I/O Request Package Major function - device_control
 pub unsafe extern "system" fn irp_mj_device_control(device: &mut DEVICE_OBJECT, irp: &mut IRP) -> NTSTATUS {
-    let mut status: NTSTATUS = STATUS_SUCCESS;
-    DbgPrintEx(0, 0, "Device control IRP called.\0".as_ptr());
-
+    log_trace!(Dispatch, "Device control IRP called.");
     let io_stack_location_ptr: PIO_STACK_LOCATION = IoGetCurrentIrpStackLocation(irp as PIRP);
     let io_control_code: ULONG = (*io_stack_location_ptr).Parameters.DeviceIoControl().IoControlCode;
-    DbgPrintEx(0, 0, "IRP_MJ_DEVICE_CONTROL>>IO control code: %u.\0".as_ptr(), io_control_code);
-    DbgPrintEx(0, 0, "IRP_MJ_DEVICE_CONTROL>>MouseEvent control code: %u.\0".as_ptr(), META_IRP_MOUSE_EVENT);
+    let result = if io_control_code == META_IRP_AUTH_NONCE {
+        auth_nonce(irp)
+    } else if let Some(handler) = pending_handler(device::device_role(device), io_control_code) {
+        // Long running requests, None means pended with a cancel routine
+        match authenticate(irp, io_control_code).and_then(|input_length| handler(irp, input_length)) {
+            Ok(Some(information)) => Ok(information),
+            Ok(None) => return STATUS_PENDING,
+            Err(error) => Err(error)
+        }
+    } else {
+        authenticate(irp, io_control_code).and_then(|input_length| match device::device_role(device) {
+            _ if io_control_code == META_IRP_SET_DRY_RUN => set_dry_run(irp, input_length),
+            DeviceRole::Mouse => mouse_control(irp, io_control_code, input_length),
+            DeviceRole::Keyboard => keyboard_control(irp, io_control_code, input_length),
+            DeviceRole::Control => control_control(irp, io_control_code, input_length)
+        })
+    };
+    if let Err(error) = result {
+        log_warn!(Dispatch, "IRP_MJ_DEVICE_CONTROL>>Request 0x{:08X} failed: {}", io_control_code, error);
+        etw::write(tracelog::rejected(io_control_code, error.status, error.reason as u32));
+        stats::rejected(error.reason);
+    }
+    complete_request(irp, result)
+}
 
-    let mut bytes_io = 0;
+// Nonce of the session the request was sent on
+unsafe fn auth_nonce(irp: &mut IRP) -> NtResult<usize> {
+    if output_buffer_length(irp) < core::mem::size_of::<AuthNonceReply>() {
+        return Err(NtError::from_reason(NtReason::OutputBufferTooSmall));
+    }
+    let nonce = match request_session(irp) {
+        Some(session_id) => GLOBAL_STATE.lock().sessions.get(session_id).map(|session| session.nonce),
+        None => None
+    };
+    match nonce {
+        Some(nonce) => {
+            *(*irp.AssociatedIrp.SystemBuffer() as *mut AuthNonceReply) = AuthNonceReply { nonce };
+            Ok(core::mem::size_of::<AuthNonceReply>())
+        }
+        None => Err(NtError::from_reason(NtReason::InvalidRequest))
+    }
+}
 
-    if io_control_code == META_IRP_MOUSE_EVENT {
-        bytes_io = core::mem::size_of::<MouseRequest>();
-        let mouse_request: &MouseRequest = &(*(*irp.AssociatedIrp.SystemBuffer() as PMouseRequest));
-        /*let mut flags: USHORT = 0;
-        flags |= MOUSE_MOVE_ABSOLUTE;
-        mouse_event(GLOBAL_MOUSE_OBJ_PTR, mouse_request.x as _, mouse_request.y as _, 0x0000, flags);
-        DbgPrintEx(0, 0, "IRP_MJ_DEVICE_CONTROL>>x: %u.\0".as_ptr(), mouse_request.x);*/
-        let mut flags: USHORT = 0;
-        flags |= MOUSE_MOVE_ABSOLUTE;
-        mouse_event(&mut GLOBAL_MOUSE_OBJ as PMOUSE_OBJECT, mouse_request.x as _, mouse_request.y as _, 0x0000, flags);
-        //DbgPrintEx(0, 0, "!MOUSE POINTER: %p\0".as_ptr(), GLOBAL_MOUSE_OBJ_PTR);
+// Handler of a request which may be pended, called with the request body length.
+// Returns None once the request is pended.
+type PendingHandler = unsafe fn(&mut IRP, usize) -> NtResult<Option<usize>>;
 
+// Requests which may be pended
+fn pending_handler(role: DeviceRole, io_control_code: ULONG) -> Option<PendingHandler> {
+    match (role, io_control_code) {
+        (DeviceRole::Mouse, META_IRP_PLAYBACK) => Some(mouse_playback),
+        (DeviceRole::Keyboard, META_IRP_PLAYBACK) => Some(keyboard_playback),
+        (DeviceRole::Mouse, META_IRP_MOUSE_HOLD) => Some(mouse_hold),
+        (DeviceRole::Keyboard, META_IRP_KEYBOARD_HOLD) => Some(keyboard_hold),
+        (DeviceRole::Mouse, META_IRP_MOUSE_LOOPBACK_READ) => Some(mouse_loopback_read),
+        (DeviceRole::Keyboard, META_IRP_KEYBOARD_LOOPBACK_READ) => Some(keyboard_loopback_read),
+        _ => None
     }
-    
-    irp.IoStatus.Information = bytes_io;
-    let io_status = irp.IoStatus.__bindgen_anon_1.Status_mut();
-    *io_status = status;
+}
 
-    IoCompleteRequest(irp as PIRP, IO_PRIORITY::IO_NO_INCREMENT);
+unsafe fn mouse_playback(irp: &mut IRP, input_length: usize) -> NtResult<Option<usize>> {
+    start_playback(irp, input_length, DeviceRole::Mouse)
+}
 
-    status
+unsafe fn keyboard_playback(irp: &mut IRP, input_length: usize) -> NtResult<Option<usize>> {
+    start_playback(irp, input_length, DeviceRole::Keyboard)
+}
+
+// Validate sequence, pend the request and arm the playback timer.
+// Events of another device are refused, the device ACL decides who may play them.
+unsafe fn start_playback(irp: &mut IRP, input_length: usize, role: DeviceRole) -> NtResult<Option<usize>> {
+    let header_size = core::mem::size_of::<PlaybackHeader>();
+    if input_length < header_size {
+        return Err(NtError::from_reason(NtReason::InputBufferTooSmall));
+    }
+    if output_buffer_length(irp) < core::mem::size_of::<PlaybackReply>() {
+        return Err(NtError::from_reason(NtReason::OutputBufferTooSmall));
+    }
+    if !hrtimer::available() {
+        return Err(NtError::from_reason(NtReason::TimerCreateFailed));
+    }
+    let buffer = *irp.AssociatedIrp.SystemBuffer() as *const u8;
+    let header: PlaybackHeader = *(buffer as *const PlaybackHeader);
+    let count = header.count as usize;
+    if input_length < header_size + count * core::mem::size_of::<PlaybackEvent>() {
+        return Err(NtError::from_reason(NtReason::InputBufferTooSmall));
+    }
+    let events = core::slice::from_raw_parts(buffer.add(header_size) as *const PlaybackEvent, count);
+    // PLAYBACK_DEVICE_* values match DeviceRole
+    let valid = events.iter().all(|event| event.device == role as u32 && match role {
+        DeviceRole::Mouse => event.flags & !MOUSE_INPUT_FLAGS == 0 && event.code & !MOUSE_INPUT_BUTTON_FLAGS == 0,
+        _ => event.flags & !(KEY_BREAK | KEY_E0 | KEY_E1) == 0
+    });
+    if !valid {
+        return Err(NtError::from_reason(NtReason::InvalidRequest));
+    }
+    check_armed()?;
+    match role {
+        DeviceRole::Mouse => ensure_mouse_discovered()?,
+        _ => ensure_keyboard_discovered()?
+    }
+
+    let parked = {
+        let mut playback = PLAYBACK.lock();
+        if playback.is_active() || !PLAYBACK_IRP.peek().is_null() {
+            return Err(NtError::from_reason(NtReason::PlaybackBusy));
+        }
+        if let Err(error) = playback.start(&header, events, request_session(irp), clock::now_us()) {
+            log_warn!(Dispatch, "Playback refused: {}.", error.description());
+            return Err(NtError::from_reason(NtReason::InvalidRequest));
+        }
+        let parked = PLAYBACK_IRP.park(irp as PIRP, playback_cancel);
+        if parked != Parked::Pending {
+            playback.stop();
+        }
+        parked
+    };
+    match parked {
+        Parked::Pending => {
+            log_info!(Dispatch, "Playback of {} events started.", count);
+            hrtimer::schedule(PLAYBACK.lock().deadline_us());
+        }
+        Parked::Cancelled => {
+            complete_playback(irp as PIRP, Err(NtError::from_reason(NtReason::Cancelled)), 0);
+        }
+        Parked::Busy => return Err(NtError::from_reason(NtReason::PlaybackBusy))
+    }
+    Ok(None)
+}
+
+// Play one scheduled event. Kill switch, lease and rate limits are checked per
+// event, over the limit the sequence fails instead of waiting at DISPATCH_LEVEL.
+unsafe fn play_event(session_id: Option<SessionId>, event: &PlaybackEvent) -> NtResult<()> {
+    let (role, device) = match event.device {
+        PLAYBACK_DEVICE_MOUSE => (DeviceRole::Mouse, Device::Mouse),
+        PLAYBACK_DEVICE_KEYBOARD => (DeviceRole::Keyboard, Device::Keyboard),
+        _ => return Err(NtError::from_reason(NtReason::InvalidRequest))
+    };
+    check_armed()?;
+    if !GLOBAL_STATE.lock().leases.allows(role, session_id, clock::now_ms()) {
+        return Err(NtError::from_reason(NtReason::LeaseBusy));
+    }
+    if take_tokens(session_id, device, 1).0 != RateDecision::Allow {
+        return Err(NtError::from_reason(NtReason::RateLimited));
+    }
+    match device {
+        Device::Mouse => {
+            inject_mouse(session_id, event.x, event.y, event.code, event.flags, None)?;
+            record_input(session_id, InputEvent::Buttons(event.code), None);
+        }
+        Device::Keyboard => {
+            inject_key(session_id, event.code, event.flags, None)?;
+            record_input(session_id, InputEvent::Key {
+                make_code: event.code,
+                flags: event.flags
+            }, None);
+        }
+    }
+    Ok(())
+}
+
+// Complete PLAYBACK request taken out of PLAYBACK_IRP
+unsafe fn complete_playback(irp: PIRP, result: NtResult<()>, delivered: u32) {
+    let irp = &mut *irp;
+    match result {
+        Ok(()) => {
+            log_info!(Dispatch, "Playback finished, {} events played.", delivered);
+            *(*irp.AssociatedIrp.SystemBuffer() as *mut PlaybackReply) = PlaybackReply { delivered };
+            complete_request(irp, Ok(core::mem::size_of::<PlaybackReply>()));
+        }
+        Err(error) => {
+            log_warn!(Dispatch, "Playback stopped after {} events: {}", delivered, error);
+            etw::write(tracelog::rejected(META_IRP_PLAYBACK, error.status, error.reason as u32));
+            stats::rejected(error.reason);
+            // Error status copies nothing back, Information carries the count
+            complete_with_information(irp, error.status, delivered as usize);
+        }
+    }
+}
+
+// Switch dry run of the session the request was sent on
+unsafe fn set_dry_run(irp: &mut IRP, input_length: usize) -> NtResult<usize> {
+    if input_length < core::mem::size_of::<DryRunRequest>() {
+        return Err(NtError::from_reason(NtReason::InputBufferTooSmall));
+    }
+    let dry_run_request: &DryRunRequest = &(*(*irp.AssociatedIrp.SystemBuffer() as PDryRunRequest));
+    if dry_run_request.enabled > 1 {
+        return Err(NtError::from_reason(NtReason::InvalidRequest));
+    }
+    let session_id = request_session(irp).ok_or(NtError::from_reason(NtReason::InvalidRequest))?;
+    match GLOBAL_STATE.lock().sessions.get(session_id) {
+        Some(session) => session.dry_run = dry_run_request.enabled != 0,
+        None => return Err(NtError::from_reason(NtReason::InvalidRequest))
+    }
+    log_info!(Dispatch, "Dry run of session {} {}.", session_id.value(),
+        if dry_run_request.enabled != 0 { "enabled" } else { "disabled" });
+    Ok(0)
+}
+
+// With an AuthKey configured, valid or not, input must end with an AuthTrailer whose MAC
+// matches and whose sequence number is above the last one of the session.
+// Returns length of the request body, input without the trailer.
+unsafe fn authenticate(irp: &mut IRP, io_control_code: ULONG) -> NtResult<usize> {
+    let io_stack_location_ptr: PIO_STACK_LOCATION = IoGetCurrentIrpStackLocation(irp as PIRP);
+    let input_buffer_length = (*io_stack_location_ptr).Parameters.DeviceIoControl().InputBufferLength as usize;
+    let key = GLOBAL_STATE.lock().config.auth_key;
+    if !key.is_required() {
+        return Ok(input_buffer_length);
+    }
+    let session_id = match request_session(irp) {
+        Some(session_id) => session_id,
+        None => return Err(NtError::from_reason(NtReason::AuthFailed))
+    };
+    let nonce = match GLOBAL_STATE.lock().sessions.get(session_id) {
+        Some(session) => session.nonce,
+        None => return Err(NtError::from_reason(NtReason::AuthFailed))
+    };
+
+    // MAC is computed outside the lock, only the sequence check needs it
+    let input: &[u8] = if input_buffer_length == 0 {
+        &[]
+    } else {
+        core::slice::from_raw_parts(*irp.AssociatedIrp.SystemBuffer() as *const u8, input_buffer_length)
+    };
+    let result = key.verify(nonce, io_control_code, input).and_then(|sequence| {
+        match GLOBAL_STATE.lock().sessions.get(session_id) {
+            Some(session) => session.replay.accept(sequence),
+            None => Err(AuthFailure::Replayed)
+        }
+    });
+    match result {
+        Ok(()) => Ok(input_buffer_length - core::mem::size_of::<AuthTrailer>()),
+        Err(failure) => {
+            log_warn!(Dispatch, "IRP_MJ_DEVICE_CONTROL>>Request 0x{:08X} failed authentication: {}.", io_control_code, failure.description());
+            Err(NtError::from_reason(NtReason::AuthFailed))
+        }
+    }
+}
+
+// Output buffer length of device control request. Input length comes from
+// authenticate, which leaves the AuthTrailer out.
+unsafe fn output_buffer_length(irp: &mut IRP) -> usize {
+    let io_stack_location_ptr: PIO_STACK_LOCATION = IoGetCurrentIrpStackLocation(irp as PIRP);
+    (*io_stack_location_ptr).Parameters.DeviceIoControl().OutputBufferLength as usize
+}
+
+// Mouse device requests
+unsafe fn mouse_control(irp: &mut IRP, io_control_code: ULONG, input_length: usize) -> NtResult<usize> {
+    log_trace!(Dispatch, "IRP_MJ_DEVICE_CONTROL>>Mouse IO control code: 0x{:08X}.", io_control_code);
+    if io_control_code == META_IRP_MOUSE_LEASE {
+        return lease_control(irp, DeviceRole::Mouse, input_length);
+    }
+
+    // Malformed requests are refused before they touch the lease or the rate budget
+    match io_control_code {
+        META_IRP_MOUSE_EVENT => {
+            if input_length < MOUSE_REQUEST_MIN_SIZE {
+                return Err(NtError::from_reason(NtReason::InputBufferTooSmall));
+            }
+            let mouse_request: &MouseRequest = &(*(*irp.AssociatedIrp.SystemBuffer() as PMouseRequest));
+            let policy = if input_length < core::mem::size_of::<MouseRequest>() {
+                None
+            } else {
+                request_policy(mouse_request.unconsumed_policy)?
+            };
+            let session_id = admit_input(irp, DeviceRole::Mouse, Device::Mouse)?;
+            ensure_mouse_discovered()?;
+            let mut flags: USHORT = 0;
+            flags |= MOUSE_MOVE_ABSOLUTE;
+            inject_mouse(session_id, mouse_request.x as _, mouse_request.y as _, 0x0000, flags, policy)?;
+            Ok(core::mem::size_of::<MouseRequest>())
+        }
+        META_IRP_MOUSE_INPUT => {
+            if input_length < MOUSE_INPUT_REQUEST_MIN_SIZE {
+                return Err(NtError::from_reason(NtReason::InputBufferTooSmall));
+            }
+            let mouse_request: &MouseInputRequest = &(*(*irp.AssociatedIrp.SystemBuffer() as PMouseInputRequest));
+            if mouse_request.flags & !MOUSE_INPUT_FLAGS != 0 || mouse_request.button_flags & !MOUSE_INPUT_BUTTON_FLAGS != 0 {
+                return Err(NtError::from_reason(NtReason::InvalidRequest));
+            }
+            let policy = if input_length < core::mem::size_of::<MouseInputRequest>() {
+                None
+            } else {
+                request_policy(mouse_request.unconsumed_policy)?
+            };
+            let session_id = admit_input(irp, DeviceRole::Mouse, Device::Mouse)?;
+            ensure_mouse_discovered()?;
+            inject_mouse(session_id, mouse_request.x, mouse_request.y, mouse_request.button_flags, mouse_request.flags, policy)?;
+            record_input(session_id, InputEvent::Buttons(mouse_request.button_flags), None);
+            Ok(core::mem::size_of::<MouseInputRequest>())
+        }
+        _ => Err(NtError::from_reason(NtReason::UnsupportedIoctl))
+    }
+}
+
+// Keyboard device requests
+unsafe fn keyboard_control(irp: &mut IRP, io_control_code: ULONG, input_length: usize) -> NtResult<usize> {
+    log_trace!(Dispatch, "IRP_MJ_DEVICE_CONTROL>>Keyboard IO control code: 0x{:08X}.", io_control_code);
+    if io_control_code == META_IRP_KEYBOARD_LEASE {
+        return lease_control(irp, DeviceRole::Keyboard, input_length);
+    }
+
+    // Malformed requests are refused before they touch the lease or the rate budget
+    match io_control_code {
+        META_IRP_KEYBOARD_EVENT => {
+            if input_length < KEYBOARD_REQUEST_MIN_SIZE {
+                return Err(NtError::from_reason(NtReason::InputBufferTooSmall));
+            }
+            let keyboard_request: &KeyboardRequest = &(*(*irp.AssociatedIrp.SystemBuffer() as PKeyboardRequest));
+            if keyboard_request.flags & !(KEY_BREAK | KEY_E0 | KEY_E1) != 0 {
+                return Err(NtError::from_reason(NtReason::InvalidRequest));
+            }
+            let policy = if input_length < core::mem::size_of::<KeyboardRequest>() {
+                None
+            } else {
+                request_policy(keyboard_request.unconsumed_policy)?
+            };
+            let session_id = admit_input(irp, DeviceRole::Keyboard, Device::Keyboard)?;
+            ensure_keyboard_discovered()?;
+            inject_key(session_id, keyboard_request.make_code, keyboard_request.flags, policy)?;
+            record_input(session_id, InputEvent::Key {
+                make_code: keyboard_request.make_code,
+                flags: keyboard_request.flags
+            }, None);
+            Ok(core::mem::size_of::<KeyboardRequest>())
+        }
+        _ => Err(NtError::from_reason(NtReason::UnsupportedIoctl))
+    }
+}
+
+// Control device requests
+unsafe fn control_control(irp: &mut IRP, io_control_code: ULONG, input_length: usize) -> NtResult<usize> {
+    let output_buffer_length = output_buffer_length(irp);
+    log_trace!(Dispatch, "IRP_MJ_DEVICE_CONTROL>>Control IO control code: 0x{:08X}.", io_control_code);
+
+    match io_control_code {
+        META_IRP_AUDIT_SNAPSHOT => {
+            let header_size = core::mem::size_of::<AuditSnapshotHeader>();
+            if output_buffer_length < header_size {
+                return Err(NtError::from_reason(NtReason::OutputBufferTooSmall));
+            }
+            let system_buffer = *irp.AssociatedIrp.SystemBuffer() as *mut u8;
+            let records = core::slice::from_raw_parts_mut(system_buffer.add(header_size) as *mut AuditRecord,
+                (output_buffer_length - header_size) / core::mem::size_of::<AuditRecord>());
+            let ring = AUDIT_RING.lock();
+            let record_count = ring.snapshot(records);
+            *(system_buffer as *mut AuditSnapshotHeader) = AuditSnapshotHeader {
+                total_count: ring.total(),
+                record_count: record_count as u32,
+                capacity: ring.capacity() as u32
+            };
+            Ok(header_size + record_count * core::mem::size_of::<AuditRecord>())
+        }
+        META_IRP_STATS => {
+            if output_buffer_length < core::mem::size_of::<Stats>() {
+                return Err(NtError::from_reason(NtReason::OutputBufferTooSmall));
+            }
+            let reset = input_length >= core::mem::size_of::<StatsRequest>()
+                && (*(*irp.AssociatedIrp.SystemBuffer() as PStatsRequest)).reset != 0;
+            *(*irp.AssociatedIrp.SystemBuffer() as *mut Stats) = stats::snapshot(reset, clock::now_ms());
+            if reset {
+                log_info!(Dispatch, "Statistics reset.");
+            }
+            Ok(core::mem::size_of::<Stats>())
+        }
+        META_IRP_SELF_TEST => {
+            if output_buffer_length < core::mem::size_of::<SelfTestReport>() {
+                return Err(NtError::from_reason(NtReason::OutputBufferTooSmall));
+            }
+            check_armed()?;
+            *(*irp.AssociatedIrp.SystemBuffer() as *mut SelfTestReport) = self_test();
+            Ok(core::mem::size_of::<SelfTestReport>())
+        }
+        META_IRP_AUDIT_CLEAR => {
+            AUDIT_RING.lock().clear();
+            log_info!(Dispatch, "Audit ring cleared.");
+            Ok(0)
+        }
+        META_IRP_SET_ARMED => {
+            if input_length < core::mem::size_of::<ArmRequest>() {
+                return Err(NtError::from_reason(NtReason::InputBufferTooSmall));
+            }
+            let arm_request: &ArmRequest = &(*(*irp.AssociatedIrp.SystemBuffer() as PArmRequest));
+            match arm_request.armed {
+                0 => disarm(),
+                1 => arm(),
+                _ => return Err(NtError::from_reason(NtReason::InvalidRequest))
+            }
+            // Reply is optional, a refresh needs no output buffer
+            if output_buffer_length < core::mem::size_of::<ArmReply>() {
+                return Ok(0);
+            }
+            let reply = {
+                let state = GLOBAL_STATE.lock();
+                ArmReply {
+                    armed: state.arm.is_armed() as u32,
+                    remaining_ms: state.arm.remaining_ms(clock::now_ms()).min(u32::MAX as u64) as u32
+                }
+            };
+            *(*irp.AssociatedIrp.SystemBuffer() as *mut ArmReply) = reply;
+            Ok(core::mem::size_of::<ArmReply>())
+        }
+        META_IRP_RELOAD_CONFIG => {
+            if output_buffer_length < core::mem::size_of::<ConfigReply>() {
+                return Err(NtError::from_reason(NtReason::OutputBufferTooSmall));
+            }
+            reload_config(&mut *(*irp.AssociatedIrp.SystemBuffer() as *mut ConfigReply))?;
+            Ok(core::mem::size_of::<ConfigReply>())
+        }
+        _ => Err(NtError::from_reason(NtReason::UnsupportedIoctl))
+    }
+}
+
+// File object of request
+unsafe fn request_file_object(irp: &mut IRP) -> PFILE_OBJECT {
+    let io_stack_location_ptr: PIO_STACK_LOCATION = IoGetCurrentIrpStackLocation(irp as PIRP);
+    (*io_stack_location_ptr).FileObject as PFILE_OBJECT
+}
+
+// Session of the handle the request was sent on
+unsafe fn request_session(irp: &mut IRP) -> Option<SessionId> {
+    let file_object = request_file_object(irp);
+    if file_object.is_null() {
+        return None;
+    }
+    SessionId::from_context((*file_object).FsContext as usize)
+}
+
+// Gate well-formed input requests: armed, leased and within the rate limits
+unsafe fn admit_input(irp: &mut IRP, role: DeviceRole, device: Device) -> NtResult<Option<SessionId>> {
+    check_armed()?;
+    check_lease(irp, role)?;
+    rate_limit(irp, device, 1)?;
+    Ok(request_session(irp))
+}
+
+// Press button and pend the request until the driver releases it
+unsafe fn mouse_hold(irp: &mut IRP, input_length: usize) -> NtResult<Option<usize>> {
+    if input_length < core::mem::size_of::<MouseHoldRequest>() {
+        return Err(NtError::from_reason(NtReason::InputBufferTooSmall));
+    }
+    let hold_request: &MouseHoldRequest = &(*(*irp.AssociatedIrp.SystemBuffer() as PMouseHoldRequest));
+    if hold_request.button < 1 || hold_request.button > 5 || hold_request.reserved != 0 {
+        return Err(NtError::from_reason(NtReason::InvalidRequest));
+    }
+    let (button, duration_ms) = (hold_request.button - 1, hold_request.duration_ms);
+    check_hold_duration(duration_ms)?;
+    let session_id = admit_input(irp, DeviceRole::Mouse, Device::Mouse)?;
+    check_hold(irp)?;
+    ensure_mouse_discovered()?;
+    let button_flags = MOUSE_LEFT_BUTTON_DOWN << (button * 2);
+    inject_mouse(session_id, 0, 0, button_flags, MOUSE_MOVE_RELATIVE, None)?;
+    record_input(session_id, InputEvent::Buttons(button_flags), Some(duration_ms));
+    park_hold(irp, session_id, HoldInput::Button(button))
+}
+
+// Press key and pend the request until the driver releases it
+unsafe fn keyboard_hold(irp: &mut IRP, input_length: usize) -> NtResult<Option<usize>> {
+    if input_length < core::mem::size_of::<KeyboardHoldRequest>() {
+        return Err(NtError::from_reason(NtReason::InputBufferTooSmall));
+    }
+    let hold_request: &KeyboardHoldRequest = &(*(*irp.AssociatedIrp.SystemBuffer() as PKeyboardHoldRequest));
+    // Press only, E1 sequences have no release
+    if hold_request.flags & !KEY_E0 != 0 || hold_request.make_code > 0xFF {
+        return Err(NtError::from_reason(NtReason::InvalidRequest));
+    }
+    let (make_code, flags, duration_ms) = (hold_request.make_code, hold_request.flags, hold_request.duration_ms);
+    check_hold_duration(duration_ms)?;
+    let session_id = admit_input(irp, DeviceRole::Keyboard, Device::Keyboard)?;
+    check_hold(irp)?;
+    ensure_keyboard_discovered()?;
+    inject_key(session_id, make_code, flags, None)?;
+    record_input(session_id, InputEvent::Key { make_code, flags }, Some(duration_ms));
+    park_hold(irp, session_id, HoldInput::Key { make_code, flags })
+}
+
+// Pend hold request until its hold ends, the press is already injected.
+// Completes at once if there is nothing to wait for (dry run, released meanwhile).
+unsafe fn park_hold(irp: &mut IRP, session_id: Option<SessionId>, input: HoldInput) -> NtResult<Option<usize>> {
+    let session_id = match session_id {
+        Some(session_id) => session_id,
+        None => return Ok(Some(0))
+    };
+    let (parked, released, superseded) = {
+        let mut guard = GLOBAL_STATE.lock();
+        let state = &mut *guard;
+        let index = match state.holds.slot_of(session_id, input) {
+            Some(index) => index,
+            None => return Ok(Some(0))
+        };
+        // Input held again, the earlier request ends here
+        let superseded = match state.hold_waits[index].take() {
+            Some(_) => HOLD_IRPS[index].claim(),
+            None => None
+        };
+        let parked = HOLD_IRPS[index].park(irp as PIRP, hold_cancel);
+        let released = match parked {
+            Parked::Pending => {
+                state.hold_waits[index] = Some((session_id, input));
+                false
+            }
+            Parked::Cancelled => drop_hold(state, session_id, input),
+            Parked::Busy => false
+        };
+        (parked, released, superseded)
+    };
+    if let Some(superseded) = superseded {
+        complete_request(&mut *superseded, Ok(0));
+    }
+    if released {
+        release_hold_input(input);
+    }
+    match parked {
+        Parked::Pending => Ok(None),
+        Parked::Cancelled => {
+            complete_request(irp, Err(NtError::from_reason(NtReason::Cancelled)));
+            Ok(None)
+        }
+        // Cancel routine of the earlier request still runs, the watchdog releases this one
+        Parked::Busy => Ok(Some(0))
+    }
+}
+
+// Validate timed hold duration against the configured maximum
+fn check_hold_duration(duration_ms: u32) -> NtResult<()> {
+    let max_hold_ms = match GLOBAL_STATE.lock().config.max_hold_ms {
+        0 => config::MAX_HOLD_MS_LIMIT,
+        max_hold_ms => max_hold_ms
+    };
+    if duration_ms == 0 || duration_ms > max_hold_ms {
+        return Err(NtError::from_reason(NtReason::InvalidRequest));
+    }
+    Ok(())
+}
+
+// Check the timed hold can be tracked before the press is injected
+unsafe fn check_hold(irp: &mut IRP) -> NtResult<()> {
+    if request_session(irp).is_none() {
+        return Err(NtError::from_reason(NtReason::InvalidRequest));
+    }
+    let state = GLOBAL_STATE.lock();
+    if !state.holds.has_room() {
+        return Err(NtError::from_reason(NtReason::TooManyHolds));
+    }
+    Ok(())
+}
+
+// Record injected input in its session and arm its release:
+// after hold_ms for timed holds, otherwise after the configured max hold time.
+// Input which arrives after cleanup (another thread raced CloseHandle) is released at once.
+unsafe fn record_input(session_id: Option<SessionId>, event: InputEvent, hold_ms: Option<u32>) {
+    let session_id = match session_id {
+        Some(session_id) => session_id,
+        None => return
+    };
+    let now_ms = clock::now_ms();
+    let (late, ended) = {
+        let mut guard = GLOBAL_STATE.lock();
+        let state = &mut *guard;
+        let hold_ms = match (hold_ms, state.config.max_hold_ms) {
+            (Some(hold_ms), _) => Some(hold_ms),
+            (None, 0) => None,
+            (None, max_hold_ms) => Some(max_hold_ms)
+        };
+        // Disarm raced the injection, its releases are already done
+        let armed = state.arm.is_armed();
+        let dry_run = state.config.dry_run;
+        let late = match state.sessions.get(session_id) {
+            // Nothing was pressed, there is nothing to release
+            Some(session) if session.dry_run || dry_run => None,
+            Some(session) if !session.cleaned_up && armed => {
+                session.held.record(event);
+                let holds = &mut state.holds;
+                HoldInput::for_each_in(event, |input, pressed| {
+                    if !pressed {
+                        holds.disarm(session_id, input);
+                    } else if let Some(hold_ms) = hold_ms {
+                        if !holds.arm(session_id, input, now_ms + hold_ms as u64) {
+                            log_warn!(Dispatch, "Hold table full, {:?} is not released by watchdog.", input);
+                        }
+                    }
+                });
+                schedule_watchdog(state);
+                None
+            }
+            Some(_) => {
+                let mut held = HeldInput::new();
+                held.record(event);
+                Some(held)
+            }
+            None => None
+        };
+        // Holds released by this input end their requests
+        (late, take_ended_holds(state))
+    };
+    if let Some(held) = late {
+        release_held(&held);
+    }
+    complete_holds(&ended, Ok(0));
+}
+
+// Arm timer to the earliest hold release or auto-disarm deadline
+fn schedule_watchdog(state: &DriverState) {
+    let deadline_ms = match (state.holds.next_deadline(), state.arm.deadline()) {
+        (Some(hold_ms), Some(disarm_ms)) => Some(hold_ms.min(disarm_ms)),
+        (hold_ms, disarm_ms) => hold_ms.or(disarm_ms)
+    };
+    watchdog::schedule(deadline_ms);
+}
+
+// Injection requests fail while disarmed
+fn check_armed() -> NtResult<()> {
+    if GLOBAL_STATE.lock().arm.is_armed() {
+        Ok(())
+    } else {
+        Err(NtError::from_reason(NtReason::Disarmed))
+    }
+}
+
+unsafe fn mouse_loopback_read(irp: &mut IRP, _input_length: usize) -> NtResult<Option<usize>> {
+    loopback_read(irp, &LOOPBACK_MOUSE, &LOOPBACK_MOUSE_READ)
+}
+
+unsafe fn keyboard_loopback_read(irp: &mut IRP, _input_length: usize) -> NtResult<Option<usize>> {
+    loopback_read(irp, &LOOPBACK_KEYBOARD, &LOOPBACK_KEYBOARD_READ)
+}
+
+// Drain loopback queue into output buffer, as many whole MOUSE_INPUT_DATA or
+// KEYBOARD_INPUT_DATA records as fit, oldest first. On an empty queue the
+// request waits for the next event, one waiting read per device.
+// The queue is not filtered by session: any handle on the device reads the
+// events injected by every client, so only one client should read it.
+unsafe fn loopback_read<T: Copy>(irp: &mut IRP, queue: &SpinLock<LoopbackQueue<T>>, slot: &PendingIrp) -> NtResult<Option<usize>> {
+    if output_buffer_length(irp) < core::mem::size_of::<T>() {
+        return Err(NtError::from_reason(NtReason::OutputBufferTooSmall));
+    }
+    let parked = {
+        let mut queue = queue.lock();
+        if !queue.is_empty() {
+            return Ok(Some(take_loopback(irp, &mut *queue)));
+        }
+        slot.park(irp as PIRP, loopback_read_cancel)
+    };
+    match parked {
+        Parked::Pending => Ok(None),
+        Parked::Cancelled => {
+            complete_request(irp, Err(NtError::from_reason(NtReason::Cancelled)));
+            Ok(None)
+        }
+        Parked::Busy => Err(NtError::from_reason(NtReason::ReadBusy))
+    }
+}
+
+// Move queued events into output buffer of read request, returns its length
+unsafe fn take_loopback<T: Copy>(irp: &mut IRP, queue: &mut LoopbackQueue<T>) -> usize {
+    let capacity = output_buffer_length(irp) / core::mem::size_of::<T>();
+    let out = core::slice::from_raw_parts_mut(*irp.AssociatedIrp.SystemBuffer() as *mut T, capacity);
+    let taken = queue.take(out);
+    log_trace!(Dispatch, "Loopback read returned {} events.", taken);
+    taken * core::mem::size_of::<T>()
+}
+
+// Complete waiting loopback read after events were queued
+unsafe fn complete_loopback_read<T: Copy>(queue: &SpinLock<LoopbackQueue<T>>, slot: &PendingIrp) {
+    let completed = {
+        let mut queue = queue.lock();
+        if queue.is_empty() {
+            return;
+        }
+        match slot.claim() {
+            Some(irp) => (irp, take_loopback(&mut *irp, &mut *queue)),
+            None => return
+        }
+    };
+    complete_request(&mut *completed.0, Ok(completed.1));
+}
+
+// Acquire, renew or release lease of mouse or keyboard device
+unsafe fn lease_control(irp: &mut IRP, role: DeviceRole, input_length: usize) -> NtResult<usize> {
+    if input_length < core::mem::size_of::<LeaseRequest>() {
+        return Err(NtError::from_reason(NtReason::InputBufferTooSmall));
+    }
+    let lease_request: &LeaseRequest = &(*(*irp.AssociatedIrp.SystemBuffer() as PLeaseRequest));
+    if lease_request.duration_ms > config::LEASE_MS_LIMIT {
+        return Err(NtError::from_reason(NtReason::InvalidRequest));
+    }
+    let session_id = match request_session(irp) {
+        Some(session_id) => session_id,
+        None => return Err(NtError::from_reason(NtReason::InvalidRequest))
+    };
+
+    let mut state = GLOBAL_STATE.lock();
+    if lease_request.duration_ms == 0 {
+        if state.leases.release(role, session_id) {
+            log_info!(Dispatch, "{} lease released.", role.name());
+        }
+        return Ok(0);
+    }
+    let policy = state.config.arbitration_policy;
+    match state.leases.acquire(role, session_id, lease_request.priority, lease_request.duration_ms, clock::now_ms(), policy) {
+        Ok(()) => {
+            log_debug!(Dispatch, "{} lease granted for {} ms, priority {}.", role.name(), lease_request.duration_ms, lease_request.priority);
+            Ok(0)
+        }
+        Err(holder) => {
+            log_debug!(Dispatch, "{} lease busy, holder priority {}.", role.name(), holder.priority);
+            Err(NtError::from_reason(NtReason::LeaseBusy))
+        }
+    }
+}
+
+// Injection on a leased device is refused for other sessions. They wait up to
+// the configured lease wait time for the lease to end.
+unsafe fn check_lease(irp: &mut IRP, role: DeviceRole) -> NtResult<()> {
+    let session_id = request_session(irp);
+    let mut waited_ms = 0;
+    loop {
+        let (allowed, lease_wait_ms) = {
+            let mut state = GLOBAL_STATE.lock();
+            (state.leases.allows(role, session_id, clock::now_ms()), state.config.lease_wait_ms as u64)
+        };
+        if allowed {
+            return Ok(());
+        }
+        if waited_ms >= lease_wait_ms {
+            return Err(NtError::from_reason(NtReason::LeaseBusy));
+        }
+        clock::sleep_ms(LEASE_POLL_MS);
+        waited_ms += LEASE_POLL_MS;
+    }
+}
+
+// Take session and driver-wide tokens for count events. Over the limit the
+// request waits (Delay policy, up to MAX_RATE_DELAY_MS) or fails.
+unsafe fn rate_limit(irp: &mut IRP, device: Device, count: u32) -> NtResult<()> {
+    let session_id = request_session(irp);
+    let mut delayed_ms = 0;
+    loop {
+        let (decision, policy) = take_tokens(session_id, device, count);
+        match decision {
+            RateDecision::Allow => return Ok(()),
+            RateDecision::Wait(wait_ms) if policy == RatePolicy::Delay && delayed_ms + wait_ms <= MAX_RATE_DELAY_MS => {
+                clock::sleep_ms(wait_ms);
+                delayed_ms += wait_ms;
+            }
+            _ => {
+                log_debug!(Dispatch, "{} request over rate limit.", device.name());
+                return Err(NtError::from_reason(NtReason::RateLimited));
+            }
+        }
+    }
+}
+
+// Take session and driver-wide tokens, also returns the configured policy
+fn take_tokens(session_id: Option<SessionId>, device: Device, count: u32) -> (RateDecision, RatePolicy) {
+    let mut guard = GLOBAL_STATE.lock();
+    let state = &mut *guard;
+    let session = session_id.and_then(|session_id| state.sessions.get(session_id));
+    let decision = match device {
+        Device::Mouse => ratelimit::take_both(session.map(|session| &mut session.mouse_bucket),
+            &mut state.mouse_bucket, state.config.mouse_rate, clock::now_ms(), count),
+        Device::Keyboard => ratelimit::take_both(session.map(|session| &mut session.keyboard_bucket),
+            &mut state.keyboard_bucket, state.config.keyboard_rate, clock::now_ms(), count)
+    };
+    (decision, state.config.rate_policy)
+}
+
+// Arm or refresh the kill switch
+unsafe fn arm() {
+    let mut guard = GLOBAL_STATE.lock();
+    let state = &mut *guard;
+    let was_armed = state.arm.is_armed();
+    state.arm.arm(clock::now_ms(), state.config.auto_disarm_ms);
+    schedule_watchdog(state);
+    if !was_armed {
+        log_info!(Dispatch, "Injection armed, auto-disarm after {} ms (0 is never).", state.config.auto_disarm_ms);
+    }
+}
+
+// Disarm the kill switch and release held input of every session
+unsafe fn disarm() {
+    let (held, ended) = {
+        let mut guard = GLOBAL_STATE.lock();
+        let state = &mut *guard;
+        if !state.arm.disarm() {
+            return;
+        }
+        state.holds.clear();
+        schedule_watchdog(state);
+        (state.sessions.take_all_held(), take_ended_holds(state))
+    };
+    log_warn!(Dispatch, "Injection disarmed.");
+    release_held(&held);
+    complete_holds(&ended, Err(NtError::from_reason(NtReason::Disarmed)));
+}
+
+// ExtraInformation, process and dry-run flag of input injected for session,
+// None for input the driver produces itself. Driver input only releases
+// what was really pressed, so it is never a dry run.
+fn origin(session_id: Option<SessionId>) -> (ULONG, u32, bool) {
+    let mut guard = GLOBAL_STATE.lock();
+    let state = &mut *guard;
+    let tag = state.config.extra_information(session_id);
+    match session_id.and_then(|session_id| state.sessions.get(session_id)) {
+        Some(session) => (tag, session.process_id, state.config.dry_run || session.dry_run),
+        None => (tag, 0, false)
+    }
+}
+
+// Probe both class service callbacks without visible input. Bypasses sinks
+// and dry run, the point is to prove the real callbacks work.
+unsafe fn self_test() -> SelfTestReport {
+    let tag = GLOBAL_STATE.lock().config.extra_information(None);
+    let mouse = ProbeReport::evaluate(mouse_probe(GLOBAL_MOUSE_OBJ.load(Ordering::Acquire), tag), 1);
+    let keyboard = ProbeReport::evaluate(kbd_probe(GLOBAL_KBD_OBJ.load(Ordering::Acquire)), 0);
+    let report = SelfTestReport::new(mouse, keyboard);
+    if report.passed != 0 {
+        log_info!(Dispatch, "Self-test passed.");
+    } else {
+        log_warn!(Dispatch, "Self-test failed, mouse failures 0x{:X} keyboard failures 0x{:X}.",
+            report.mouse.failures, report.keyboard.failures);
+    }
+    report
+}
+
+// Run f with input sink of kind
+unsafe fn with_sink<R, F: FnOnce(&dyn InputSink) -> R>(kind: SinkKind, f: F) -> R {
+    match kind {
+        SinkKind::Class => f(&ClassSink {
+            mouse_object: GLOBAL_MOUSE_OBJ.load(Ordering::Acquire),
+            kbd_object: GLOBAL_KBD_OBJ.load(Ordering::Acquire)
+        }),
+        SinkKind::Loopback => f(&LoopbackSink {
+            mouse: &LOOPBACK_MOUSE,
+            keyboard: &LOOPBACK_KEYBOARD
+        }),
+        SinkKind::Null => f(&NullSink)
+    }
+}
+
+// Hand one event to the configured sink of device until it is consumed. Unconsumed
+// events are sent again with doubling delay (Retry policy, PASSIVE_LEVEL only)
+// or reported at once. The request policy, if any, overrides the configured one.
+// Returns events consumed by the last attempt.
+unsafe fn deliver<F: FnMut(&dyn InputSink) -> NtResult<ULONG>>(device: Device, policy: Option<UnconsumedPolicy>,
+    mut send: F) -> NtResult<ULONG> {
+    let (policy, retries, kind) = {
+        let state = GLOBAL_STATE.lock();
+        let kind = match device {
+            Device::Mouse => state.config.mouse_sink,
+            Device::Keyboard => state.config.keyboard_sink
+        };
+        (policy.unwrap_or(state.config.unconsumed_policy), state.config.unconsumed_retries, kind)
+    };
+    let result = with_sink(kind, |sink| {
+        let mut backoff_ms = UNCONSUMED_BACKOFF_MS;
+        let mut attempt = 0;
+        loop {
+            let consumed = send(sink)?;
+            if consumed != 0 {
+                return Ok(consumed);
+            }
+            if policy != UnconsumedPolicy::Retry || attempt == retries || KeGetCurrentIrql() != PASSIVE_LEVEL {
+                log_warn!(Dispatch, "{} event not consumed by {} sink after {} retries.",
+                    device.name(), kind.name(), attempt);
+                stats::undelivered(device);
+                return Ok(0);
+            }
+            stats::retried(device);
+            clock::sleep_ms(backoff_ms);
+            backoff_ms *= 2;
+            attempt += 1;
+        }
+    });
+    if kind == SinkKind::Loopback {
+        match device {
+            Device::Mouse => complete_loopback_read(&LOOPBACK_MOUSE, &LOOPBACK_MOUSE_READ),
+            Device::Keyboard => complete_loopback_read(&LOOPBACK_KEYBOARD, &LOOPBACK_KEYBOARD_READ)
+        }
+    }
+    result
+}
+
+// Policy field of an input request, None for the configured policy
+fn request_policy(value: u32) -> NtResult<Option<UnconsumedPolicy>> {
+    match value {
+        REQUEST_POLICY_DEFAULT => Ok(None),
+        REQUEST_POLICY_REPORT => Ok(Some(UnconsumedPolicy::Report)),
+        REQUEST_POLICY_RETRY => Ok(Some(UnconsumedPolicy::Retry)),
+        _ => Err(NtError::from_reason(NtReason::InvalidRequest))
+    }
+}
+
+// Failure of an event the class service callback did not consume
+fn check_consumed(consumed: ULONG) -> NtResult<()> {
+    if consumed == 0 {
+        return Err(NtError::from_reason(NtReason::InputNotConsumed));
+    }
+    Ok(())
+}
+
+// Inject mouse input through the configured sink and audit it
+unsafe fn inject_mouse(session_id: Option<SessionId>, x: i32, y: i32, button_flags: USHORT, flags: USHORT,
+    policy: Option<UnconsumedPolicy>) -> NtResult<()> {
+    let (tag, process_id, dry_run) = origin(session_id);
+    let consumed = if dry_run {
+        log_debug!(Dispatch, "Dry run, mouse x: {} y: {} flags: 0x{:04X} button_flags: 0x{:04X} not injected.", x, y, flags, button_flags);
+        stats::mouse_dry_run(flags & MOUSE_MOVE_ABSOLUTE != 0 || x != 0 || y != 0, button_flags != 0);
+        1
+    } else {
+        let data = mouse_input_data(x, y, button_flags, flags, tag);
+        deliver(Device::Mouse, policy, |sink| sink.mouse(&data))?
+    };
+    AUDIT_RING.lock().push(AuditRecord {
+        timestamp_ms: clock::now_ms(),
+        process_id,
+        session: session_id.map_or(0, SessionId::value),
+        device: audit::AUDIT_DEVICE_MOUSE,
+        consumed,
+        flags,
+        code: button_flags,
+        x,
+        y,
+        extra_information: tag,
+        dry_run: dry_run as u32,
+        ..AuditRecord::zeroed()
+    });
+    check_consumed(consumed)
+}
+
+// Inject keyboard input through the configured sink and audit it
+unsafe fn inject_key(session_id: Option<SessionId>, make_code: USHORT, flags: USHORT,
+    policy: Option<UnconsumedPolicy>) -> NtResult<()> {
+    let (tag, process_id, dry_run) = origin(session_id);
+    let consumed = if dry_run {
+        log_debug!(Dispatch, "Dry run, key make_code: 0x{:04X} flags: 0x{:04X} not injected.", make_code, flags);
+        stats::keyboard_dry_run(flags & KEY_BREAK != 0);
+        1
+    } else {
+        let data = kbd_input_data(make_code, flags, tag);
+        deliver(Device::Keyboard, policy, |sink| sink.keyboard(&data))?
+    };
+    AUDIT_RING.lock().push(AuditRecord {
+        timestamp_ms: clock::now_ms(),
+        process_id,
+        session: session_id.map_or(0, SessionId::value),
+        device: audit::AUDIT_DEVICE_KEYBOARD,
+        consumed,
+        flags,
+        code: make_code,
+        extra_information: tag,
+        dry_run: dry_run as u32,
+        ..AuditRecord::zeroed()
+    });
+    check_consumed(consumed)
+}
+
+// Inject input event the driver produces itself
+unsafe fn inject(event: InputEvent) -> NtResult<()> {
+    match event {
+        InputEvent::Key { make_code, flags } => inject_key(None, make_code, flags, None),
+        InputEvent::Buttons(button_flags) => inject_mouse(None, 0, 0, button_flags, MOUSE_MOVE_RELATIVE, None)
+    }
+}
+
+// Playback timer callback at DISPATCH_LEVEL, plays due events and completes
+// the request when the sequence ends or an event fails
+unsafe extern "system" fn playback_timer(_timer: PEX_TIMER, _context: PVOID) {
+    for _ in 0..PLAYBACK_BATCH {
+        let (event, session_id, generation) = {
+            let mut playback = PLAYBACK.lock();
+            match playback.take_due(clock::now_us()) {
+                Some(event) => (event, playback.session(), playback.generation()),
+                None => break
+            }
+        };
+        // Cancel may complete the request meanwhile, it reports what was counted so far
+        if let Err(error) = play_event(session_id, &event) {
+            let (irp, delivered) = {
+                let mut playback = PLAYBACK.lock();
+                match playback.fail(generation) {
+                    Some(delivered) => (PLAYBACK_IRP.claim(), delivered),
+                    None => return
+                }
+            };
+            if let Some(irp) = irp {
+                complete_playback(irp, Err(error), delivered);
+            }
+            return;
+        }
+        PLAYBACK.lock().played(generation);
+    }
+    let (irp, delivered, deadline_us) = {
+        let playback = PLAYBACK.lock();
+        if playback.is_active() {
+            (None, 0, playback.deadline_us())
+        } else {
+            (PLAYBACK_IRP.claim(), playback.delivered(), None)
+        }
+    };
+    if let Some(irp) = irp {
+        complete_playback(irp, Ok(()), delivered);
+    } else if deadline_us.is_some() {
+        hrtimer::schedule(deadline_us);
+    }
+}
+
+// Cancel routine of PLAYBACK (CancelIoEx, thread exit). Stops the sequence
+// and releases what the session holds.
+unsafe extern "system" fn playback_cancel(_device: PDEVICE_OBJECT, irp: PIRP) {
+    pending::release_cancel_lock(irp);
+    let (claimed, delivered, session_id) = {
+        let mut playback = PLAYBACK.lock();
+        let claimed = PLAYBACK_IRP.claim_cancelled(irp);
+        let delivered = if claimed { playback.stop() } else { 0 };
+        (claimed, delivered, playback.session())
+    };
+    if !claimed {
+        return;
+    }
+    hrtimer::schedule(None);
+    if let Some(session_id) = session_id {
+        release_session_input(session_id);
+    }
+    complete_playback(irp, Err(NtError::from_reason(NtReason::Cancelled)), delivered);
+}
+
+// Handle closed, stop its playback and complete the request as cancelled
+unsafe fn cancel_playback(session_id: SessionId) {
+    let (irp, delivered) = {
+        let mut playback = PLAYBACK.lock();
+        if playback.session() != Some(session_id) {
+            return;
+        }
+        match PLAYBACK_IRP.claim() {
+            Some(irp) => (irp, playback.stop()),
+            None => return
+        }
+    };
+    hrtimer::schedule(None);
+    complete_playback(irp, Err(NtError::from_reason(NtReason::Cancelled)), delivered);
+}
+
+// Handle closed, complete its waiting loopback read as cancelled
+unsafe fn cancel_loopback_read<T: Copy>(queue: &SpinLock<LoopbackQueue<T>>, slot: &PendingIrp, file_object: PFILE_OBJECT) {
+    let irp = {
+        let _queue = queue.lock();
+        let parked = slot.peek();
+        if parked.is_null() || request_file_object(&mut *parked) != file_object {
+            return;
+        }
+        slot.claim()
+    };
+    if let Some(irp) = irp {
+        complete_request(&mut *irp, Err(NtError::from_reason(NtReason::Cancelled)));
+    }
+}
+
+// Cancel routine of waiting loopback reads
+unsafe extern "system" fn loopback_read_cancel(device: PDEVICE_OBJECT, irp: PIRP) {
+    pending::release_cancel_lock(irp);
+    let claimed = match device::device_role(&*device) {
+        DeviceRole::Mouse => {
+            let _queue = LOOPBACK_MOUSE.lock();
+            LOOPBACK_MOUSE_READ.claim_cancelled(irp)
+        }
+        _ => {
+            let _queue = LOOPBACK_KEYBOARD.lock();
+            LOOPBACK_KEYBOARD_READ.claim_cancelled(irp)
+        }
+    };
+    if claimed {
+        complete_request(&mut *irp, Err(NtError::from_reason(NtReason::Cancelled)));
+    }
+}
+
+// Cancel routine of MOUSE_HOLD and KEYBOARD_HOLD (CancelIoEx, thread exit),
+// releases the held input at once
+unsafe extern "system" fn hold_cancel(_device: PDEVICE_OBJECT, irp: PIRP) {
+    pending::release_cancel_lock(irp);
+    let (claimed, released) = {
+        let mut guard = GLOBAL_STATE.lock();
+        let state = &mut *guard;
+        match HOLD_IRPS.iter().position(|slot| slot.claim_cancelled(irp)) {
+            Some(index) => match state.hold_waits[index].take() {
+                Some((session_id, input)) if drop_hold(state, session_id, input) => (true, Some(input)),
+                _ => (true, None)
+            },
+            None => (false, None)
+        }
+    };
+    if !claimed {
+        return;
+    }
+    if let Some(input) = released {
+        release_hold_input(input);
+    }
+    complete_request(&mut *irp, Err(NtError::from_reason(NtReason::Cancelled)));
+}
+
+// Drop armed hold of session input before its deadline, called under the
+// GLOBAL_STATE lock. True if it was armed, the caller injects the release.
+fn drop_hold(state: &mut DriverState, session_id: SessionId, input: HoldInput) -> bool {
+    if state.holds.slot_of(session_id, input).is_none() {
+        return false;
+    }
+    state.holds.disarm(session_id, input);
+    if let Some(session) = state.sessions.get(session_id) {
+        session.held.record(input.release_event());
+    }
+    schedule_watchdog(state);
+    true
+}
+
+// Inject release of a dropped hold
+unsafe fn release_hold_input(input: HoldInput) {
+    if let Err(error) = inject(input.release_event()) {
+        log_warn!(Dispatch, "Release of cancelled hold {:?} failed: {}", input, error);
+    }
+}
+
+// Take pended hold requests whose hold is no longer armed, called under the
+// GLOBAL_STATE lock after holds changed. Null where nothing ended.
+unsafe fn take_ended_holds(state: &mut DriverState) -> [PIRP; MAX_HOLDS] {
+    let mut ended = [core::ptr::null_mut(); MAX_HOLDS];
+    for (index, wait) in state.hold_waits.iter_mut().enumerate() {
+        if let Some((session_id, input)) = *wait {
+            if state.holds.slot_of(session_id, input) != Some(index) {
+                *wait = None;
+                // None while its cancel routine runs, which completes it
+                if let Some(irp) = HOLD_IRPS[index].claim() {
+                    ended[index] = irp;
+                }
+            }
+        }
+    }
+    ended
+}
+
+// Complete ended hold requests, after their input is released
+unsafe fn complete_holds(ended: &[PIRP; MAX_HOLDS], result: NtResult<usize>) {
+    for &irp in ended.iter().filter(|irp| !irp.is_null()) {
+        complete_request(&mut *irp, result);
+    }
+}
+
+// Release input held by session and drop its timed holds, the session stays open
+unsafe fn release_session_input(session_id: SessionId) {
+    let (held, ended) = {
+        let mut guard = GLOBAL_STATE.lock();
+        let state = &mut *guard;
+        state.holds.disarm_session(session_id);
+        schedule_watchdog(state);
+        let held = match state.sessions.get(session_id) {
+            Some(session) => {
+                let held = session.held;
+                session.held = HeldInput::new();
+                held
+            }
+            None => HeldInput::new()
+        };
+        (held, take_ended_holds(state))
+    };
+    if !held.is_empty() {
+        log_info!(Dispatch, "Releasing input held by cancelled request.");
+        release_held(&held);
+    }
+    complete_holds(&ended, Err(NtError::from_reason(NtReason::Cancelled)));
+}
+
+// Watchdog DPC, releases holds whose deadline passed
+unsafe extern "system" fn watchdog_dpc(_dpc: PKDPC, _context: PVOID, _argument1: PVOID, _argument2: PVOID) {
+    let now_ms = clock::now_ms();
+    let mut expired: [Option<Hold>; WATCHDOG_BATCH] = [None; WATCHDOG_BATCH];
+    let (count, disarmed, ended) = {
+        let mut guard = GLOBAL_STATE.lock();
+        let state = &mut *guard;
+        // No arm refresh in time, release everything
+        let disarmed = if state.arm.expire(now_ms) {
+            state.holds.clear();
+            Some(state.sessions.take_all_held())
+        } else {
+            None
+        };
+        let count = state.holds.take_expired(now_ms, &mut expired);
+        for hold in expired[..count].iter().flatten() {
+            if let Some(session) = state.sessions.get(hold.session) {
+                session.held.record(hold.input.release_event());
+            }
+        }
+        schedule_watchdog(state);
+        (count, disarmed, take_ended_holds(state))
+    };
+    if let Some(held) = disarmed {
+        log_warn!(Dispatch, "Auto-disarm timeout passed, injection disarmed.");
+        release_held(&held);
+        complete_holds(&ended, Err(NtError::from_reason(NtReason::Disarmed)));
+        return;
+    }
+    for hold in expired[..count].iter().flatten() {
+        log_info!(Dispatch, "Watchdog releases {:?}.", hold.input);
+        if let Err(error) = inject(hold.input.release_event()) {
+            log_warn!(Dispatch, "Watchdog release of {:?} failed: {}", hold.input, error);
+        }
+    }
+    complete_holds(&ended, Ok(0));
+}
+
+// Inject releases of held keys and buttons
+unsafe fn release_held(held: &HeldInput) {
+    for release in held.key_releases() {
+        if let Err(error) = inject_key(None, release.make_code, release.flags, None) {
+            log_warn!(Keyboard, "Release of key 0x{:02X} failed: {}", release.make_code, error);
+        }
+    }
+    let button_flags = held.button_release_flags();
+    if button_flags != 0 {
+        if let Err(error) = inject_mouse(None, 0, 0, button_flags, MOUSE_MOVE_RELATIVE, None) {
+            log_warn!(Mouse, "Release of buttons 0x{:04X} failed: {}", button_flags, error);
+        }
+    }
+}
+
+// Opening process must be on the image allowlist, if one is configured.
+// IRP_MJ_CREATE runs in the context of the opening process.
+unsafe fn check_caller() -> NtResult<()> {
+    if !GLOBAL_STATE.lock().allowed_images.is_restricted() {
+        return Ok(());
+    }
+    let process_id = PsGetCurrentProcessId() as usize;
+    let mut image_name: PUNICODE_STRING = core::ptr::null_mut();
+    let status = SeLocateProcessImageName(IoGetCurrentProcess(), &mut image_name);
+    if status < 0 {
+        log_warn!(Dispatch, "IRP_MJ_CREATE>>Image of process {} not found: {}", process_id, Status(status));
+        eventlog::open_rejected(process_id, &"?");
+        return Err(NtError::from_reason(NtReason::CallerNotAllowed));
+    }
+
+    let image_path = core::slice::from_raw_parts((*image_name).Buffer, (*image_name).Length as usize / 2);
+    let allowed = GLOBAL_STATE.lock().allowed_images.allows(image_path);
+    if !allowed {
+        log_warn!(Dispatch, "IRP_MJ_CREATE>>Process {} ({}) is not allowed.", process_id, WideStr(image_path));
+        eventlog::open_rejected(process_id, &WideStr(allowlist::image_file_name(image_path)));
+    }
+    ExFreePool(image_name as PVOID);
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(NtError::from_reason(NtReason::CallerNotAllowed))
+    }
 }
 
 // I/O Request Package Major function - create
 pub unsafe extern "system" fn irp_mj_create(device: &mut DEVICE_OBJECT, irp: &mut IRP) -> NTSTATUS {
-    DbgPrintEx(0, 0, "IRP_MJ_CREATE called %u.\0".as_ptr(), irp.Type as i32);
-    IoCompleteRequest(irp as PIRP, IO_PRIORITY::IO_NO_INCREMENT);
-    // Return NSTATUS
-    let status = *irp.IoStatus.__bindgen_anon_1.Status();
-    DbgPrintEx(0, 0, "IRP_MJ_CREATE>>IoCompleteRequest status: %u.\0".as_ptr(), status);
+    log_trace!(Dispatch, "IRP_MJ_CREATE called {}.", irp.Type);
+    if let Err(error) = check_caller() {
+        stats::rejected(error.reason);
+        return complete_request(irp, Err(error));
+    }
+    let nonce = NEXT_NONCE.fetch_add(1, Ordering::Relaxed);
+    let process_id = PsGetCurrentProcessId() as u32;
+    let session_id = GLOBAL_STATE.lock().sessions.open(nonce, process_id);
+    let session_id = match session_id {
+        Some(session_id) => session_id,
+        None => {
+            log_warn!(Dispatch, "IRP_MJ_CREATE>>All {} sessions in use.", session::MAX_SESSIONS);
+            stats::rejected(NtReason::TooManySessions);
+            return complete_request(irp, Err(NtError::from_reason(NtReason::TooManySessions)));
+        }
+    };
+    (*request_file_object(irp)).FsContext = session_id.to_context() as _;
+    OPEN_HANDLES.fetch_add(1, Ordering::Relaxed);
+    let status = complete_request(irp, Ok(0));
+    log_trace!(Dispatch, "IRP_MJ_CREATE>>IoCompleteRequest status: {}.", Status(status));
     status
 }
 
+/// I/O Request Package Major function - cleanup.
+/// Last handle is closed, release everything the client still holds.
+///
+/// # Safety
+/// Called by the I/O manager only, irp must be an IRP_MJ_CLEANUP request for device
+pub unsafe extern "system" fn irp_mj_cleanup(device: &mut DEVICE_OBJECT, irp: &mut IRP) -> NTSTATUS {
+    log_trace!(Dispatch, "IRP_MJ_CLEANUP called.");
+    if let Some(session_id) = request_session(irp) {
+        // Requests of the handle end before its input is released
+        cancel_playback(session_id);
+        let file_object = request_file_object(irp);
+        cancel_loopback_read(&LOOPBACK_MOUSE, &LOOPBACK_MOUSE_READ, file_object);
+        cancel_loopback_read(&LOOPBACK_KEYBOARD, &LOOPBACK_KEYBOARD_READ, file_object);
+        let (held, ended) = {
+            let mut state = GLOBAL_STATE.lock();
+            let state = &mut *state;
+            state.holds.disarm_session(session_id);
+            state.leases.release_session(session_id);
+            schedule_watchdog(state);
+            let held = match state.sessions.get(session_id) {
+                Some(session) => {
+                    session.cleaned_up = true;
+                    let held = session.held;
+                    session.held = HeldInput::new();
+                    held
+                }
+                None => HeldInput::new()
+            };
+            (held, take_ended_holds(state))
+        };
+        if !held.is_empty() {
+            log_info!(Dispatch, "IRP_MJ_CLEANUP>>Releasing input held by closed handle.");
+            release_held(&held);
+        }
+        complete_holds(&ended, Err(NtError::from_reason(NtReason::Cancelled)));
+    }
+    complete_request(irp, Ok(0))
+}
+
 // I/O Request Package Major function - close
 pub unsafe extern "system" fn irp_mj_close(device: &mut DEVICE_OBJECT, irp: &mut IRP) -> NTSTATUS {
-    DbgPrintEx(0, 0, "IRP_MJ_CLOSE called.\0".as_ptr());
-    IoCompleteRequest(irp as PIRP, IO_PRIORITY::IO_NO_INCREMENT);
-    // Return NSTATUS
-    let status = *irp.IoStatus.__bindgen_anon_1.Status();
-    DbgPrintEx(0, 0, "IRP_MJ_CLOSE>>IoCompleteRequest status: %u.\0".as_ptr(), status);
+    log_trace!(Dispatch, "IRP_MJ_CLOSE called.");
+    if let Some(session_id) = request_session(irp) {
+        GLOBAL_STATE.lock().sessions.close(session_id);
+    }
+    OPEN_HANDLES.fetch_sub(1, Ordering::Relaxed);
+    let status = complete_request(irp, Ok(0));
+    log_trace!(Dispatch, "IRP_MJ_CLOSE>>IoCompleteRequest status: {}.", Status(status));
     status
 }
 
@@ -248,23 +2046,29 @@ pub unsafe extern "system" fn irp_mj_close(device: &mut DEVICE_OBJECT, irp: &mut
 pub extern "system" fn driver_exit(driver: &mut DRIVER_OBJECT) {
     unsafe {
 
-        // Delete device symlink
-        let mut io_symlink_name_unicode: UNICODE_STRING = zeroed_unicode_string();
-        RtlInitUnicodeString(&mut io_symlink_name_unicode as *mut UNICODE_STRING,
-            IO_SYMLINK_NAME.as_ptr());
-        IoDeleteSymbolicLink(&io_symlink_name_unicode);
-        DbgPrintEx(0, 0, "Device symlink removed.\0".as_ptr());
+        // No hold releases or playback after this point
+        stop_timers();
+
+        // Delete devices and their symlinks
+        let devices = GLOBAL_STATE.lock().config.devices;
+        device::delete_devices(driver as PDRIVER_OBJECT, &devices);
 
-        // Close IO device
-        IoDeleteDevice(driver.DeviceObject);
-        DbgPrintEx(0, 0, "Device removed.\0".as_ptr());
+        // Report leaks
+        let open_handles = OPEN_HANDLES.load(Ordering::Relaxed);
+        if open_handles != 0 {
+            log_warn!(Driver, "Unloading with {} open handles.", open_handles);
+            eventlog::unload_with_leaks(open_handles, "open handles");
+        }
 
         // Bye-bye
-        DbgPrintEx(0, 0, "meta_driver unloaded. Bye-Bye!.\0".as_ptr());
+        log_info!(Driver, "meta_driver unloaded. Bye-Bye!.");
+        etw::write(tracelog::driver_unload());
+        etw::unregister();
     }
 }
 
 // Temporary __CxxFrameHandler3 issue fix
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "system" fn __CxxFrameHandler3() -> i32 {
     0
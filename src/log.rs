@@ -0,0 +1,304 @@
+// Imports
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU8, Ordering};
+use wchar::{wchz, wchar_t};
+use winapi::shared::ntdef::NTSTATUS;
+use winapi::shared::ntstatus::{
+	STATUS_SUCCESS,
+	STATUS_PENDING,
+	STATUS_TIMEOUT,
+	STATUS_DEVICE_BUSY,
+	STATUS_UNSUCCESSFUL,
+	STATUS_NOT_IMPLEMENTED,
+	STATUS_INVALID_HANDLE,
+	STATUS_INVALID_PARAMETER,
+	STATUS_NO_SUCH_DEVICE,
+	STATUS_INVALID_DEVICE_REQUEST,
+	STATUS_ACCESS_DENIED,
+	STATUS_BUFFER_TOO_SMALL,
+	STATUS_OBJECT_TYPE_MISMATCH,
+	STATUS_OBJECT_NAME_INVALID,
+	STATUS_OBJECT_NAME_NOT_FOUND,
+	STATUS_OBJECT_NAME_COLLISION,
+	STATUS_OBJECT_PATH_NOT_FOUND,
+	STATUS_INSUFFICIENT_RESOURCES,
+	STATUS_DEVICE_NOT_READY,
+	STATUS_NOT_SUPPORTED,
+	STATUS_CANCELLED,
+	STATUS_INVALID_BUFFER_SIZE,
+	STATUS_NOT_FOUND,
+	STATUS_FAIL_CHECK
+};
+use winapi::km::wdm::DbgPrintEx;
+use crate::error::NtError;
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Log levels. Lower value is more important.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[repr(u8)]
+pub enum Level {
+	Off = 0,
+	Error = 1,
+	Warn = 2,
+	Info = 3,
+	Debug = 4,
+	Trace = 5
+}
+
+// Log components. Each one has its own runtime level.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Component {
+	Driver = 0,
+	Dispatch = 1,
+	Mouse = 2,
+	Keyboard = 3,
+	Discovery = 4
+}
+pub const COMPONENT_COUNT: usize = 5;
+pub const COMPONENTS: [Component; COMPONENT_COUNT] = [
+	Component::Driver,
+	Component::Dispatch,
+	Component::Mouse,
+	Component::Keyboard,
+	Component::Discovery
+];
+
+// Compile-time maximum level. Calls above it are removed by the compiler.
+#[cfg(debug_assertions)]
+pub const STATIC_MAX_LEVEL: Level = Level::Trace;
+#[cfg(not(debug_assertions))]
+pub const STATIC_MAX_LEVEL: Level = Level::Info;
+
+// Runtime level used until registry values are loaded
+pub const DEFAULT_LEVEL: Level = Level::Info;
+
+// Max length of one log line including prefix and terminator
+const LINE_SIZE: usize = 256;
+
+// Runtime levels per component
+static LEVELS: [AtomicU8; COMPONENT_COUNT] = [const { AtomicU8::new(DEFAULT_LEVEL as u8) }; COMPONENT_COUNT];
+
+// NTSTATUS display wrapper: hex value plus symbolic name
+#[derive(Copy, Clone)]
+pub struct Status(pub NTSTATUS);
+
+// UTF-16 string display wrapper, stops at first NUL
+#[derive(Copy, Clone)]
+pub struct WideStr<'a>(pub &'a [wchar_t]);
+
+// Fixed size line buffer, silently truncates long messages
+struct LineBuffer {
+	buf: [u8; LINE_SIZE],
+	len: usize
+}
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl Level {
+	// Level from numeric value, out of range values are clamped to Trace
+	pub fn from_u32(value: u32) -> Level {
+		match value {
+			0 => Level::Off,
+			1 => Level::Error,
+			2 => Level::Warn,
+			3 => Level::Info,
+			4 => Level::Debug,
+			_ => Level::Trace
+		}
+	}
+
+	pub fn tag(self) -> &'static str {
+		match self {
+			Level::Off => "OFF  ",
+			Level::Error => "ERROR",
+			Level::Warn => "WARN ",
+			Level::Info => "INFO ",
+			Level::Debug => "DEBUG",
+			Level::Trace => "TRACE"
+		}
+	}
+}
+
+impl Component {
+	pub fn tag(self) -> &'static str {
+		match self {
+			Component::Driver => "driver",
+			Component::Dispatch => "dispatch",
+			Component::Mouse => "mouse",
+			Component::Keyboard => "keyboard",
+			Component::Discovery => "discovery"
+		}
+	}
+
+	// Registry value which overrides LogLevel for the component
+	pub fn level_value_name(self) -> &'static [wchar_t] {
+		match self {
+			Component::Driver => wchz!("LogLevelDriver"),
+			Component::Dispatch => wchz!("LogLevelDispatch"),
+			Component::Mouse => wchz!("LogLevelMouse"),
+			Component::Keyboard => wchz!("LogLevelKeyboard"),
+			Component::Discovery => wchz!("LogLevelDiscovery")
+		}
+	}
+}
+
+// Set runtime level of one component
+pub fn set_level(component: Component, level: Level) {
+	LEVELS[component as usize].store(level as u8, Ordering::Relaxed);
+}
+
+// Runtime level of one component
+pub fn level(component: Component) -> Level {
+	Level::from_u32(LEVELS[component as usize].load(Ordering::Relaxed) as u32)
+}
+
+// Apply runtime levels of all components, e.g. from configuration
+pub fn apply_levels(levels: &[Level; COMPONENT_COUNT]) {
+	for component in COMPONENTS.iter() {
+		set_level(*component, levels[*component as usize]);
+	}
+}
+
+// Check if message should be printed
+#[inline(always)]
+pub fn enabled(component: Component, level: Level) -> bool {
+	level != Level::Off
+		&& level <= STATIC_MAX_LEVEL
+		&& level as u8 <= LEVELS[component as usize].load(Ordering::Relaxed)
+}
+
+// Symbolic NTSTATUS name, empty if unknown
+pub fn status_name(status: NTSTATUS) -> &'static str {
+	match status {
+		STATUS_SUCCESS => "STATUS_SUCCESS",
+		STATUS_PENDING => "STATUS_PENDING",
+		STATUS_TIMEOUT => "STATUS_TIMEOUT",
+		STATUS_DEVICE_BUSY => "STATUS_DEVICE_BUSY",
+		STATUS_UNSUCCESSFUL => "STATUS_UNSUCCESSFUL",
+		STATUS_NOT_IMPLEMENTED => "STATUS_NOT_IMPLEMENTED",
+		STATUS_INVALID_HANDLE => "STATUS_INVALID_HANDLE",
+		STATUS_INVALID_PARAMETER => "STATUS_INVALID_PARAMETER",
+		STATUS_NO_SUCH_DEVICE => "STATUS_NO_SUCH_DEVICE",
+		STATUS_INVALID_DEVICE_REQUEST => "STATUS_INVALID_DEVICE_REQUEST",
+		STATUS_ACCESS_DENIED => "STATUS_ACCESS_DENIED",
+		STATUS_BUFFER_TOO_SMALL => "STATUS_BUFFER_TOO_SMALL",
+		STATUS_OBJECT_TYPE_MISMATCH => "STATUS_OBJECT_TYPE_MISMATCH",
+		STATUS_OBJECT_NAME_INVALID => "STATUS_OBJECT_NAME_INVALID",
+		STATUS_OBJECT_NAME_NOT_FOUND => "STATUS_OBJECT_NAME_NOT_FOUND",
+		STATUS_OBJECT_NAME_COLLISION => "STATUS_OBJECT_NAME_COLLISION",
+		STATUS_OBJECT_PATH_NOT_FOUND => "STATUS_OBJECT_PATH_NOT_FOUND",
+		STATUS_INSUFFICIENT_RESOURCES => "STATUS_INSUFFICIENT_RESOURCES",
+		STATUS_DEVICE_NOT_READY => "STATUS_DEVICE_NOT_READY",
+		STATUS_NOT_SUPPORTED => "STATUS_NOT_SUPPORTED",
+		STATUS_CANCELLED => "STATUS_CANCELLED",
+		STATUS_INVALID_BUFFER_SIZE => "STATUS_INVALID_BUFFER_SIZE",
+		STATUS_NOT_FOUND => "STATUS_NOT_FOUND",
+		STATUS_FAIL_CHECK => "STATUS_FAIL_CHECK",
+		_ => ""
+	}
+}
+
+impl fmt::Display for Status {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let name = status_name(self.0);
+		if name.is_empty() {
+			write!(f, "0x{:08X}", self.0 as u32)
+		} else {
+			write!(f, "0x{:08X} ({})", self.0 as u32, name)
+		}
+	}
+}
+
+impl fmt::Display for NtError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} reason {:?} ({})", Status(self.status), self.reason, self.reason as u32)
+	}
+}
+
+impl<'a> fmt::Display for WideStr<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let units = self.0.iter().cloned().take_while(|unit| *unit != 0);
+		for decoded in core::char::decode_utf16(units) {
+			f.write_char(decoded.unwrap_or(core::char::REPLACEMENT_CHARACTER))?;
+		}
+		Ok(())
+	}
+}
+
+impl LineBuffer {
+	const fn new() -> LineBuffer {
+		LineBuffer {
+			buf: [0u8; LINE_SIZE],
+			len: 0
+		}
+	}
+
+	// Terminate line with new line and NUL
+	fn finish(&mut self) -> *const u8 {
+		let end = if self.len > LINE_SIZE - 2 { LINE_SIZE - 2 } else { self.len };
+		self.buf[end] = b'\n';
+		self.buf[end + 1] = 0;
+		self.buf.as_ptr()
+	}
+}
+
+impl fmt::Write for LineBuffer {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		// Keep two bytes for line terminator, drop NULs which would cut the line
+		for &byte in s.as_bytes() {
+			if self.len >= LINE_SIZE - 2 {
+				break;
+			}
+			if byte != 0 {
+				self.buf[self.len] = byte;
+				self.len += 1;
+			}
+		}
+		Ok(())
+	}
+}
+
+// Format and print one line. Use log_* macros instead of calling directly.
+pub fn write(component: Component, level: Level, args: fmt::Arguments) {
+	let mut line = LineBuffer::new();
+	let _ = write!(line, "meta_driver [{}][{}] ", level.tag(), component.tag());
+	let _ = line.write_fmt(args);
+	unsafe {
+		// Message goes as %s argument so it is never parsed as a format string
+		DbgPrintEx(0, 0, c"%s".as_ptr().cast(), line.finish());
+	}
+}
+
+// MACROS ==========================================
+
+// Log with explicit level
+macro_rules! log_at {
+	($component:ident, $level:expr, $($arg:tt)+) => {
+		if $crate::log::enabled($crate::log::Component::$component, $level) {
+			$crate::log::write($crate::log::Component::$component, $level, format_args!($($arg)+));
+		}
+	};
+}
+
+macro_rules! log_error {
+	($component:ident, $($arg:tt)+) => { log_at!($component, $crate::log::Level::Error, $($arg)+) };
+}
+
+macro_rules! log_warn {
+	($component:ident, $($arg:tt)+) => { log_at!($component, $crate::log::Level::Warn, $($arg)+) };
+}
+
+macro_rules! log_info {
+	($component:ident, $($arg:tt)+) => { log_at!($component, $crate::log::Level::Info, $($arg)+) };
+}
+
+macro_rules! log_debug {
+	($component:ident, $($arg:tt)+) => { log_at!($component, $crate::log::Level::Debug, $($arg)+) };
+}
+
+macro_rules! log_trace {
+	($component:ident, $($arg:tt)+) => { log_at!($component, $crate::log::Level::Trace, $($arg)+) };
+}
@@ -15,15 +15,20 @@ pub use winapi::shared::minwindef::{
 	USHORT
 };
 use winapi::km::wdm::{
-	DbgPrintEx,
 	DEVICE_OBJECT,
 	PDEVICE_OBJECT,
 	PDRIVER_OBJECT,
 	KPROCESSOR_MODE
 };
-use winapi::shared::ntstatus::{
-	STATUS_SUCCESS,
-	STATUS_FAIL_CHECK
+use crate::log::{Status, WideStr};
+use crate::etw;
+use crate::tracelog;
+use crate::stats;
+use crate::error::{
+	NtError,
+	NtReason,
+	NtResult,
+	nt_check
 };
 use crate::winapi_local::km::wdm::{
     zeroed_unicode_string,
@@ -33,6 +38,7 @@ use crate::winapi_local::km::wdm::{
     IoDriverObjectType,
     KeRaiseIrql,
     KeLowerIrql,
+    KeQueryPerformanceCounter,
     ULONG_PTR,
     PULONG_PTR,
     DISPATCH_LEVEL,
@@ -43,7 +49,6 @@ use crate::winapi_local::km::wdm::{
 
 // Constants
 pub const MOUSE_DRIVER_CLASS_NAME: &[wchar_t] = wchz!("\\Driver\\MouClass");
-pub const MOUSE_DRIVER_HID_NAME: &[wchar_t] = wchz!("\\Driver\\MouHID");
 
 pub const MOUSE_LEFT_BUTTON_DOWN: USHORT = 0x0001;  // Left Button changed to down.
 pub const MOUSE_LEFT_BUTTON_UP: USHORT = 0x0002;  // Left Button changed to up.
@@ -119,10 +124,24 @@ pub const fn zeroed_mouse_object() -> MOUSE_OBJECT {
 	}
 }
 
-// Mouse init
-pub fn mouse_init(mouse_object: PMOUSE_OBJECT) -> NTSTATUS {
+// Mouse input data of one event
+pub const fn mouse_input_data(x: LONG, y: LONG, button_flags: USHORT, flags: USHORT, extra_information: ULONG) -> MOUSE_INPUT_DATA {
+	MOUSE_INPUT_DATA {
+		UnitId: 0,
+		Flags: flags,
+		ButtonFlags: button_flags,
+		ButtonData: 0,
+		RawButtons: 0,
+		LastX: x,
+		LastY: y,
+		ExtraInformation: extra_information
+	}
+}
+
+// Mouse init, HID driver name must be NUL terminated
+pub fn mouse_init(mouse_object: PMOUSE_OBJECT, hid_driver_name: &[wchar_t]) -> NtResult<()> {
 	unsafe {
-		DbgPrintEx(0, 0, "mouse_init called.\0".as_ptr());
+		log_debug!(Discovery, "mouse_init called.");
 
 		// FIND MOU CLASS DRIVER
 		let mut mouse_driver_class_unicode: UNICODE_STRING = zeroed_unicode_string();
@@ -139,18 +158,16 @@ pub fn mouse_init(mouse_object: PMOUSE_OBJECT) -> NTSTATUS {
 			core::ptr::null_mut(),
 			&mut class_driver_obj as *mut _ as *mut PVOID
 		);
-		DbgPrintEx(0, 0, "ObReferenceObjectByName>>MouClass status: %u\0".as_ptr(), mou_driver_find_status);
-		DbgPrintEx(0, 0, "Mouse class object pointer: %p\0".as_ptr(), class_driver_obj);
-		if mou_driver_find_status != STATUS_SUCCESS {
-			return mou_driver_find_status;
-		}
+		log_debug!(Discovery, "ObReferenceObjectByName>>MouClass status: {}", Status(mou_driver_find_status));
+		log_trace!(Discovery, "Mouse class object pointer: {:p}", class_driver_obj);
+		nt_check(mou_driver_find_status, NtReason::ClassDriverNotFound)?;
 		let class_driver_obj_uptr: ULONG_PTR = class_driver_obj as ULONG_PTR;
 
 		// FIND MOU HID DRIVER
 		let mut mouse_driver_hid_unicode: UNICODE_STRING = zeroed_unicode_string();
 		let mut hid_driver_obj: PDRIVER_OBJECT = core::ptr::null_mut();
 		RtlInitUnicodeString(&mut mouse_driver_hid_unicode as *mut UNICODE_STRING,
-            MOUSE_DRIVER_HID_NAME.as_ptr());
+            hid_driver_name.as_ptr());
 		let hid_driver_find_status = ObReferenceObjectByName(
 			&mut mouse_driver_hid_unicode as *mut UNICODE_STRING,
 			OBJ_CASE_INSENSITIVE,
@@ -161,11 +178,11 @@ pub fn mouse_init(mouse_object: PMOUSE_OBJECT) -> NTSTATUS {
 			core::ptr::null_mut(),
 			&mut hid_driver_obj as *mut _ as *mut PVOID
 		);
-		DbgPrintEx(0, 0, "ObReferenceObjectByName>>MouHID status: %u\0".as_ptr(), hid_driver_find_status);
-		DbgPrintEx(0, 0, "Hid object pointer: %p\0".as_ptr(), hid_driver_obj);
-		if hid_driver_find_status != STATUS_SUCCESS {
+		log_debug!(Discovery, "ObReferenceObjectByName>>{} status: {}", WideStr(hid_driver_name), Status(hid_driver_find_status));
+		log_trace!(Discovery, "Hid object pointer: {:p}", hid_driver_obj);
+		if let Err(error) = nt_check(hid_driver_find_status, NtReason::HidDriverNotFound) {
 			ObDereferenceObject(class_driver_obj as PVOID);
-			return hid_driver_find_status;
+			return Err(error);
 		}
 
 		// Lookup
@@ -180,7 +197,8 @@ pub fn mouse_init(mouse_object: PMOUSE_OBJECT) -> NTSTATUS {
 					(*mouse_object).mouse_device = class_device_obj;
 				}
 				if (*hid_device_obj).DeviceObjectExtension.is_null() {
-					DbgPrintEx(0, 0, "NULL DEVICE EXTENSION\0".as_ptr());
+					log_warn!(Discovery, "NULL DEVICE EXTENSION");
+					class_device_obj = (*class_device_obj).NextDevice;
 					continue;
 				}
 
@@ -188,7 +206,7 @@ pub fn mouse_init(mouse_object: PMOUSE_OBJECT) -> NTSTATUS {
 				let device_extension_ptr: PULONG_PTR = (*hid_device_obj).DeviceExtension as PULONG_PTR;
 				let mut device_extension_size: ULONG_PTR = (*hid_device_obj).DeviceObjectExtension as ULONG_PTR;
 				device_extension_size = (device_extension_size - device_extension) / 4;
-				DbgPrintEx(0, 0, "Device extension size: %u\0".as_ptr(), device_extension_size);
+				log_trace!(Discovery, "Device extension size: {}", device_extension_size);
 				
 				class_driver_base = (*class_driver_obj).DriverStart as PVOID;
 				let class_device_obj_uptr: ULONG_PTR = class_device_obj as ULONG_PTR;
@@ -198,7 +216,7 @@ pub fn mouse_init(mouse_object: PMOUSE_OBJECT) -> NTSTATUS {
 					if *device_extension_ptr.offset(ext_idx_i) == class_device_obj_uptr
 						&& *device_extension_ptr.offset(ext_idx_i + 1) > class_driver_obj_uptr {
 							(*mouse_object).service_callback = Some(core::mem::transmute(*device_extension_ptr.offset(ext_idx_i + 1)));
-							DbgPrintEx(0, 0, "Service callback address: %p\0".as_ptr(), (*mouse_object).service_callback.unwrap());
+							log_debug!(Discovery, "Service callback address: {:p}", (*mouse_object).service_callback.unwrap());
 							break;
 					}
 				}
@@ -226,32 +244,35 @@ pub fn mouse_init(mouse_object: PMOUSE_OBJECT) -> NTSTATUS {
 		ObDereferenceObject(class_driver_obj as PVOID);
 		ObDereferenceObject(hid_driver_obj as PVOID);
 
+		// Check lookup results
+		if (*mouse_object).mouse_device.is_null() {
+			return Err(NtError::from_reason(NtReason::DeviceNotFound));
+		}
+		if (*mouse_object).service_callback.is_none() {
+			return Err(NtError::from_reason(NtReason::CallbackNotFound));
+		}
 	}
-	STATUS_SUCCESS
+	Ok(())
 }
 
 
-// Mouse event
-pub unsafe fn mouse_event(mouse_object: PMOUSE_OBJECT, x: LONG, y: LONG, button_flags: USHORT, flags: USHORT) -> NTSTATUS {
-	if (*mouse_object).service_callback.is_some() {
+/// Mouse event. Null mouse object (not discovered) fails like a missing callback.
+///
+/// # Safety
+/// mouse_object must be null or point to a discovered MOUSE_OBJECT, IRQL <= DISPATCH_LEVEL
+pub unsafe fn mouse_event(mouse_object: PMOUSE_OBJECT, x: LONG, y: LONG, button_flags: USHORT, flags: USHORT, extra_information: ULONG) -> NtResult<ULONG> {
+	if !mouse_object.is_null() && (*mouse_object).service_callback.is_some() {
 		let mut input_data: ULONG = 0u32;
 		let mut origin_irql: KIRQL = PASSIVE_LEVEL;
 		let mut origin_irql_ptr = &mut origin_irql as PKIRQL; 
 		let new_irql: KIRQL = DISPATCH_LEVEL;
-		let mut mouse_input_data = MOUSE_INPUT_DATA {
-			UnitId: 0,
-			Flags: flags,
-			ButtonFlags: button_flags,
-			ButtonData: 0,
-			RawButtons: 0,
-			LastX: x,
-			LastY: y,
-			ExtraInformation: 0
-		};
+		let mut mouse_input_data = mouse_input_data(x, y, button_flags, flags, extra_information);
 		let mouse_input_data_ptr = &mut mouse_input_data as PMOUSE_INPUT_DATA;
 
-		DbgPrintEx(0, 0, "mouse_event: NEW LEVEL %u\0".as_ptr(), new_irql as ULONG);
-		DbgPrintEx(0, 0, "mouse_event: CALLBACK ADDR %p\0".as_ptr(), (*mouse_object).service_callback.unwrap());
+		log_trace!(Mouse, "mouse_event: NEW LEVEL {}", new_irql);
+		log_trace!(Mouse, "mouse_event: CALLBACK ADDR {:p}", (*mouse_object).service_callback.unwrap());
+		let mut frequency: i64 = 0;
+		let start = KeQueryPerformanceCounter(&mut frequency);
 		KeRaiseIrql(new_irql, origin_irql_ptr);
 		((*mouse_object).service_callback.unwrap())(
 			(*mouse_object).mouse_device,
@@ -259,12 +280,45 @@ pub unsafe fn mouse_event(mouse_object: PMOUSE_OBJECT, x: LONG, y: LONG, button_
 			mouse_input_data_ptr.offset(1),
 			&mut input_data
 		);
-		KeLowerIrql(PASSIVE_LEVEL);
-		DbgPrintEx(0, 0, "mouse_event: service callback called with x: %i y: %i button_flags: %i\0".as_ptr(), x, y, button_flags as ULONG);
-		DbgPrintEx(0, 0, "mouse_event: OLD LEVEL %u\0".as_ptr(), *origin_irql_ptr as ULONG);
-		STATUS_SUCCESS
+		// Back to caller IRQL, hold releases run in a DPC at DISPATCH_LEVEL
+		KeLowerIrql(origin_irql);
+		let end = KeQueryPerformanceCounter(core::ptr::null_mut());
+		let latency_us = (end - start) as u64 * 1_000_000 / frequency as u64;
+		etw::write(tracelog::injection(tracelog::Device::Mouse, 1, input_data, latency_us));
+		stats::mouse_batch(flags & MOUSE_MOVE_ABSOLUTE != 0 || x != 0 || y != 0, button_flags != 0, input_data, latency_us);
+		log_debug!(Mouse, "mouse_event: service callback called with x: {} y: {} button_flags: 0x{:04X}", x, y, button_flags);
+		log_trace!(Mouse, "mouse_event: OLD LEVEL {}", *origin_irql_ptr);
+		Ok(input_data)
+	} else {
+		log_error!(Mouse, "mouse_event: service callback not defined");
+		Err(NtError::from_reason(NtReason::CallbackNotFound))
+	}
+}
+
+/// Call service callback with a zero-delta relative move, nothing visible reaches
+/// the system. Not counted in statistics or traced. Returns consumed count.
+///
+/// # Safety
+/// mouse_object must be null or point to a discovered MOUSE_OBJECT, IRQL <= DISPATCH_LEVEL
+pub unsafe fn mouse_probe(mouse_object: PMOUSE_OBJECT, extra_information: ULONG) -> NtResult<ULONG> {
+	if !mouse_object.is_null() && (*mouse_object).service_callback.is_some() {
+		let mut input_data: ULONG = 0u32;
+		let mut origin_irql: KIRQL = PASSIVE_LEVEL;
+		let mut mouse_input_data = mouse_input_data(0, 0, 0, MOUSE_MOVE_RELATIVE, extra_information);
+		let mouse_input_data_ptr = &mut mouse_input_data as PMOUSE_INPUT_DATA;
+
+		KeRaiseIrql(DISPATCH_LEVEL, &mut origin_irql as PKIRQL);
+		((*mouse_object).service_callback.unwrap())(
+			(*mouse_object).mouse_device,
+			mouse_input_data_ptr,
+			mouse_input_data_ptr.offset(1),
+			&mut input_data
+		);
+		KeLowerIrql(origin_irql);
+		log_debug!(Mouse, "mouse_probe: service callback called with zero move, consumed {}", input_data);
+		Ok(input_data)
 	} else {
-		DbgPrintEx(0, 0, "mouse_event: service callback not defined\0".as_ptr());
-		STATUS_FAIL_CHECK
+		log_error!(Mouse, "mouse_probe: service callback not defined");
+		Err(NtError::from_reason(NtReason::CallbackNotFound))
 	}
 }
\ No newline at end of file
@@ -0,0 +1,100 @@
+// Slot of one pended request with a cancel routine. Whoever takes the request
+// out of the slot completes it: the completion path through claim, the
+// cancel routine through claim_cancelled. Callers hold their own lock around
+// every call and around any use of a peeked request, so a request is never
+// completed while another path looks at it.
+
+// Imports
+use core::sync::atomic::{AtomicPtr, Ordering};
+use winapi::km::wdm::{IRP, PIRP};
+use crate::winapi_local::km::wdm::{
+	IoMarkIrpPending,
+	IoSetCancelRoutine,
+	IoReleaseCancelSpinLock,
+	PDRIVER_CANCEL
+};
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Result of parking a request
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Parked {
+	// Slot holds another request, nothing was done
+	Busy,
+	// Request is pending with its cancel routine armed
+	Pending,
+	// Request was cancelled before it was parked. It is marked pending, the
+	// caller completes it and returns STATUS_PENDING.
+	Cancelled
+}
+
+pub struct PendingIrp {
+	irp: AtomicPtr<IRP>
+}
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl Default for PendingIrp {
+	fn default() -> PendingIrp {
+		PendingIrp::new()
+	}
+}
+
+impl PendingIrp {
+	pub const fn new() -> PendingIrp {
+		PendingIrp {
+			irp: AtomicPtr::new(core::ptr::null_mut())
+		}
+	}
+
+	/// Mark request pending and arm its cancel routine
+	///
+	/// # Safety
+	/// irp must be a valid request owned by the caller, which returns STATUS_PENDING for it
+	pub unsafe fn park(&self, irp: PIRP, cancel: PDRIVER_CANCEL) -> Parked {
+		if self.irp.compare_exchange(core::ptr::null_mut(), irp, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+			return Parked::Busy;
+		}
+		IoMarkIrpPending(irp);
+		IoSetCancelRoutine(irp, Some(cancel));
+		// Cancelled before the routine was set, take it back unless the routine already runs
+		if (*irp).Cancel != 0 && IoSetCancelRoutine(irp, None).is_some() {
+			self.irp.store(core::ptr::null_mut(), Ordering::SeqCst);
+			return Parked::Cancelled;
+		}
+		Parked::Pending
+	}
+
+	// Parked request, it may be completed by others any time
+	pub fn peek(&self) -> PIRP {
+		self.irp.load(Ordering::SeqCst)
+	}
+
+	/// Take request for completion. None if the slot is empty or its cancel
+	/// routine runs, which then completes it.
+	///
+	/// # Safety
+	/// Called under the lock of the slot, a parked request must still be valid
+	pub unsafe fn claim(&self) -> Option<PIRP> {
+		let irp = self.irp.load(Ordering::SeqCst);
+		if irp.is_null() || IoSetCancelRoutine(irp, None).is_none() {
+			return None;
+		}
+		self.irp.store(core::ptr::null_mut(), Ordering::SeqCst);
+		Some(irp)
+	}
+
+	// Cancel routine takes the request if it is still parked here
+	pub fn claim_cancelled(&self, irp: PIRP) -> bool {
+		self.irp.compare_exchange(irp, core::ptr::null_mut(), Ordering::SeqCst, Ordering::SeqCst).is_ok()
+	}
+}
+
+/// First call of every cancel routine, before any other lock is taken.
+/// The request stays valid, only its cancel routine may complete it now.
+///
+/// # Safety
+/// Called only by a cancel routine with the request it was called for
+pub unsafe fn release_cancel_lock(irp: PIRP) {
+	IoReleaseCancelSpinLock((*irp).CancelIrql);
+}
@@ -0,0 +1,303 @@
+// PLAYBACK schedule: due events of one submitted sequence by clock::now_us.
+
+// Imports
+use crate::session::SessionId;
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Max events in one sequence
+pub const MAX_PLAYBACK_EVENTS: usize = 512;
+
+// Max time from start to the last event, 10 minutes
+pub const MAX_PLAYBACK_SPAN_US: u64 = 600_000_000;
+
+// PlaybackHeader flags
+// Event times are offsets from the start instead of delays after the previous event
+pub const PLAYBACK_ABSOLUTE: u32 = 0x1;
+
+// Played device, values match DeviceRole
+pub const PLAYBACK_DEVICE_MOUSE: u32 = 0;
+pub const PLAYBACK_DEVICE_KEYBOARD: u32 = 1;
+
+// PLAYBACK request, followed by count PlaybackEvents
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct PlaybackHeader {
+	pub flags: u32,
+	pub count: u32
+}
+
+// One scheduled event
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct PlaybackEvent {
+	// Microseconds after the previous event, or after the start with PLAYBACK_ABSOLUTE
+	pub time_us: u64,
+	// PLAYBACK_DEVICE_*
+	pub device: u32,
+	// Mouse: MOUSE_MOVE_* flags. Keyboard: KEY_* flags.
+	pub flags: u16,
+	// Mouse: button flags. Keyboard: make code.
+	pub code: u16,
+	// Mouse position or movement, 0 for keyboard
+	pub x: i32,
+	pub y: i32
+}
+
+// Why a sequence was refused
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PlaybackError {
+	// No events or more than MAX_PLAYBACK_EVENTS
+	BadCount,
+	// Unknown flags or device
+	BadEvent,
+	// Absolute times go back
+	NotMonotonic,
+	// Last event is later than MAX_PLAYBACK_SPAN_US
+	TooLong
+}
+
+// Sequence being played
+pub struct Playback {
+	events: [PlaybackEvent; MAX_PLAYBACK_EVENTS],
+	count: usize,
+	// Index of the next event to play
+	next: usize,
+	// Events injected, counted only once their injection succeeded
+	delivered: u32,
+	// Grows with every started sequence, so results of an event taken from
+	// an earlier sequence do not touch the current one
+	generation: u32,
+	start_us: u64,
+	session: Option<SessionId>
+}
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl PlaybackEvent {
+	pub const fn zeroed() -> PlaybackEvent {
+		PlaybackEvent {
+			time_us: 0,
+			device: 0,
+			flags: 0,
+			code: 0,
+			x: 0,
+			y: 0
+		}
+	}
+}
+
+impl PlaybackError {
+	pub fn description(self) -> &'static str {
+		match self {
+			PlaybackError::BadCount => "event count out of range",
+			PlaybackError::BadEvent => "unknown flags or device",
+			PlaybackError::NotMonotonic => "absolute times go back",
+			PlaybackError::TooLong => "sequence too long"
+		}
+	}
+}
+
+impl Default for Playback {
+	fn default() -> Playback {
+		Playback::new()
+	}
+}
+
+impl Playback {
+	pub const fn new() -> Playback {
+		Playback {
+			events: [PlaybackEvent::zeroed(); MAX_PLAYBACK_EVENTS],
+			count: 0,
+			next: 0,
+			delivered: 0,
+			generation: 0,
+			start_us: 0,
+			session: None
+		}
+	}
+
+	// Start sequence for session at start_us. Event times become offsets from
+	// the start, the caller validates device specific fields.
+	pub fn start(&mut self, header: &PlaybackHeader, events: &[PlaybackEvent], session: Option<SessionId>,
+		start_us: u64) -> Result<(), PlaybackError> {
+		if events.is_empty() || events.len() > MAX_PLAYBACK_EVENTS || header.count as usize != events.len() {
+			return Err(PlaybackError::BadCount);
+		}
+		if header.flags & !PLAYBACK_ABSOLUTE != 0 {
+			return Err(PlaybackError::BadEvent);
+		}
+		let absolute = header.flags & PLAYBACK_ABSOLUTE != 0;
+		let mut offset_us: u64 = 0;
+		for (slot, event) in self.events.iter_mut().zip(events.iter()) {
+			if event.device != PLAYBACK_DEVICE_MOUSE && event.device != PLAYBACK_DEVICE_KEYBOARD {
+				return Err(PlaybackError::BadEvent);
+			}
+			offset_us = if absolute {
+				if event.time_us < offset_us {
+					return Err(PlaybackError::NotMonotonic);
+				}
+				event.time_us
+			} else {
+				offset_us.saturating_add(event.time_us)
+			};
+			if offset_us > MAX_PLAYBACK_SPAN_US {
+				return Err(PlaybackError::TooLong);
+			}
+			*slot = PlaybackEvent { time_us: offset_us, ..*event };
+		}
+		self.count = events.len();
+		self.next = 0;
+		self.delivered = 0;
+		self.generation = self.generation.wrapping_add(1);
+		self.start_us = start_us;
+		self.session = session;
+		Ok(())
+	}
+
+	pub fn is_active(&self) -> bool {
+		self.next < self.count
+	}
+
+	pub fn session(&self) -> Option<SessionId> {
+		self.session
+	}
+
+	// Events played so far
+	pub fn delivered(&self) -> u32 {
+		self.delivered
+	}
+
+	pub fn generation(&self) -> u32 {
+		self.generation
+	}
+
+	// Next event if it is due. It counts as delivered once played() is called.
+	pub fn take_due(&mut self, now_us: u64) -> Option<PlaybackEvent> {
+		if !self.is_active() || self.start_us + self.events[self.next].time_us > now_us {
+			return None;
+		}
+		self.next += 1;
+		Some(self.events[self.next - 1])
+	}
+
+	// Deadline of the next event, None when the sequence is done
+	pub fn deadline_us(&self) -> Option<u64> {
+		if self.is_active() {
+			Some(self.start_us + self.events[self.next].time_us)
+		} else {
+			None
+		}
+	}
+
+	// Event taken from sequence generation was injected
+	pub fn played(&mut self, generation: u32) {
+		if generation == self.generation {
+			self.delivered += 1;
+		}
+	}
+
+	// Stop after an event taken from sequence generation failed. Returns events
+	// played, None if that sequence was replaced meanwhile.
+	pub fn fail(&mut self, generation: u32) -> Option<u32> {
+		if generation != self.generation {
+			return None;
+		}
+		Some(self.stop())
+	}
+
+	// Drop remaining events, returns events played
+	pub fn stop(&mut self) -> u32 {
+		self.count = self.next;
+		self.delivered
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn key(time_us: u64) -> PlaybackEvent {
+		PlaybackEvent {
+			time_us,
+			device: PLAYBACK_DEVICE_KEYBOARD,
+			..PlaybackEvent::zeroed()
+		}
+	}
+
+	fn started(events: &[PlaybackEvent], flags: u32) -> Box<Playback> {
+		let mut playback = Box::new(Playback::new());
+		let header = PlaybackHeader {
+			flags,
+			count: events.len() as u32
+		};
+		playback.start(&header, events, None, 1000).unwrap();
+		playback
+	}
+
+	#[test]
+	fn events_are_due_at_their_offset() {
+		let mut playback = started(&[key(10), key(5), key(0)], 0);
+		assert_eq!(playback.deadline_us(), Some(1010));
+		assert!(playback.take_due(1009).is_none());
+		assert!(playback.take_due(1010).is_some());
+		assert_eq!(playback.deadline_us(), Some(1015));
+		assert!(playback.take_due(1015).is_some());
+		assert!(playback.take_due(1015).is_some());
+		assert!(!playback.is_active());
+		assert_eq!(playback.deadline_us(), None);
+	}
+
+	#[test]
+	fn absolute_times_must_not_go_back() {
+		let mut playback = Box::new(Playback::new());
+		let header = PlaybackHeader { flags: PLAYBACK_ABSOLUTE, count: 2 };
+		assert_eq!(playback.start(&header, &[key(10), key(5)], None, 0), Err(PlaybackError::NotMonotonic));
+		let header = PlaybackHeader { flags: 0, count: 1 };
+		let bad_device = PlaybackEvent { device: 2, ..key(0) };
+		assert_eq!(playback.start(&header, &[bad_device], None, 0), Err(PlaybackError::BadEvent));
+		assert_eq!(playback.start(&header, &[key(MAX_PLAYBACK_SPAN_US + 1)], None, 0), Err(PlaybackError::TooLong));
+		assert_eq!(playback.start(&header, &[], None, 0), Err(PlaybackError::BadCount));
+	}
+
+	#[test]
+	fn only_played_events_are_delivered() {
+		let mut playback = started(&[key(0), key(0), key(0)], 0);
+		let generation = playback.generation();
+		playback.take_due(1000).unwrap();
+		playback.played(generation);
+		// Taken but not yet injected
+		playback.take_due(1000).unwrap();
+		assert_eq!(playback.delivered(), 1);
+		assert_eq!(playback.stop(), 1);
+		// Injection finished after the stop still counts
+		playback.played(generation);
+		assert_eq!(playback.delivered(), 2);
+		assert!(!playback.is_active());
+	}
+
+	#[test]
+	fn failed_event_is_not_delivered() {
+		let mut playback = started(&[key(0), key(0), key(0)], 0);
+		let generation = playback.generation();
+		playback.take_due(1000).unwrap();
+		playback.played(generation);
+		playback.take_due(1000).unwrap();
+		assert_eq!(playback.fail(generation), Some(1));
+		assert!(!playback.is_active());
+	}
+
+	#[test]
+	fn stale_results_leave_new_sequence_alone() {
+		let mut playback = started(&[key(0), key(0)], 0);
+		let old = playback.generation();
+		playback.take_due(1000).unwrap();
+		playback.stop();
+		let header = PlaybackHeader { flags: 0, count: 2 };
+		playback.start(&header, &[key(0), key(0)], None, 2000).unwrap();
+		playback.played(old);
+		assert_eq!(playback.fail(old), None);
+		assert_eq!(playback.delivered(), 0);
+		assert!(playback.is_active());
+	}
+}
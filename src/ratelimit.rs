@@ -0,0 +1,288 @@
+// Token bucket rate limiting of injected events. Pure bookkeeping, the caller
+// passes the current time (clock::now_ms) and decides what to do with a wait.
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Tokens are counted in thousandths, so refill is events_per_sec per millisecond
+const MILLI: u64 = 1000;
+
+// Bucket size and refill rate. 0 events per second disables the limit.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct RateLimit {
+	pub events_per_sec: u32,
+	// Events accepted at once after idle time, at least 1
+	pub burst: u32
+}
+
+// Session and driver-wide limits of one device
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct RateLimits {
+	pub session: RateLimit,
+	pub global: RateLimit
+}
+
+// What to do with a request over the limit
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum RatePolicy {
+	// Fail the request
+	Reject = 0,
+	// Wait until tokens are available, up to a bounded delay
+	Delay = 1
+}
+
+// Outcome of a rate check
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RateDecision {
+	// Tokens were taken
+	Allow,
+	// Nothing was taken, tokens are available after this time
+	Wait(u64),
+	// Request is larger than the burst, it never fits
+	Never
+}
+
+// Token bucket state, the limit is passed in so reloads apply at once
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TokenBucket {
+	millitokens: u64,
+	// None until first use, a new bucket is full
+	last_ms: Option<u64>
+}
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl RateLimit {
+	pub const UNLIMITED: RateLimit = RateLimit {
+		events_per_sec: 0,
+		burst: 0
+	};
+
+	pub fn is_unlimited(&self) -> bool {
+		self.events_per_sec == 0
+	}
+
+	fn capacity(&self) -> u64 {
+		self.burst.max(1) as u64 * MILLI
+	}
+}
+
+impl RatePolicy {
+	pub fn from_u32(value: u32) -> RatePolicy {
+		match value {
+			0 => RatePolicy::Reject,
+			_ => RatePolicy::Delay
+		}
+	}
+}
+
+impl Default for TokenBucket {
+	fn default() -> TokenBucket {
+		TokenBucket::new()
+	}
+}
+
+impl TokenBucket {
+	pub const fn new() -> TokenBucket {
+		TokenBucket {
+			millitokens: 0,
+			last_ms: None
+		}
+	}
+
+	// Time until count events fit, 0 if they fit now, None if they never do
+	pub fn wait_ms(&mut self, limit: RateLimit, now_ms: u64, count: u32) -> Option<u64> {
+		if limit.is_unlimited() {
+			return Some(0);
+		}
+		self.refill(limit, now_ms);
+		let needed = count as u64 * MILLI;
+		if needed > limit.capacity() {
+			None
+		} else if self.millitokens >= needed {
+			Some(0)
+		} else {
+			let rate = limit.events_per_sec as u64;
+			Some((needed - self.millitokens).div_ceil(rate))
+		}
+	}
+
+	// Take count events, wait_ms must have returned 0 for them
+	pub fn take(&mut self, limit: RateLimit, count: u32) {
+		if !limit.is_unlimited() {
+			self.millitokens = self.millitokens.saturating_sub(count as u64 * MILLI);
+		}
+	}
+
+	fn refill(&mut self, limit: RateLimit, now_ms: u64) {
+		let capacity = limit.capacity();
+		self.millitokens = match self.last_ms {
+			None => capacity,
+			Some(last_ms) => {
+				let elapsed_ms = now_ms.saturating_sub(last_ms);
+				self.millitokens
+					.saturating_add(elapsed_ms.saturating_mul(limit.events_per_sec as u64))
+					.min(capacity)
+			}
+		};
+		self.last_ms = Some(now_ms);
+	}
+}
+
+// Take count events from session and global bucket, or from neither of them
+pub fn take_both(session: Option<&mut TokenBucket>, global: &mut TokenBucket, limits: RateLimits, now_ms: u64, count: u32) -> RateDecision {
+	let global_wait = match global.wait_ms(limits.global, now_ms, count) {
+		Some(wait_ms) => wait_ms,
+		None => return RateDecision::Never
+	};
+	let (session, session_wait) = match session {
+		Some(bucket) => match bucket.wait_ms(limits.session, now_ms, count) {
+			Some(wait_ms) => (Some(bucket), wait_ms),
+			None => return RateDecision::Never
+		},
+		None => (None, 0)
+	};
+	let wait_ms = global_wait.max(session_wait);
+	if wait_ms > 0 {
+		return RateDecision::Wait(wait_ms);
+	}
+	global.take(limits.global, count);
+	if let Some(bucket) = session {
+		bucket.take(limits.session, count);
+	}
+	RateDecision::Allow
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TEN_PER_SEC: RateLimit = RateLimit {
+		events_per_sec: 10,
+		burst: 3
+	};
+
+	fn limits(session: RateLimit, global: RateLimit) -> RateLimits {
+		RateLimits {
+			session,
+			global
+		}
+	}
+
+	// Take single events until the bucket refuses, returns events taken
+	fn drain(bucket: &mut TokenBucket, limit: RateLimit, now_ms: u64) -> u32 {
+		let mut taken = 0;
+		while bucket.wait_ms(limit, now_ms, 1) == Some(0) {
+			bucket.take(limit, 1);
+			taken += 1;
+		}
+		taken
+	}
+
+	#[test]
+	fn new_bucket_is_full() {
+		let mut bucket = TokenBucket::new();
+		assert_eq!(drain(&mut bucket, TEN_PER_SEC, 1000), 3);
+	}
+
+	#[test]
+	fn wait_is_time_to_next_token() {
+		let mut bucket = TokenBucket::new();
+		drain(&mut bucket, TEN_PER_SEC, 1000);
+		assert_eq!(bucket.wait_ms(TEN_PER_SEC, 1000, 1), Some(100));
+		assert_eq!(bucket.wait_ms(TEN_PER_SEC, 1030, 1), Some(70));
+		assert_eq!(bucket.wait_ms(TEN_PER_SEC, 1030, 2), Some(170));
+		// Partial tokens round the wait up
+		let fast = RateLimit { events_per_sec: 3, burst: 1 };
+		let mut bucket = TokenBucket::new();
+		drain(&mut bucket, fast, 0);
+		assert_eq!(bucket.wait_ms(fast, 0, 1), Some(334));
+	}
+
+	#[test]
+	fn tokens_refill_with_time() {
+		let mut bucket = TokenBucket::new();
+		drain(&mut bucket, TEN_PER_SEC, 1000);
+		assert_eq!(drain(&mut bucket, TEN_PER_SEC, 1099), 0);
+		assert_eq!(drain(&mut bucket, TEN_PER_SEC, 1100), 1);
+		assert_eq!(drain(&mut bucket, TEN_PER_SEC, 1300), 2);
+	}
+
+	#[test]
+	fn refill_stops_at_burst() {
+		let mut bucket = TokenBucket::new();
+		drain(&mut bucket, TEN_PER_SEC, 1000);
+		assert_eq!(drain(&mut bucket, TEN_PER_SEC, 60_000), 3);
+	}
+
+	#[test]
+	fn clock_going_back_adds_nothing() {
+		let mut bucket = TokenBucket::new();
+		drain(&mut bucket, TEN_PER_SEC, 1000);
+		assert_eq!(bucket.wait_ms(TEN_PER_SEC, 500, 1), Some(100));
+	}
+
+	#[test]
+	fn more_than_burst_never_fits() {
+		let mut bucket = TokenBucket::new();
+		assert_eq!(bucket.wait_ms(TEN_PER_SEC, 0, 4), None);
+		assert_eq!(bucket.wait_ms(TEN_PER_SEC, 0, 3), Some(0));
+		// Burst 0 still takes single events
+		let zero_burst = RateLimit { events_per_sec: 10, burst: 0 };
+		assert_eq!(bucket.wait_ms(zero_burst, 0, 1), Some(0));
+		assert_eq!(bucket.wait_ms(zero_burst, 0, 2), None);
+	}
+
+	#[test]
+	fn unlimited_always_fits() {
+		let mut bucket = TokenBucket::new();
+		assert_eq!(bucket.wait_ms(RateLimit::UNLIMITED, 0, u32::MAX), Some(0));
+		bucket.take(RateLimit::UNLIMITED, u32::MAX);
+		assert_eq!(bucket, TokenBucket::new());
+	}
+
+	#[test]
+	fn take_both_allows_within_limits() {
+		let mut session = TokenBucket::new();
+		let mut global = TokenBucket::new();
+		let limits = limits(TEN_PER_SEC, TEN_PER_SEC);
+		for _ in 0..3 {
+			assert_eq!(take_both(Some(&mut session), &mut global, limits, 0, 1), RateDecision::Allow);
+		}
+		assert_eq!(take_both(Some(&mut session), &mut global, limits, 0, 1), RateDecision::Wait(100));
+		assert_eq!(take_both(None, &mut global, limits, 0, 1), RateDecision::Wait(100));
+	}
+
+	#[test]
+	fn take_both_reports_longer_wait() {
+		let slow = RateLimit { events_per_sec: 1, burst: 1 };
+		let mut session = TokenBucket::new();
+		let mut global = TokenBucket::new();
+		let limits = limits(slow, TEN_PER_SEC);
+		assert_eq!(take_both(Some(&mut session), &mut global, limits, 0, 1), RateDecision::Allow);
+		assert_eq!(take_both(Some(&mut session), &mut global, limits, 0, 1), RateDecision::Wait(1000));
+	}
+
+	#[test]
+	fn refused_session_keeps_global_tokens() {
+		let mut session = TokenBucket::new();
+		let mut global = TokenBucket::new();
+		let limits = limits(RateLimit { events_per_sec: 10, burst: 1 }, TEN_PER_SEC);
+		assert_eq!(take_both(Some(&mut session), &mut global, limits, 0, 1), RateDecision::Allow);
+		assert_eq!(take_both(Some(&mut session), &mut global, limits, 0, 1), RateDecision::Wait(100));
+		assert_eq!(take_both(Some(&mut session), &mut global, limits, 0, 2), RateDecision::Never);
+		// Only the allowed event left the global bucket
+		assert_eq!(drain(&mut global, TEN_PER_SEC, 0), 2);
+	}
+
+	#[test]
+	fn refused_global_keeps_session_tokens() {
+		let mut session = TokenBucket::new();
+		let mut global = TokenBucket::new();
+		let limits = limits(TEN_PER_SEC, RateLimit { events_per_sec: 10, burst: 1 });
+		assert_eq!(take_both(Some(&mut session), &mut global, limits, 0, 1), RateDecision::Allow);
+		assert_eq!(take_both(Some(&mut session), &mut global, limits, 0, 1), RateDecision::Wait(100));
+		assert_eq!(take_both(Some(&mut session), &mut global, limits, 0, 2), RateDecision::Never);
+		assert_eq!(drain(&mut session, TEN_PER_SEC, 0), 2);
+	}
+}
@@ -0,0 +1,132 @@
+// Imports
+use wchar::wchar_t;
+use winapi::shared::ntdef::{
+	HANDLE,
+	UNICODE_STRING,
+	OBJECT_ATTRIBUTES,
+	OBJ_CASE_INSENSITIVE,
+	OBJ_KERNEL_HANDLE,
+	InitializeObjectAttributes
+};
+use winapi::um::winnt::{
+	KEY_READ,
+	REG_DWORD
+};
+use crate::winapi_local::km::wdm::{
+	zeroed_unicode_string,
+	RtlInitUnicodeString,
+	ZwOpenKey,
+	ZwQueryValueKey,
+	ZwClose,
+	KEY_VALUE_INFORMATION_CLASS,
+	KEY_VALUE_PARTIAL_INFORMATION
+};
+use crate::error::{
+	NtResult,
+	NtReason,
+	nt_check
+};
+use crate::config::ConfigSource;
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Max size of one value query, header included. Larger values are reported as missing.
+const VALUE_BUFFER_SIZE: usize = 1024;
+
+// Opened registry key, handle is closed on drop
+pub struct RegistryKey {
+	handle: HANDLE
+}
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl RegistryKey {
+	// Open key relative to root handle (or absolute if root is null)
+	unsafe fn open_relative(root: HANDLE, name: *mut UNICODE_STRING) -> NtResult<RegistryKey> {
+		let mut object_attributes: OBJECT_ATTRIBUTES = core::mem::zeroed();
+		InitializeObjectAttributes(
+			&mut object_attributes,
+			name,
+			OBJ_CASE_INSENSITIVE | OBJ_KERNEL_HANDLE,
+			root,
+			core::ptr::null_mut()
+		);
+		let mut handle: HANDLE = core::ptr::null_mut();
+		let status = ZwOpenKey(&mut handle, KEY_READ, &mut object_attributes);
+		nt_check(status, NtReason::RegistryOpenFailed)?;
+		Ok(RegistryKey {
+			handle
+		})
+	}
+
+	/// Open key by full path, e.g. driver_entry RegistryPath
+	///
+	/// # Safety
+	/// path must point to a valid UNICODE_STRING
+	pub unsafe fn open(path: *const UNICODE_STRING) -> NtResult<RegistryKey> {
+		RegistryKey::open_relative(core::ptr::null_mut(), path as *mut UNICODE_STRING)
+	}
+
+	// Open subkey, name must be NUL terminated
+	pub fn open_subkey(&self, name: &[wchar_t]) -> NtResult<RegistryKey> {
+		unsafe {
+			let mut name_unicode: UNICODE_STRING = zeroed_unicode_string();
+			RtlInitUnicodeString(&mut name_unicode as *mut UNICODE_STRING, name.as_ptr());
+			RegistryKey::open_relative(self.handle, &mut name_unicode)
+		}
+	}
+
+	// Read raw value into buffer, truncated if it does not fit.
+	// Returns value type and full data length. Name must be NUL terminated.
+	pub fn query_value(&self, name: &[wchar_t], buffer: &mut [u8]) -> Option<(u32, usize)> {
+		unsafe {
+			let mut name_unicode: UNICODE_STRING = zeroed_unicode_string();
+			RtlInitUnicodeString(&mut name_unicode as *mut UNICODE_STRING, name.as_ptr());
+
+			// u64 storage keeps header aligned. Larger values fail with
+			// STATUS_BUFFER_OVERFLOW and are reported as missing.
+			let mut storage = [0u64; VALUE_BUFFER_SIZE / 8];
+			let mut result_length: u32 = 0;
+			let status = ZwQueryValueKey(
+				self.handle,
+				&mut name_unicode,
+				KEY_VALUE_INFORMATION_CLASS::KeyValuePartialInformation,
+				storage.as_mut_ptr() as _,
+				VALUE_BUFFER_SIZE as u32,
+				&mut result_length
+			);
+			if status < 0 {
+				return None;
+			}
+
+			let info = &*(storage.as_ptr() as *const KEY_VALUE_PARTIAL_INFORMATION);
+			let data_length = info.DataLength as usize;
+			let copy_length = core::cmp::min(data_length, buffer.len());
+			core::ptr::copy_nonoverlapping(info.Data.as_ptr(), buffer.as_mut_ptr(), copy_length);
+			Some((info.Type, data_length))
+		}
+	}
+
+	// Read REG_DWORD value
+	pub fn query_dword(&self, name: &[wchar_t]) -> Option<u32> {
+		let mut buffer = [0u8; 4];
+		match self.query_value(name, &mut buffer) {
+			Some((REG_DWORD, 4)) => Some(u32::from_le_bytes(buffer)),
+			_ => None
+		}
+	}
+}
+
+impl ConfigSource for RegistryKey {
+	fn query_value(&self, name: &[wchar_t], buffer: &mut [u8]) -> Option<(u32, usize)> {
+		RegistryKey::query_value(self, name, buffer)
+	}
+}
+
+impl Drop for RegistryKey {
+	fn drop(&mut self) {
+		unsafe {
+			ZwClose(self.handle);
+		}
+	}
+}
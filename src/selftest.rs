@@ -0,0 +1,104 @@
+// SELF_TEST probes of the class service callbacks, invisible to the user.
+
+// Imports
+use winapi::shared::ntdef::NTSTATUS;
+use winapi::shared::ntstatus::STATUS_SUCCESS;
+use crate::error::NtResult;
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// ProbeReport failure bits
+// Callback is missing or the call failed
+pub const PROBE_FAILED_CALL: u32 = 0x1;
+// Consumed count differs from expected
+pub const PROBE_FAILED_CONSUMED: u32 = 0x2;
+
+// Result of one callback probe
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct ProbeReport {
+	// 1 if every check passed
+	pub passed: u32,
+	// PROBE_FAILED_* bits
+	pub failures: u32,
+	// Status of the call, checks below are only valid on STATUS_SUCCESS
+	pub status: NTSTATUS,
+	pub consumed: u32,
+	pub expected_consumed: u32
+}
+
+// SELF_TEST reply
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SelfTestReport {
+	// 1 if both probes passed
+	pub passed: u32,
+	pub mouse: ProbeReport,
+	pub keyboard: ProbeReport
+}
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl ProbeReport {
+	// Check probe result and consumed count
+	pub fn evaluate(result: NtResult<u32>, expected_consumed: u32) -> ProbeReport {
+		let mut report = ProbeReport {
+			passed: 0,
+			failures: 0,
+			status: STATUS_SUCCESS,
+			consumed: 0,
+			expected_consumed
+		};
+		match result {
+			Ok(consumed) => {
+				report.consumed = consumed;
+				if consumed != expected_consumed {
+					report.failures |= PROBE_FAILED_CONSUMED;
+				}
+			}
+			Err(error) => {
+				report.status = error.status;
+				report.failures |= PROBE_FAILED_CALL;
+			}
+		}
+		report.passed = (report.failures == 0) as u32;
+		report
+	}
+}
+
+impl SelfTestReport {
+	pub fn new(mouse: ProbeReport, keyboard: ProbeReport) -> SelfTestReport {
+		SelfTestReport {
+			passed: (mouse.passed != 0 && keyboard.passed != 0) as u32,
+			mouse,
+			keyboard
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::error::{NtError, NtReason};
+
+	#[test]
+	fn probe_checks_consumed_count() {
+		let report = ProbeReport::evaluate(Ok(1), 1);
+		assert_eq!(report.passed, 1);
+		assert_eq!(report.failures, 0);
+
+		let report = ProbeReport::evaluate(Ok(0), 1);
+		assert_eq!(report.passed, 0);
+		assert_eq!(report.failures, PROBE_FAILED_CONSUMED);
+		assert_eq!(report.consumed, 0);
+	}
+
+	#[test]
+	fn failed_call_fails_probe() {
+		let error = NtError::from_reason(NtReason::CallbackNotFound);
+		let report = ProbeReport::evaluate(Err(error), 0);
+		assert_eq!(report.failures, PROBE_FAILED_CALL);
+		assert_eq!(report.status, error.status);
+		assert_eq!(SelfTestReport::new(report, ProbeReport::evaluate(Ok(0), 0)).passed, 0);
+	}
+}
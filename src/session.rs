@@ -0,0 +1,296 @@
+// Per-handle client sessions. Pure bookkeeping, injection is done by the caller.
+
+// Imports
+use crate::keyboard::{
+	KEY_BREAK,
+	KEY_E0,
+	KEY_E1
+};
+use crate::mouse::MOUSE_LEFT_BUTTON_DOWN;
+use crate::ratelimit::TokenBucket;
+use crate::auth::ReplayGuard;
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Max number of open handles, further creates fail
+pub const MAX_SESSIONS: usize = 64;
+
+// Tracked keys: 256 make codes, plain and E0 prefixed
+const KEY_SLOTS: usize = 512;
+const KEY_WORDS: usize = KEY_SLOTS / 64;
+
+// Mouse buttons are DOWN/UP flag pairs, left button in the lowest pair
+const MOUSE_BUTTON_COUNT: u16 = 5;
+
+// Injected input as recorded in sessions
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum InputEvent {
+	// Make code with KEY_* flags
+	Key { make_code: u16, flags: u16 },
+	// MOUSE_*_BUTTON_DOWN / _UP flags
+	Buttons(u16)
+}
+
+// Keys and buttons pressed and not yet released
+#[derive(Copy, Clone)]
+pub struct HeldInput {
+	keys: [u64; KEY_WORDS],
+	// Bit n is button n + 1
+	buttons: u16
+}
+
+// Held key to release, flags are KEY_BREAK plus KEY_E0 if needed
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct KeyRelease {
+	pub make_code: u16,
+	pub flags: u16
+}
+
+// Session of one file handle
+#[derive(Copy, Clone)]
+pub struct Session {
+	pub id: SessionId,
+	pub held: HeldInput,
+	// Set on IRP_MJ_CLEANUP, input recorded afterwards must be released at once
+	pub cleaned_up: bool,
+	// Session rate limit state
+	pub mouse_bucket: TokenBucket,
+	pub keyboard_bucket: TokenBucket,
+	// Unique per session, bound into request MACs so requests cannot move between handles
+	pub nonce: u64,
+	pub replay: ReplayGuard,
+	// Process which opened the handle
+	pub process_id: u32,
+	// Set by SET_DRY_RUN, input of the session never reaches the class drivers
+	pub dry_run: bool
+}
+
+// Session slots, found by session id
+pub struct SessionTable {
+	slots: [Option<Session>; MAX_SESSIONS],
+	// Id of the next opened session
+	next_id: u64
+}
+
+// Grows with every opened handle and is never reused while the driver is loaded,
+// so a stale id cannot reach a newer session. Stored in FILE_OBJECT FsContext, 0 means no session.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SessionId(u64);
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl Default for HeldInput {
+	fn default() -> HeldInput {
+		HeldInput::new()
+	}
+}
+
+impl HeldInput {
+	pub const fn new() -> HeldInput {
+		HeldInput {
+			keys: [0; KEY_WORDS],
+			buttons: 0
+		}
+	}
+
+	// Track key after injection. E1 sequences (Pause) have no break and are not tracked.
+	pub fn record_key(&mut self, make_code: u16, flags: u16) {
+		let slot = match key_slot(make_code, flags) {
+			Some(slot) => slot,
+			None => return
+		};
+		if flags & KEY_BREAK != 0 {
+			self.keys[slot / 64] &= !(1u64 << (slot % 64));
+		} else {
+			self.keys[slot / 64] |= 1u64 << (slot % 64);
+		}
+	}
+
+	// Track MOUSE_*_BUTTON_DOWN / _UP flags after injection
+	pub fn record_buttons(&mut self, button_flags: u16) {
+		for button in 0..MOUSE_BUTTON_COUNT {
+			let down = MOUSE_LEFT_BUTTON_DOWN << (button * 2);
+			let up = down << 1;
+			if button_flags & up != 0 {
+				self.buttons &= !(1 << button);
+			} else if button_flags & down != 0 {
+				self.buttons |= 1 << button;
+			}
+		}
+	}
+
+	pub fn record(&mut self, event: InputEvent) {
+		match event {
+			InputEvent::Key { make_code, flags } => self.record_key(make_code, flags),
+			InputEvent::Buttons(button_flags) => self.record_buttons(button_flags)
+		}
+	}
+
+	// Add input held in other
+	pub fn merge(&mut self, other: &HeldInput) {
+		for (word, other_word) in self.keys.iter_mut().zip(other.keys.iter()) {
+			*word |= *other_word;
+		}
+		self.buttons |= other.buttons;
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.buttons == 0 && self.keys.iter().all(|word| *word == 0)
+	}
+
+	// Button flags which release every held button, 0 if none is held
+	pub fn button_release_flags(&self) -> u16 {
+		let mut flags = 0;
+		for button in 0..MOUSE_BUTTON_COUNT {
+			if self.buttons & (1 << button) != 0 {
+				flags |= MOUSE_LEFT_BUTTON_DOWN << (button * 2 + 1);
+			}
+		}
+		flags
+	}
+
+	// Releases of every held key
+	pub fn key_releases(&self) -> impl Iterator<Item = KeyRelease> + '_ {
+		(0..KEY_SLOTS)
+			.filter(move |slot| self.keys[slot / 64] & (1u64 << (slot % 64)) != 0)
+			.map(|slot| KeyRelease {
+				make_code: (slot % 256) as u16,
+				flags: KEY_BREAK | if slot >= 256 { KEY_E0 } else { 0 }
+			})
+	}
+}
+
+impl Session {
+	const fn new(id: SessionId, nonce: u64, process_id: u32) -> Session {
+		Session {
+			id,
+			held: HeldInput::new(),
+			cleaned_up: false,
+			mouse_bucket: TokenBucket::new(),
+			keyboard_bucket: TokenBucket::new(),
+			nonce,
+			replay: ReplayGuard::new(),
+			process_id,
+			dry_run: false
+		}
+	}
+}
+
+impl Default for SessionTable {
+	fn default() -> SessionTable {
+		SessionTable::new()
+	}
+}
+
+impl SessionTable {
+	pub const fn new() -> SessionTable {
+		SessionTable {
+			slots: [None; MAX_SESSIONS],
+			next_id: 1
+		}
+	}
+
+	// Take free slot, None if all are in use
+	pub fn open(&mut self, nonce: u64, process_id: u32) -> Option<SessionId> {
+		let index = self.slots.iter().position(|slot| slot.is_none())?;
+		let id = SessionId(self.next_id);
+		self.next_id += 1;
+		self.slots[index] = Some(Session::new(id, nonce, process_id));
+		Some(id)
+	}
+
+	pub fn close(&mut self, id: SessionId) {
+		if let Some(slot) = self.slots.iter_mut().find(|slot| slot.is_some_and(|session| session.id == id)) {
+			*slot = None;
+		}
+	}
+
+	pub fn get(&mut self, id: SessionId) -> Option<&mut Session> {
+		self.slots.iter_mut().flatten().find(|session| session.id == id)
+	}
+
+	// Clear held input of every session, returns all of it
+	pub fn take_all_held(&mut self) -> HeldInput {
+		let mut all = HeldInput::new();
+		for session in self.slots.iter_mut().flatten() {
+			all.merge(&session.held);
+			session.held = HeldInput::new();
+		}
+		all
+	}
+
+	// Open sessions
+	pub fn count(&self) -> usize {
+		self.slots.iter().filter(|slot| slot.is_some()).count()
+	}
+}
+
+impl SessionId {
+	pub fn value(self) -> u64 {
+		self.0
+	}
+
+	// FsContext value for this session
+	pub fn to_context(self) -> usize {
+		self.0 as usize
+	}
+
+	// Session from FsContext value, None if it holds no session
+	pub fn from_context(context: usize) -> Option<SessionId> {
+		if context == 0 {
+			None
+		} else {
+			Some(SessionId(context as u64))
+		}
+	}
+}
+
+// PRIVATE FUNCTIONS ==========================================
+
+fn key_slot(make_code: u16, flags: u16) -> Option<usize> {
+	if make_code > 0xFF || flags & KEY_E1 != 0 {
+		return None;
+	}
+	let extended = if flags & KEY_E0 != 0 { 256 } else { 0 };
+	Some(extended + make_code as usize)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ids_are_not_reused() {
+		let mut table = SessionTable::new();
+		let first = table.open(1, 100).unwrap();
+		table.close(first);
+		let second = table.open(2, 100).unwrap();
+		assert_ne!(first, second);
+		assert!(second.value() > first.value());
+		// Stale id does not reach the session which took over its slot
+		assert!(table.get(first).is_none());
+		assert_eq!(table.get(second).unwrap().nonce, 2);
+		table.close(first);
+		assert_eq!(table.count(), 1);
+	}
+
+	#[test]
+	fn context_round_trips() {
+		let mut table = SessionTable::new();
+		let id = table.open(1, 100).unwrap();
+		assert_ne!(id.to_context(), 0);
+		assert_eq!(SessionId::from_context(id.to_context()), Some(id));
+		assert_eq!(SessionId::from_context(0), None);
+	}
+
+	#[test]
+	fn table_is_bounded() {
+		let mut table = SessionTable::new();
+		let ids: Vec<SessionId> = (0..MAX_SESSIONS).map(|nonce| table.open(nonce as u64, 100).unwrap()).collect();
+		assert!(table.open(0, 100).is_none());
+		table.close(ids[3]);
+		let id = table.open(0, 100).unwrap();
+		assert_eq!(id.value(), MAX_SESSIONS as u64 + 1);
+		assert_eq!(table.count(), MAX_SESSIONS);
+	}
+}
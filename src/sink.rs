@@ -0,0 +1,181 @@
+// Input sinks, the backends injection paths deliver events to. The class
+// sink calls the discovered MouClass/KbdClass service callbacks, the
+// loopback sink queues events inside the driver and the null sink drops them.
+
+// Imports
+use winapi::shared::ntdef::ULONG;
+use crate::sync::SpinLock;
+use crate::stats;
+use crate::error::NtResult;
+use crate::mouse::{
+	mouse_event,
+	MOUSE_INPUT_DATA,
+	PMOUSE_OBJECT,
+	MOUSE_MOVE_ABSOLUTE
+};
+use crate::keyboard::{
+	kbd_event,
+	KEYBOARD_INPUT_DATA,
+	PKBD_OBJECT,
+	KEY_BREAK
+};
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Events held by a loopback queue per device
+pub const LOOPBACK_CAPACITY: usize = 256;
+
+// Sink of a device, chosen by registry
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum SinkKind {
+	// Class service callback, real input
+	Class = 0,
+	// Driver queue
+	Loopback = 1,
+	// Events are consumed and dropped
+	Null = 2
+}
+
+// Backend injection paths deliver to. Returns events consumed, 0 if the
+// sink had no room for the event.
+pub trait InputSink {
+	/// # Safety
+	/// IRQL <= DISPATCH_LEVEL, class objects of the sink must be discovered or null
+	unsafe fn mouse(&self, data: &MOUSE_INPUT_DATA) -> NtResult<ULONG>;
+	/// # Safety
+	/// Same as mouse
+	unsafe fn keyboard(&self, data: &KEYBOARD_INPUT_DATA) -> NtResult<ULONG>;
+}
+
+// Discovered class service callbacks
+pub struct ClassSink {
+	pub mouse_object: PMOUSE_OBJECT,
+	pub kbd_object: PKBD_OBJECT
+}
+
+// Bounded FIFO of events, full queue takes nothing.
+// Single-consumer and global: one queue per device holds the events of every
+// session untagged, and whoever reads drains them all. Loopback is a test sink
+// for one reading client per device, not a per-session channel.
+pub struct LoopbackQueue<T: Copy> {
+	events: [T; LOOPBACK_CAPACITY],
+	// Slot of the oldest event
+	head: usize,
+	count: usize
+}
+
+// Queues of both devices
+pub struct LoopbackSink {
+	pub mouse: &'static SpinLock<LoopbackQueue<MOUSE_INPUT_DATA>>,
+	pub keyboard: &'static SpinLock<LoopbackQueue<KEYBOARD_INPUT_DATA>>
+}
+
+pub struct NullSink;
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl SinkKind {
+	pub fn from_u32(value: u32) -> SinkKind {
+		match value {
+			0 => SinkKind::Class,
+			1 => SinkKind::Loopback,
+			_ => SinkKind::Null
+		}
+	}
+
+	pub fn name(self) -> &'static str {
+		match self {
+			SinkKind::Class => "class",
+			SinkKind::Loopback => "loopback",
+			SinkKind::Null => "null"
+		}
+	}
+}
+
+impl InputSink for ClassSink {
+	unsafe fn mouse(&self, data: &MOUSE_INPUT_DATA) -> NtResult<ULONG> {
+		mouse_event(self.mouse_object, data.LastX, data.LastY, data.ButtonFlags, data.Flags, data.ExtraInformation)
+	}
+
+	unsafe fn keyboard(&self, data: &KEYBOARD_INPUT_DATA) -> NtResult<ULONG> {
+		kbd_event(self.kbd_object, data.MakeCode, data.Flags, data.ExtraInformation)
+	}
+}
+
+impl<T: Copy> LoopbackQueue<T> {
+	// Queue of empty slots
+	pub const fn new(empty: T) -> LoopbackQueue<T> {
+		LoopbackQueue {
+			events: [empty; LOOPBACK_CAPACITY],
+			head: 0,
+			count: 0
+		}
+	}
+
+	// Append event, false if the queue is full
+	pub fn push(&mut self, event: T) -> bool {
+		if self.count == LOOPBACK_CAPACITY {
+			return false;
+		}
+		self.events[(self.head + self.count) % LOOPBACK_CAPACITY] = event;
+		self.count += 1;
+		true
+	}
+
+	pub fn len(&self) -> usize {
+		self.count
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.count == 0
+	}
+
+	// Move oldest events into out, returns number moved
+	pub fn take(&mut self, out: &mut [T]) -> usize {
+		let taken = self.count.min(out.len());
+		for (index, event) in out[..taken].iter_mut().enumerate() {
+			*event = self.events[(self.head + index) % LOOPBACK_CAPACITY];
+		}
+		self.head = (self.head + taken) % LOOPBACK_CAPACITY;
+		self.count -= taken;
+		taken
+	}
+
+	pub fn clear(&mut self) {
+		self.head = 0;
+		self.count = 0;
+	}
+}
+
+impl InputSink for LoopbackSink {
+	unsafe fn mouse(&self, data: &MOUSE_INPUT_DATA) -> NtResult<ULONG> {
+		let consumed = self.mouse.lock().push(*data) as ULONG;
+		stats::mouse_batch(mouse_moved(data), data.ButtonFlags != 0, consumed, 0);
+		Ok(consumed)
+	}
+
+	unsafe fn keyboard(&self, data: &KEYBOARD_INPUT_DATA) -> NtResult<ULONG> {
+		let consumed = self.keyboard.lock().push(*data) as ULONG;
+		stats::keyboard_batch(data.Flags & KEY_BREAK != 0, consumed, 0);
+		Ok(consumed)
+	}
+}
+
+impl InputSink for NullSink {
+	unsafe fn mouse(&self, data: &MOUSE_INPUT_DATA) -> NtResult<ULONG> {
+		stats::mouse_batch(mouse_moved(data), data.ButtonFlags != 0, 1, 0);
+		Ok(1)
+	}
+
+	unsafe fn keyboard(&self, data: &KEYBOARD_INPUT_DATA) -> NtResult<ULONG> {
+		stats::keyboard_batch(data.Flags & KEY_BREAK != 0, 1, 0);
+		Ok(1)
+	}
+}
+
+// PRIVATE FUNCTIONS ==========================================
+
+fn mouse_moved(data: &MOUSE_INPUT_DATA) -> bool {
+	data.Flags & MOUSE_MOVE_ABSOLUTE != 0 || data.LastX != 0 || data.LastY != 0
+}
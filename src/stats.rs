@@ -0,0 +1,249 @@
+// Injection, rejection, discovery and latency counters returned by STATS.
+
+// Imports
+use crate::sync::SpinLock;
+use crate::tracelog::Device;
+use crate::error::{NtReason, REASON_COUNT};
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Latency buckets: 0 is below 1 us, bucket n covers [2^(n-1), 2^n) us,
+// the last one everything from 2^(LATENCY_BUCKETS-2) us up
+pub const LATENCY_BUCKETS: usize = 18;
+
+// Counters since load or last reset, STATS reply
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Stats {
+	// clock::now_ms of load or last reset
+	pub since_ms: u64,
+	// Events by device and type. A mouse event may both move and press.
+	pub mouse_moves: u64,
+	pub mouse_button_events: u64,
+	pub key_makes: u64,
+	pub key_breaks: u64,
+	// Batches handed to input sinks, in Device order
+	pub batches: [u64; 2],
+	// Batches the callback did not consume completely
+	pub partial_batches: [u64; 2],
+	// Unconsumed events sent again, and events given up on
+	pub retried_events: [u64; 2],
+	pub undelivered_events: [u64; 2],
+	// Dry-run events, counted by type above but never handed to a callback
+	pub dry_run_events: [u64; 2],
+	// Failed requests by NtReason
+	pub rejected: [u64; REASON_COUNT],
+	// Class service callback lookups, in Device order
+	pub discovery_attempts: [u64; 2],
+	pub discovery_failures: [u64; 2],
+	// Callback latency per batch, 0 for driver sinks
+	pub latency_histogram: [u64; LATENCY_BUCKETS],
+	pub latency_total_us: u64,
+	pub latency_max_us: u64
+}
+
+static STATS: SpinLock<Stats> = SpinLock::new(Stats::new(0));
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl Stats {
+	pub const fn new(since_ms: u64) -> Stats {
+		Stats {
+			since_ms,
+			mouse_moves: 0,
+			mouse_button_events: 0,
+			key_makes: 0,
+			key_breaks: 0,
+			batches: [0; 2],
+			partial_batches: [0; 2],
+			retried_events: [0; 2],
+			undelivered_events: [0; 2],
+			dry_run_events: [0; 2],
+			rejected: [0; REASON_COUNT],
+			discovery_attempts: [0; 2],
+			discovery_failures: [0; 2],
+			latency_histogram: [0; LATENCY_BUCKETS],
+			latency_total_us: 0,
+			latency_max_us: 0
+		}
+	}
+
+	// Batch of submitted events, consumed of them taken by the callback
+	pub fn count_batch(&mut self, device: Device, submitted: u32, consumed: u32, latency_us: u64) {
+		self.batches[device as usize] += 1;
+		if consumed < submitted {
+			self.partial_batches[device as usize] += 1;
+		}
+		self.latency_histogram[latency_bucket(latency_us)] += 1;
+		self.latency_total_us += latency_us;
+		self.latency_max_us = self.latency_max_us.max(latency_us);
+	}
+
+	pub fn count_rejected(&mut self, reason: NtReason) {
+		self.rejected[(reason as usize).min(REASON_COUNT - 1)] += 1;
+	}
+
+	pub fn count_discovery(&mut self, device: Device, found: bool) {
+		self.discovery_attempts[device as usize] += 1;
+		if !found {
+			self.discovery_failures[device as usize] += 1;
+		}
+	}
+
+	// Current counters, optionally starting over at now_ms
+	pub fn take(&mut self, reset: bool, now_ms: u64) -> Stats {
+		let current = *self;
+		if reset {
+			*self = Stats::new(now_ms);
+		}
+		current
+	}
+}
+
+// Histogram bucket of latency
+pub fn latency_bucket(latency_us: u64) -> usize {
+	let bits = (64 - latency_us.leading_zeros()) as usize;
+	bits.min(LATENCY_BUCKETS - 1)
+}
+
+// One mouse event passed to MouClass
+pub fn mouse_batch(moved: bool, buttons: bool, consumed: u32, latency_us: u64) {
+	let mut stats = STATS.lock();
+	if moved {
+		stats.mouse_moves += 1;
+	}
+	if buttons {
+		stats.mouse_button_events += 1;
+	}
+	stats.count_batch(Device::Mouse, 1, consumed, latency_us);
+}
+
+// One keyboard event passed to KbdClass
+pub fn keyboard_batch(key_break: bool, consumed: u32, latency_us: u64) {
+	let mut stats = STATS.lock();
+	if key_break {
+		stats.key_breaks += 1;
+	} else {
+		stats.key_makes += 1;
+	}
+	stats.count_batch(Device::Keyboard, 1, consumed, latency_us);
+}
+
+// Dry-run mouse event, counted like an injected one without a batch
+pub fn mouse_dry_run(moved: bool, buttons: bool) {
+	let mut stats = STATS.lock();
+	if moved {
+		stats.mouse_moves += 1;
+	}
+	if buttons {
+		stats.mouse_button_events += 1;
+	}
+	stats.dry_run_events[Device::Mouse as usize] += 1;
+}
+
+// Dry-run keyboard event, counted like an injected one without a batch
+pub fn keyboard_dry_run(key_break: bool) {
+	let mut stats = STATS.lock();
+	if key_break {
+		stats.key_breaks += 1;
+	} else {
+		stats.key_makes += 1;
+	}
+	stats.dry_run_events[Device::Keyboard as usize] += 1;
+}
+
+// Unconsumed event is sent again
+pub fn retried(device: Device) {
+	STATS.lock().retried_events[device as usize] += 1;
+}
+
+// Unconsumed event is given up on
+pub fn undelivered(device: Device) {
+	STATS.lock().undelivered_events[device as usize] += 1;
+}
+
+pub fn rejected(reason: NtReason) {
+	STATS.lock().count_rejected(reason);
+}
+
+pub fn discovery(device: Device, found: bool) {
+	STATS.lock().count_discovery(device, found);
+}
+
+// Current counters, optionally starting over at now_ms
+pub fn snapshot(reset: bool, now_ms: u64) -> Stats {
+	STATS.lock().take(reset, now_ms)
+}
+
+// Counting starts at load
+pub fn start(now_ms: u64) {
+	*STATS.lock() = Stats::new(now_ms);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn latency_bucket_edges() {
+		assert_eq!(latency_bucket(0), 0);
+		assert_eq!(latency_bucket(1), 1);
+		assert_eq!(latency_bucket(2), 2);
+		assert_eq!(latency_bucket(3), 2);
+		assert_eq!(latency_bucket(4), 3);
+		// Last bucket starts at 2^(LATENCY_BUCKETS-2) us
+		assert_eq!(latency_bucket((1 << (LATENCY_BUCKETS - 2)) - 1), LATENCY_BUCKETS - 2);
+		assert_eq!(latency_bucket(1 << 16), LATENCY_BUCKETS - 1);
+		assert_eq!(latency_bucket(1 << 20), LATENCY_BUCKETS - 1);
+		assert_eq!(latency_bucket(u64::MAX), LATENCY_BUCKETS - 1);
+	}
+
+	#[test]
+	fn batches_are_counted_by_device() {
+		let mut stats = Stats::new(0);
+		stats.count_batch(Device::Mouse, 1, 1, 0);
+		stats.count_batch(Device::Keyboard, 1, 0, 5);
+		stats.count_batch(Device::Keyboard, 2, 2, 100);
+		assert_eq!(stats.batches, [1, 2]);
+		assert_eq!(stats.partial_batches, [0, 1]);
+		assert_eq!(stats.latency_histogram[0], 1);
+		assert_eq!(stats.latency_histogram[latency_bucket(5)], 1);
+		assert_eq!(stats.latency_histogram[latency_bucket(100)], 1);
+		assert_eq!(stats.latency_total_us, 105);
+		assert_eq!(stats.latency_max_us, 100);
+	}
+
+	#[test]
+	fn rejections_and_discovery_are_counted() {
+		let mut stats = Stats::new(0);
+		stats.count_rejected(NtReason::AuthFailed);
+		stats.count_rejected(NtReason::AuthFailed);
+		stats.count_discovery(Device::Mouse, true);
+		stats.count_discovery(Device::Keyboard, false);
+		assert_eq!(stats.rejected[NtReason::AuthFailed as usize], 2);
+		assert_eq!(stats.discovery_attempts, [1, 1]);
+		assert_eq!(stats.discovery_failures, [0, 1]);
+	}
+
+	#[test]
+	fn take_with_reset_starts_over() {
+		let mut stats = Stats::new(10);
+		stats.count_batch(Device::Mouse, 1, 1, 3);
+		stats.key_makes = 4;
+
+		let current = stats.take(false, 20);
+		assert_eq!(current.since_ms, 10);
+		assert_eq!(current.key_makes, 4);
+		assert_eq!(stats.batches, [1, 0]);
+
+		let current = stats.take(true, 30);
+		assert_eq!(current.since_ms, 10);
+		assert_eq!(current.batches, [1, 0]);
+		assert_eq!(current.latency_total_us, 3);
+		assert_eq!(stats.since_ms, 30);
+		assert_eq!(stats.key_makes, 0);
+		assert_eq!(stats.batches, [0, 0]);
+		assert_eq!(stats.latency_histogram, [0; LATENCY_BUCKETS]);
+		assert_eq!(stats.latency_max_us, 0);
+	}
+}
@@ -0,0 +1,138 @@
+// Imports
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use winapi::shared::ntdef::{KIRQL, FALSE};
+use winapi::km::wdm::KPROCESSOR_MODE;
+use crate::winapi_local::km::wdm::{
+	KeAcquireSpinLockRaiseToDpc,
+	KeReleaseSpinLock,
+	KeInitializeMutex,
+	KeWaitForSingleObject,
+	KeReleaseMutex,
+	KSPIN_LOCK,
+	KMUTEX,
+	KWAIT_REASON
+};
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Data guarded by executive spin lock. Holders run at DISPATCH_LEVEL,
+// so nothing pageable may be touched and no blocking call may be made.
+pub struct SpinLock<T> {
+	lock: UnsafeCell<KSPIN_LOCK>,
+	data: UnsafeCell<T>
+}
+
+// Held lock, released with previous IRQL restored on drop
+pub struct SpinLockGuard<'a, T> {
+	owner: &'a SpinLock<T>,
+	old_irql: KIRQL
+}
+
+// Data guarded by dispatcher mutex. Holders stay at PASSIVE_LEVEL and may
+// block, e.g. in object manager calls. Must be initialized before first use.
+pub struct Mutex<T> {
+	mutex: UnsafeCell<KMUTEX>,
+	data: UnsafeCell<T>
+}
+
+// Held mutex, released on drop
+pub struct MutexGuard<'a, T> {
+	owner: &'a Mutex<T>
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl<T> SpinLock<T> {
+	// Zero is the released state, so no KeInitializeSpinLock is needed
+	pub const fn new(data: T) -> SpinLock<T> {
+		SpinLock {
+			lock: UnsafeCell::new(0),
+			data: UnsafeCell::new(data)
+		}
+	}
+
+	// Acquire lock, IRQL must be <= DISPATCH_LEVEL
+	pub fn lock(&self) -> SpinLockGuard<'_, T> {
+		let old_irql = unsafe { KeAcquireSpinLockRaiseToDpc(self.lock.get()) };
+		SpinLockGuard {
+			owner: self,
+			old_irql
+		}
+	}
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		unsafe { &*self.owner.data.get() }
+	}
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		unsafe { &mut *self.owner.data.get() }
+	}
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+	fn drop(&mut self) {
+		unsafe {
+			KeReleaseSpinLock(self.owner.lock.get(), self.old_irql);
+		}
+	}
+}
+
+impl<T> Mutex<T> {
+	pub const fn new(data: T) -> Mutex<T> {
+		Mutex {
+			mutex: UnsafeCell::new(KMUTEX::zeroed()),
+			data: UnsafeCell::new(data)
+		}
+	}
+
+	/// Initialize mutex in released state, once before any lock
+	///
+	/// # Safety
+	/// Call once, before any lock and while no other thread uses the mutex
+	pub unsafe fn init(&self) {
+		KeInitializeMutex(self.mutex.get(), 0);
+	}
+
+	// Acquire mutex, IRQL must be PASSIVE_LEVEL
+	pub fn lock(&self) -> MutexGuard<'_, T> {
+		unsafe {
+			KeWaitForSingleObject(self.mutex.get() as _, KWAIT_REASON::Executive, KPROCESSOR_MODE::KernelMode,
+				FALSE, core::ptr::null());
+		}
+		MutexGuard {
+			owner: self
+		}
+	}
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		unsafe { &*self.owner.data.get() }
+	}
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		unsafe { &mut *self.owner.data.get() }
+	}
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+	fn drop(&mut self) {
+		unsafe {
+			KeReleaseMutex(self.owner.mutex.get(), FALSE);
+		}
+	}
+}
@@ -0,0 +1,367 @@
+// ETW TraceLogging event schema and encoding.
+// Pure code, no kernel calls: etw.rs sends the encoded buffers.
+
+// Imports
+use winapi::shared::ntdef::NTSTATUS;
+use winapi::shared::guiddef::GUID;
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// Provider name and GUID. GUID is the standard TraceLogging hash of the name,
+// so tools can also enable the provider as "*MetaDriver".
+pub const PROVIDER_NAME: &str = "MetaDriver";
+pub const PROVIDER_GUID: GUID = GUID {
+	Data1: 0xa8566d8b,
+	Data2: 0xb11b,
+	Data3: 0x5232,
+	Data4: [0x6a, 0x26, 0x14, 0x17, 0x56, 0x9e, 0xb0, 0xeb]
+};
+
+// Event channel which marks TraceLogging events
+pub const TRACELOGGING_CHANNEL: u8 = 11;
+
+// Event levels (winmeta.xml)
+pub const LEVEL_ERROR: u8 = 2;
+pub const LEVEL_WARNING: u8 = 3;
+pub const LEVEL_INFO: u8 = 4;
+pub const LEVEL_VERBOSE: u8 = 5;
+
+// Event keywords
+pub const KEYWORD_LIFECYCLE: u64 = 0x1;
+pub const KEYWORD_DISCOVERY: u64 = 0x2;
+pub const KEYWORD_INJECTION: u64 = 0x4;
+pub const KEYWORD_REJECTED: u64 = 0x8;
+
+// Field input types (TraceLoggingProvider.h TlgIn*)
+const IN_ANSISTRING: u8 = 2;
+const IN_UINT32: u8 = 8;
+const IN_INT32: u8 = 7;
+const IN_UINT64: u8 = 10;
+const IN_BOOL32: u8 = 13;
+const IN_HEXINT32: u8 = 20;
+
+// Field output types (TlgOut*), encoded after input type with 0x80 chain flag
+const OUT_NTSTATUS: u8 = 14;
+const CHAIN_FLAG: u8 = 0x80;
+
+// Encoded buffer sizes. Events which do not fit are dropped.
+const PROVIDER_METADATA_SIZE: usize = 2 + PROVIDER_NAME.len() + 1;
+const EVENT_METADATA_SIZE: usize = 192;
+const EVENT_DATA_SIZE: usize = 128;
+
+// Injection target device
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Device {
+	Mouse,
+	Keyboard
+}
+
+// Provider traits blob: u16 size, provider name
+pub struct ProviderMetadata {
+	buf: [u8; PROVIDER_METADATA_SIZE]
+}
+
+// Encoded event: metadata blob plus payload
+pub struct EventBuilder {
+	level: u8,
+	keyword: u64,
+	meta: [u8; EVENT_METADATA_SIZE],
+	meta_len: usize,
+	data: [u8; EVENT_DATA_SIZE],
+	data_len: usize,
+	overflow: bool
+}
+
+// PUBLIC FUNCTIONS ==========================================
+
+impl Device {
+	pub fn name(self) -> &'static str {
+		match self {
+			Device::Mouse => "mouse",
+			Device::Keyboard => "keyboard"
+		}
+	}
+}
+
+impl Default for ProviderMetadata {
+	fn default() -> ProviderMetadata {
+		ProviderMetadata::new()
+	}
+}
+
+impl ProviderMetadata {
+	pub const fn new() -> ProviderMetadata {
+		let mut buf = [0u8; PROVIDER_METADATA_SIZE];
+		let size = (PROVIDER_METADATA_SIZE as u16).to_le_bytes();
+		buf[0] = size[0];
+		buf[1] = size[1];
+		let name = PROVIDER_NAME.as_bytes();
+		let mut idx = 0;
+		while idx < name.len() {
+			buf[2 + idx] = name[idx];
+			idx += 1;
+		}
+		ProviderMetadata {
+			buf
+		}
+	}
+
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.buf
+	}
+}
+
+impl EventBuilder {
+	// Start event: metadata size placeholder, no tags, event name
+	pub fn new(name: &str, level: u8, keyword: u64) -> EventBuilder {
+		let mut event = EventBuilder {
+			level,
+			keyword,
+			meta: [0u8; EVENT_METADATA_SIZE],
+			meta_len: 0,
+			data: [0u8; EVENT_DATA_SIZE],
+			data_len: 0,
+			overflow: false
+		};
+		event.put_meta(&[0, 0, 0]);
+		event.put_meta_str(name);
+		event
+	}
+
+	pub fn level(&self) -> u8 {
+		self.level
+	}
+
+	pub fn keyword(&self) -> u64 {
+		self.keyword
+	}
+
+	// Encoded metadata and payload, None if any field did not fit
+	pub fn encoded(&mut self) -> Option<(&[u8], &[u8])> {
+		if self.overflow {
+			return None;
+		}
+		let size = (self.meta_len as u16).to_le_bytes();
+		self.meta[0] = size[0];
+		self.meta[1] = size[1];
+		Some((&self.meta[..self.meta_len], &self.data[..self.data_len]))
+	}
+
+	pub fn u32(mut self, name: &str, value: u32) -> EventBuilder {
+		self.put_field(name, &[IN_UINT32]);
+		self.put_data(&value.to_le_bytes());
+		self
+	}
+
+	pub fn i32(mut self, name: &str, value: i32) -> EventBuilder {
+		self.put_field(name, &[IN_INT32]);
+		self.put_data(&value.to_le_bytes());
+		self
+	}
+
+	pub fn u64(mut self, name: &str, value: u64) -> EventBuilder {
+		self.put_field(name, &[IN_UINT64]);
+		self.put_data(&value.to_le_bytes());
+		self
+	}
+
+	pub fn hex32(mut self, name: &str, value: u32) -> EventBuilder {
+		self.put_field(name, &[IN_HEXINT32]);
+		self.put_data(&value.to_le_bytes());
+		self
+	}
+
+	pub fn bool32(mut self, name: &str, value: bool) -> EventBuilder {
+		self.put_field(name, &[IN_BOOL32]);
+		self.put_data(&(value as u32).to_le_bytes());
+		self
+	}
+
+	// NTSTATUS: int32 shown by decoders as status name
+	pub fn status(mut self, name: &str, value: NTSTATUS) -> EventBuilder {
+		self.put_field(name, &[IN_INT32 | CHAIN_FLAG, OUT_NTSTATUS]);
+		self.put_data(&value.to_le_bytes());
+		self
+	}
+
+	// NUL terminated ANSI string
+	pub fn str(mut self, name: &str, value: &str) -> EventBuilder {
+		self.put_field(name, &[IN_ANSISTRING]);
+		self.put_data(value.as_bytes());
+		self.put_data(&[0]);
+		self
+	}
+
+	fn put_field(&mut self, name: &str, types: &[u8]) {
+		self.put_meta_str(name);
+		self.put_meta(types);
+	}
+
+	fn put_meta_str(&mut self, value: &str) {
+		self.put_meta(value.as_bytes());
+		self.put_meta(&[0]);
+	}
+
+	fn put_meta(&mut self, bytes: &[u8]) {
+		if self.meta_len + bytes.len() > EVENT_METADATA_SIZE {
+			self.overflow = true;
+			return;
+		}
+		self.meta[self.meta_len..self.meta_len + bytes.len()].copy_from_slice(bytes);
+		self.meta_len += bytes.len();
+	}
+
+	fn put_data(&mut self, bytes: &[u8]) {
+		if self.data_len + bytes.len() > EVENT_DATA_SIZE {
+			self.overflow = true;
+			return;
+		}
+		self.data[self.data_len..self.data_len + bytes.len()].copy_from_slice(bytes);
+		self.data_len += bytes.len();
+	}
+}
+
+// EVENT SCHEMA ==========================================
+
+// Driver loaded and initialized
+pub fn driver_load(status: NTSTATUS) -> EventBuilder {
+	EventBuilder::new("DriverLoad", LEVEL_INFO, KEYWORD_LIFECYCLE)
+		.status("Status", status)
+		.str("Version", env!("CARGO_PKG_VERSION"))
+}
+
+// Driver unloading
+pub fn driver_unload() -> EventBuilder {
+	EventBuilder::new("DriverUnload", LEVEL_INFO, KEYWORD_LIFECYCLE)
+}
+
+// Class service callback discovery result
+pub fn discovery(device: Device, status: NTSTATUS, reason: u32, callback_found: bool) -> EventBuilder {
+	let level = if status < 0 { LEVEL_ERROR } else { LEVEL_INFO };
+	EventBuilder::new("Discovery", level, KEYWORD_DISCOVERY)
+		.str("Device", device.name())
+		.status("Status", status)
+		.u32("Reason", reason)
+		.bool32("CallbackFound", callback_found)
+}
+
+// One batch handed to a class service callback
+pub fn injection(device: Device, event_count: u32, consumed_count: u32, latency_us: u64) -> EventBuilder {
+	EventBuilder::new("Injection", LEVEL_VERBOSE, KEYWORD_INJECTION)
+		.str("Device", device.name())
+		.u32("EventCount", event_count)
+		.u32("ConsumedCount", consumed_count)
+		.u64("LatencyUs", latency_us)
+}
+
+// Request failed or was refused
+pub fn rejected(io_control_code: u32, status: NTSTATUS, reason: u32) -> EventBuilder {
+	EventBuilder::new("Rejected", LEVEL_WARNING, KEYWORD_REJECTED)
+		.hex32("IoControlCode", io_control_code)
+		.status("Status", status)
+		.u32("Reason", reason)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Metadata blob of event name plus (field name, type bytes)
+	fn metadata(name: &str, fields: &[(&str, &[u8])]) -> Vec<u8> {
+		let mut meta = vec![0, 0, 0];
+		meta.extend_from_slice(name.as_bytes());
+		meta.push(0);
+		for (field, types) in fields {
+			meta.extend_from_slice(field.as_bytes());
+			meta.push(0);
+			meta.extend_from_slice(types);
+		}
+		let size = (meta.len() as u16).to_le_bytes();
+		meta[0] = size[0];
+		meta[1] = size[1];
+		meta
+	}
+
+	#[test]
+	fn provider_traits_are_size_and_name() {
+		let mut expected = vec![13, 0];
+		expected.extend_from_slice(b"MetaDriver\0");
+		assert_eq!(ProviderMetadata::new().as_bytes(), &expected[..]);
+	}
+
+	#[test]
+	fn event_without_fields() {
+		let mut event = driver_unload();
+		let (meta, data) = event.encoded().unwrap();
+		assert_eq!(meta, &b"\x10\x00\x00DriverUnload\0"[..]);
+		assert!(data.is_empty());
+		assert_eq!(event.level(), LEVEL_INFO);
+		assert_eq!(event.keyword(), KEYWORD_LIFECYCLE);
+	}
+
+	#[test]
+	fn field_type_codes() {
+		let mut event = EventBuilder::new("Types", LEVEL_VERBOSE, KEYWORD_INJECTION)
+			.u32("U32", 1)
+			.i32("I32", -1)
+			.u64("U64", 2)
+			.hex32("Hex", 3)
+			.bool32("Bool", true)
+			.status("Status", -1073741790);
+		let (meta, data) = event.encoded().unwrap();
+		assert_eq!(meta, &metadata("Types", &[
+			("U32", &[8]),
+			("I32", &[7]),
+			("U64", &[10]),
+			("Hex", &[20]),
+			("Bool", &[13]),
+			("Status", &[0x87, 14])
+		])[..]);
+		let mut expected = Vec::new();
+		expected.extend_from_slice(&1u32.to_le_bytes());
+		expected.extend_from_slice(&(-1i32).to_le_bytes());
+		expected.extend_from_slice(&2u64.to_le_bytes());
+		expected.extend_from_slice(&3u32.to_le_bytes());
+		expected.extend_from_slice(&1u32.to_le_bytes());
+		expected.extend_from_slice(&0xC0000022u32.to_le_bytes());
+		assert_eq!(data, &expected[..]);
+	}
+
+	#[test]
+	fn strings_are_nul_terminated() {
+		let mut event = EventBuilder::new("Str", LEVEL_INFO, KEYWORD_DISCOVERY)
+			.str("Device", "mouse")
+			.str("Empty", "");
+		let (meta, data) = event.encoded().unwrap();
+		assert_eq!(meta, &metadata("Str", &[("Device", &[2]), ("Empty", &[2])])[..]);
+		assert_eq!(data, &b"mouse\0\0"[..]);
+	}
+
+	#[test]
+	fn rejected_event_layout() {
+		let mut event = rejected(0x0022E004, -1073741790, 19);
+		assert_eq!(event.level(), LEVEL_WARNING);
+		assert_eq!(event.keyword(), KEYWORD_REJECTED);
+		let (meta, data) = event.encoded().unwrap();
+		assert_eq!(meta, &metadata("Rejected", &[
+			("IoControlCode", &[20]),
+			("Status", &[0x87, 14]),
+			("Reason", &[8])
+		])[..]);
+		assert_eq!(data, &[0x04, 0xE0, 0x22, 0x00, 0x22, 0x00, 0x00, 0xC0, 19, 0, 0, 0][..]);
+	}
+
+	#[test]
+	fn discovery_level_follows_status() {
+		assert_eq!(discovery(Device::Mouse, 0, 0, true).level(), LEVEL_INFO);
+		assert_eq!(discovery(Device::Keyboard, -1073741275, 3, false).level(), LEVEL_ERROR);
+	}
+
+	#[test]
+	fn oversized_event_is_dropped() {
+		let long = core::str::from_utf8(&[b'x'; EVENT_DATA_SIZE]).unwrap();
+		assert!(EventBuilder::new("Big", LEVEL_INFO, 0).str("Value", long).encoded().is_none());
+		let long = core::str::from_utf8(&[b'x'; EVENT_METADATA_SIZE]).unwrap();
+		assert!(EventBuilder::new(long, LEVEL_INFO, 0).encoded().is_none());
+	}
+}
@@ -0,0 +1,60 @@
+// Watchdog timer. One kernel timer is armed to the earliest hold or auto-disarm
+// deadline, its DPC releases expired holds or disarms and arms the timer again.
+
+// Imports
+use crate::winapi_local::km::wdm::{
+	KeInitializeTimer,
+	KeInitializeDpc,
+	KeSetTimer,
+	KeCancelTimer,
+	KeFlushQueuedDpcs,
+	KTIMER,
+	KDPC,
+	PKDEFERRED_ROUTINE
+};
+use core::ptr::addr_of_mut;
+use crate::clock;
+
+// DATA TYPES, CONSTANTS, STRUCTS etc... ================================================
+
+// 100 ns units per millisecond
+const TICKS_PER_MS: i64 = 10_000;
+
+static mut WATCHDOG_TIMER: KTIMER = KTIMER::zeroed();
+static mut WATCHDOG_DPC: KDPC = KDPC::zeroed();
+
+// PUBLIC FUNCTIONS ==========================================
+
+/// Initialize timer, routine runs at DISPATCH_LEVEL when it fires
+///
+/// # Safety
+/// Call once at load, before schedule or stop
+pub unsafe fn init(routine: PKDEFERRED_ROUTINE) {
+	KeInitializeTimer(addr_of_mut!(WATCHDOG_TIMER));
+	KeInitializeDpc(addr_of_mut!(WATCHDOG_DPC), routine, core::ptr::null_mut());
+}
+
+// Arm timer to deadline (clock::now_ms based), or cancel it if there is none.
+// A deadline which already passed fires at once.
+pub fn schedule(deadline_ms: Option<u64>) {
+	unsafe {
+		match deadline_ms {
+			Some(deadline_ms) => {
+				let delay_ms = deadline_ms.saturating_sub(clock::now_ms()) as i64;
+				KeSetTimer(addr_of_mut!(WATCHDOG_TIMER), -delay_ms * TICKS_PER_MS, addr_of_mut!(WATCHDOG_DPC));
+			}
+			None => {
+				KeCancelTimer(addr_of_mut!(WATCHDOG_TIMER));
+			}
+		}
+	}
+}
+
+/// Cancel timer and wait for a running DPC, before unload
+///
+/// # Safety
+/// IRQL PASSIVE_LEVEL, init must have run
+pub unsafe fn stop() {
+	KeCancelTimer(addr_of_mut!(WATCHDOG_TIMER));
+	KeFlushQueuedDpcs();
+}
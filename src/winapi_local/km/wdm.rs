@@ -1,6 +1,11 @@
 // Imports
 use winapi::km::wdm::{
-	KPROCESSOR_MODE
+	KPROCESSOR_MODE,
+	DEVICE_TYPE,
+	PDRIVER_OBJECT,
+	PDEVICE_OBJECT,
+	PIRP,
+	IoGetCurrentIrpStackLocation
 };
 use winapi::shared::ntdef::{
 	NTSTATUS,
@@ -9,11 +14,19 @@ use winapi::shared::ntdef::{
 	PCWSTR,
 	LUID,
 	ULONG,
+	PULONG,
 	BOOLEAN,
 	PVOID,
+	UCHAR,
+	ULONGLONG,
+	LONG,
+	HANDLE,
+	PHANDLE,
+	POBJECT_ATTRIBUTES,
 	KIRQL,
 	PKIRQL
 };
+use winapi::shared::guiddef::GUID;
 use winapi::um::winnt::{
 	SECURITY_IMPERSONATION_LEVEL,
 	PSECURITY_DESCRIPTOR,
@@ -128,10 +141,19 @@ pub type ULONG_PTR = u64;
 #[allow(non_camel_case_types)]
 pub type PULONG_PTR = *mut ULONG_PTR;
 
+// Spin lock
+#[allow(non_camel_case_types)]
+pub type KSPIN_LOCK = ULONG_PTR;
+#[allow(non_camel_case_types)]
+pub type PKSPIN_LOCK = *mut KSPIN_LOCK;
+
 // PACCESS_TOKEN type
 #[allow(non_camel_case_types)]
 pub type PACCESS_TOKEN = PVOID;
 
+// Opaque process object
+pub type PEPROCESS = PVOID;
+
 //
 //  Data structure used to capture subject security context
 //  for access validations and auditing.
@@ -215,6 +237,191 @@ pub struct ACCESS_STATE {
 //pub type PACCESS_STATE = *mut ACCESS_STATE;
 pub type PACCESS_STATE = PVOID;
 
+//
+// Registry value information classes and structures
+//
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub enum KEY_VALUE_INFORMATION_CLASS {
+	KeyValueBasicInformation = 0,
+	KeyValueFullInformation = 1,
+	KeyValuePartialInformation = 2
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct KEY_VALUE_PARTIAL_INFORMATION {
+	pub TitleIndex: ULONG,
+	pub Type: ULONG,
+	pub DataLength: ULONG,
+	pub Data: [u8; 1]
+}
+#[allow(non_camel_case_types)]
+pub type PKEY_VALUE_PARTIAL_INFORMATION = *mut KEY_VALUE_PARTIAL_INFORMATION;
+
+//
+// ETW registration and event data structures
+//
+#[allow(non_camel_case_types)]
+pub type REGHANDLE = ULONGLONG;
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct EVENT_DESCRIPTOR {
+	pub Id: u16,
+	pub Version: UCHAR,
+	pub Channel: UCHAR,
+	pub Level: UCHAR,
+	pub Opcode: UCHAR,
+	pub Task: u16,
+	pub Keyword: ULONGLONG
+}
+
+// Reserved field low byte is the descriptor type on Windows 10 and later
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct EVENT_DATA_DESCRIPTOR {
+	pub Ptr: ULONGLONG,
+	pub Size: ULONG,
+	pub Reserved: ULONG
+}
+pub const EVENT_DATA_DESCRIPTOR_TYPE_NONE: ULONG = 0;
+pub const EVENT_DATA_DESCRIPTOR_TYPE_EVENT_METADATA: ULONG = 1;
+pub const EVENT_DATA_DESCRIPTOR_TYPE_PROVIDER_METADATA: ULONG = 2;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub enum EVENT_INFO_CLASS {
+	EventProviderBinaryTrackInfo = 0,
+	EventProviderSetReserved1 = 1,
+	EventProviderSetTraits = 2,
+	EventProviderUseDescriptorType = 3
+}
+
+//
+// Error log packet
+//
+// Max size of one error log entry, packet header and strings included (ERROR_LOG_LIMIT_SIZE)
+pub const ERROR_LOG_MAXIMUM_SIZE: usize = 240;
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct IO_ERROR_LOG_PACKET {
+	pub MajorFunctionCode: UCHAR,
+	pub RetryCount: UCHAR,
+	pub DumpDataSize: u16,
+	pub NumberOfStrings: u16,
+	pub StringOffset: u16,
+	pub EventCategory: u16,
+	pub ErrorCode: NTSTATUS,
+	pub UniqueErrorValue: ULONG,
+	pub FinalStatus: NTSTATUS,
+	pub SequenceNumber: ULONG,
+	pub IoControlCode: ULONG,
+	pub DeviceOffset: i64,
+	pub DumpData: [ULONG; 1]
+}
+#[allow(non_camel_case_types)]
+pub type PIO_ERROR_LOG_PACKET = *mut IO_ERROR_LOG_PACKET;
+
+//
+// File object, only the leading fields the driver uses
+//
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct FILE_OBJECT {
+	pub Type: i16,
+	pub Size: i16,
+	pub DeviceObject: PDEVICE_OBJECT,
+	pub Vpb: PVOID,
+	pub FsContext: PVOID,
+	pub FsContext2: PVOID
+}
+#[allow(non_camel_case_types)]
+pub type PFILE_OBJECT = *mut FILE_OBJECT;
+
+//
+// Timer and DPC, opaque to the driver. Sizes are the x64 ones.
+//
+#[repr(C, align(8))]
+pub struct KTIMER {
+	_opaque: [u8; 64]
+}
+#[allow(non_camel_case_types)]
+pub type PKTIMER = *mut KTIMER;
+
+#[repr(C, align(8))]
+pub struct KDPC {
+	_opaque: [u8; 64]
+}
+#[allow(non_camel_case_types)]
+pub type PKDPC = *mut KDPC;
+
+// Dispatcher mutex, opaque to the driver. Size is the x64 one.
+#[repr(C, align(8))]
+pub struct KMUTEX {
+	_opaque: [u8; 56]
+}
+#[allow(non_camel_case_types)]
+pub type PRKMUTEX = *mut KMUTEX;
+
+// KeWaitForSingleObject wait reason, only the one drivers use
+#[allow(non_camel_case_types)]
+#[repr(u32)]
+pub enum KWAIT_REASON {
+	Executive = 0
+}
+
+#[allow(non_camel_case_types)]
+pub type PKDEFERRED_ROUTINE = unsafe extern "system" fn(
+	Dpc: PKDPC,
+	DeferredContext: PVOID,
+	SystemArgument1: PVOID,
+	SystemArgument2: PVOID
+);
+
+// Opaque EX_TIMER, allocated by ExAllocateTimer
+#[allow(non_camel_case_types)]
+pub type PEX_TIMER = PVOID;
+
+// Runs at DISPATCH_LEVEL when the timer fires
+#[allow(non_camel_case_types)]
+pub type PEXT_CALLBACK = unsafe extern "system" fn(
+	Timer: PEX_TIMER,
+	Context: PVOID
+);
+
+// Called with the cancel spin lock held, the routine releases it
+#[allow(non_camel_case_types)]
+pub type PDRIVER_CANCEL = unsafe extern "system" fn(
+	DeviceObject: PDEVICE_OBJECT,
+	Irp: PIRP
+);
+
+// ExAllocateTimer attributes
+pub const EX_TIMER_HIGH_RESOLUTION: ULONG = 0x4;
+
+// IO_STACK_LOCATION Control flag set by IoMarkIrpPending
+pub const SL_PENDING_RETURNED: u8 = 0x01;
+
+impl KTIMER {
+	pub const fn zeroed() -> KTIMER {
+		KTIMER { _opaque: [0; 64] }
+	}
+}
+
+impl KDPC {
+	pub const fn zeroed() -> KDPC {
+		KDPC { _opaque: [0; 64] }
+	}
+}
+
+impl KMUTEX {
+	pub const fn zeroed() -> KMUTEX {
+		KMUTEX { _opaque: [0; 56] }
+	}
+}
+
 // PUBLIC FUNCTIONS ==========================================
 
 // Import extern system functions and vars
@@ -243,6 +450,126 @@ extern "system" {
 	pub fn KeLowerIrql(new_irql: KIRQL);
 
 	pub static mut IoDriverObjectType: *mut POBJECT_TYPE;
+
+	// KeAcquireSpinLock is a macro over this export on x64
+	pub fn KeAcquireSpinLockRaiseToDpc(SpinLock: PKSPIN_LOCK) -> KIRQL;
+
+	pub fn KeReleaseSpinLock(SpinLock: PKSPIN_LOCK, NewIrql: KIRQL);
+
+	pub fn KeInitializeTimer(Timer: PKTIMER);
+
+	pub fn KeInitializeDpc(Dpc: PKDPC, DeferredRoutine: PKDEFERRED_ROUTINE, DeferredContext: PVOID);
+
+	// Negative due time is relative, in 100 ns units
+	pub fn KeSetTimer(Timer: PKTIMER, DueTime: i64, Dpc: PKDPC) -> BOOLEAN;
+
+	pub fn KeCancelTimer(Timer: PKTIMER) -> BOOLEAN;
+
+	pub fn KeFlushQueuedDpcs();
+
+	pub fn KeInitializeMutex(Mutex: PRKMUTEX, Level: ULONG);
+
+	// Timeout NULL waits forever. Waiting is PASSIVE_LEVEL or APC_LEVEL only.
+	pub fn KeWaitForSingleObject(
+		Object: PVOID,
+		WaitReason: KWAIT_REASON,
+		WaitMode: KPROCESSOR_MODE,
+		Alertable: BOOLEAN,
+		Timeout: *const i64
+	) -> NTSTATUS;
+
+	pub fn KeReleaseMutex(Mutex: PRKMUTEX, Wait: BOOLEAN) -> LONG;
+
+	pub fn IoReleaseCancelSpinLock(Irql: KIRQL);
+
+	// Windows 8.1 and later, PASSIVE_LEVEL
+	pub fn ExAllocateTimer(Callback: PEXT_CALLBACK, CallbackContext: PVOID, Attributes: ULONG) -> PEX_TIMER;
+
+	// Negative due time is relative, in 100 ns units. Parameters may be NULL.
+	pub fn ExSetTimer(Timer: PEX_TIMER, DueTime: i64, Period: i64, Parameters: PVOID) -> BOOLEAN;
+
+	pub fn ExCancelTimer(Timer: PEX_TIMER, Parameters: PVOID) -> BOOLEAN;
+
+	// Wait blocks until a running callback returns, PASSIVE_LEVEL only
+	pub fn ExDeleteTimer(Timer: PEX_TIMER, Cancel: BOOLEAN, Wait: BOOLEAN, Parameters: PVOID) -> BOOLEAN;
+
+	pub fn IoGetCurrentProcess() -> PEPROCESS;
+
+	pub fn PsGetCurrentProcessId() -> HANDLE;
+
+	// Name is allocated from paged pool, caller frees it with ExFreePool
+	pub fn SeLocateProcessImageName(Process: PEPROCESS, pImageFileName: *mut PUNICODE_STRING) -> NTSTATUS;
+
+	pub fn ExFreePool(P: PVOID);
+
+	// Negative interval is relative, in 100 ns units. PASSIVE_LEVEL only.
+	pub fn KeDelayExecutionThread(WaitMode: KPROCESSOR_MODE, Alertable: BOOLEAN, Interval: *const i64) -> NTSTATUS;
+
+	pub fn ZwOpenKey(
+		KeyHandle: PHANDLE,
+		DesiredAccess: ACCESS_MASK,
+		ObjectAttributes: POBJECT_ATTRIBUTES
+	) -> NTSTATUS;
+
+	pub fn ZwQueryValueKey(
+		KeyHandle: HANDLE,
+		ValueName: PUNICODE_STRING,
+		KeyValueInformationClass: KEY_VALUE_INFORMATION_CLASS,
+		KeyValueInformation: PVOID,
+		Length: ULONG,
+		ResultLength: PULONG
+	) -> NTSTATUS;
+
+	pub fn ZwClose(Handle: HANDLE) -> NTSTATUS;
+
+	pub fn IoAllocateErrorLogEntry(IoObject: PVOID, EntrySize: UCHAR) -> PVOID;
+
+	pub fn IoWriteErrorLogEntry(ElEntry: PVOID);
+
+	// LARGE_INTEGER is returned as plain i64, same layout on x64
+	pub fn KeQueryPerformanceCounter(PerformanceFrequency: *mut i64) -> i64;
+
+	pub fn EtwRegister(
+		ProviderId: *const GUID,
+		EnableCallback: PVOID,
+		CallbackContext: PVOID,
+		RegHandle: *mut REGHANDLE
+	) -> NTSTATUS;
+
+	pub fn EtwUnregister(RegHandle: REGHANDLE) -> NTSTATUS;
+
+	pub fn EtwSetInformation(
+		RegHandle: REGHANDLE,
+		InformationClass: EVENT_INFO_CLASS,
+		EventInformation: PVOID,
+		InformationLength: ULONG
+	) -> NTSTATUS;
+
+	pub fn EtwProviderEnabled(RegHandle: REGHANDLE, Level: UCHAR, Keyword: ULONGLONG) -> BOOLEAN;
+
+	pub fn EtwWrite(
+		RegHandle: REGHANDLE,
+		EventDescriptor: *const EVENT_DESCRIPTOR,
+		ActivityId: *const GUID,
+		UserDataCount: ULONG,
+		UserData: *const EVENT_DATA_DESCRIPTOR
+	) -> NTSTATUS;
+}
+
+// Secure device creation lives in a static library, not in ntoskrnl
+#[link(name = "wdmsec")]
+extern "system" {
+	pub fn IoCreateDeviceSecure(
+		DriverObject: PDRIVER_OBJECT,
+		DeviceExtensionSize: ULONG,
+		DeviceName: PUNICODE_STRING,
+		DeviceType: DEVICE_TYPE,
+		DeviceCharacteristics: ULONG,
+		Exclusive: BOOLEAN,
+		DefaultSDDLString: *const UNICODE_STRING,
+		DeviceClassGuid: *const GUID,
+		DeviceObject: *mut PDEVICE_OBJECT
+	) -> NTSTATUS;
 }
 
 // Create empty UNICODE_STRING
@@ -254,6 +581,42 @@ pub fn zeroed_unicode_string() -> UNICODE_STRING {
     }
 }
 
+/// KeGetCurrentIrql is inline on x64, IRQL lives in CR8
+///
+/// # Safety
+/// Kernel mode only, CR8 is privileged
+#[allow(non_snake_case)]
+pub unsafe fn KeGetCurrentIrql() -> KIRQL {
+	let irql: u64;
+	core::arch::asm!("mov {}, cr8", out(reg) irql, options(nomem, nostack, preserves_flags));
+	irql as KIRQL
+}
+
+/// IoMarkIrpPending macro
+///
+/// # Safety
+/// irp must be a valid IRP owned by the caller
+#[allow(non_snake_case)]
+pub unsafe fn IoMarkIrpPending(irp: PIRP) {
+	(*IoGetCurrentIrpStackLocation(irp)).Control |= SL_PENDING_RETURNED;
+}
+
+/// IoSetCancelRoutine macro, returns the previous routine
+///
+/// # Safety
+/// irp must be a valid IRP owned by the caller or parked by it
+#[allow(non_snake_case)]
+pub unsafe fn IoSetCancelRoutine(irp: PIRP, routine: Option<PDRIVER_CANCEL>) -> Option<PDRIVER_CANCEL> {
+	let slot = &*(&(*irp).CancelRoutine as *const _ as *const core::sync::atomic::AtomicUsize);
+	let new = routine.map_or(0, |routine| routine as usize);
+	let old = slot.swap(new, core::sync::atomic::Ordering::SeqCst);
+	if old == 0 {
+		None
+	} else {
+		Some(core::mem::transmute::<usize, PDRIVER_CANCEL>(old))
+	}
+}
+
 // KeRaiseIrql winapi macro
 #[allow(non_snake_case)]
 pub unsafe fn KeRaiseIrql(new_irql: KIRQL, old_irql: PKIRQL) {